@@ -1,14 +1,34 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::env;
+use std::net::{IpAddr, Ipv6Addr};
 use std::str::FromStr;
-use clap;
-use clap::{App, Arg, SubCommand};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::warn;
+
+use crate::crypto::KdfParams;
+use crate::{dns, journal, keys};
+use crate::pool::Subnet;
+use crate::route::{Prefix, Split};
 
 #[derive(Debug,Clone)]
 pub struct Server{
-    pub bind_addr:String,
+    pub bind_addr:IpAddr,
     pub port:u16,
     pub key:String,
-    pub dns:IpAddr
+    pub subnet:Subnet,
+    pub subnet6:Option<Subnet<Ipv6Addr>>,
+    pub dns:dns::Config,
+    /// Domain of the clients' names if the server forwards their DNS queries itself.
+    pub dns_proxy:Option<String>,
+    /// Prefixes every client routes through the tunnel and around it.
+    pub split:Split,
+    pub kdf:KdfParams,
+    pub legacy_kdf:bool,
+    pub clients:Option<String>,
+    pub accept_routes:bool,
+    pub isolate_clients:bool,
+    pub masquerade:bool,
+    pub egress:Option<String>,
+    pub state:String
 }
 
 #[derive(Debug,Clone)]
@@ -16,125 +36,447 @@ pub struct Client{
     pub remote_addr:String,
     pub port:u16,
     pub key:String,
-    pub default_route:bool
+    pub default_route:bool,
+    pub default_route6:bool,
+    pub legacy_kdf:bool,
+    pub identity:Option<String>,
+    pub advertise:Vec<Prefix>,
+    /// Prefixes routed through the tunnel and around it, besides the ones the server pushes.
+    pub split:Split,
+    /// Whether traffic outside the tunnel is blocked until a deliberate disconnect, with the
+    /// client reconnecting when the tunnel fails.
+    pub kill_switch:bool,
+    pub state:String
 }
 
 #[derive(Debug,Clone)]
 pub enum Args{
     Client(Client),
-    Server(Server)
+    Server(Server),
+    GenKey{output:Option<String>},
+    PubKey{input:Option<String>}
+}
+
+/// Reads the key from `--key-file`, `--key` or the environment, in that order.
+fn get_key(matches:&ArgMatches) -> Result<String,String> {
+    if let Some(path) = matches.get_one::<String>("key-file") {
+        return keys::read_key_file(path);
+    }
+    if let Some(key) = matches.get_one::<String>("key") {
+        warn!("--key is visible to other users, prefer --key-file or {}.", keys::KEY_ENV);
+        return Ok(key.to_string());
+    }
+    env::var(keys::KEY_ENV).map_err(|_| format!("no key given, use --key-file, --key or {}",keys::KEY_ENV))
+}
+
+/// Parses a listen address. IPv6 addresses may be given in brackets, e.g. `[::]`.
+fn parse_bind(bind:&str) -> Result<IpAddr,String> {
+    bind.strip_prefix('[')
+        .and_then(|bind| bind.strip_suffix(']'))
+        .unwrap_or(bind)
+        .parse::<IpAddr>()
+        .map_err(|e|format!("{}: {}",bind,e))
+}
+
+/// Reads `--state-file`, defaulting to `name` in the state directory.
+fn get_state(matches:&ArgMatches,name:&str) -> String {
+    matches
+        .get_one::<String>("state-file")
+        .cloned()
+        .unwrap_or_else(|| format!("{}/{}",journal::STATE_DIR,name))
+}
+
+/// Reads the domains given to the option `id`.
+fn get_domains(matches:&ArgMatches,id:&str) -> Result<Vec<String>,String> {
+    matches
+        .get_many::<String>(id)
+        .unwrap_or_default()
+        .map(|domain| dns::check_domain(domain).map(|_| domain.to_string()))
+        .collect()
+}
+
+/// Reads the prefixes given to the option `id`.
+fn get_prefixes(matches:&ArgMatches,id:&str) -> Result<Vec<Prefix>,String> {
+    matches
+        .get_many::<String>(id)
+        .unwrap_or_default()
+        .map(|prefix| prefix.parse::<Prefix>())
+        .collect()
+}
+
+fn get_u32(matches:&ArgMatches,id:&str) -> Result<u32,String> {
+    matches
+        .get_one::<String>(id)
+        .ok_or(format!("can't find {} value",id))?
+        .parse::<u32>()
+        .map_err(|e|format!("{}: {}",id,e))
 }
 
 pub fn get_args() -> Result<Args,String> {
-    let matches = App::new("e-net: High Performance Peer-to-Peer VPN")
+    let matches = Command::new("e-net")
+        .about("e-net: High Performance Peer-to-Peer VPN")
         .version("1.0")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .subcommand(
-            SubCommand::with_name("server")
-                .help("client mode")
+            Command::new("server")
+                .about("server mode")
                 .arg(
-                    Arg::with_name("bind")
-                        .short("l")
+                    Arg::new("bind")
+                        .short('l')
                         .long("listen")
                         .default_value("0.0.0.0")
-                        .help("set the listen address")
-                        .takes_value(true),
+                        .help("set the listen address, :: or [::] listens on both IPv6 and IPv4")
                 )
                 .arg(
-                    Arg::with_name("port")
-                        .short("p")
+                    Arg::new("port")
+                        .short('p')
                         .long("port")
                         .default_value("9527")
                         .help("set the listen port")
-                        .takes_value(true)
                 )
                 .arg(
-                    Arg::with_name("key")
-                        .short("k")
+                    Arg::new("key")
+                        .short('k')
                         .long("key")
-                        .help("set the key for encryption communication")
-                        .takes_value(true)
+                        .conflicts_with("key-file")
+                        .help("set the key for encryption communication, visible to other users")
+                )
+                .arg(
+                    Arg::new("key-file")
+                        .short('f')
+                        .long("key-file")
+                        .help("read the key for encryption communication from a file")
+                )
+                .arg(
+                    Arg::new("subnet")
+                        .long("subnet")
+                        .default_value("10.10.10.0/24")
+                        .help("set the tunnel subnet in CIDR notation, the server takes its first address")
+                )
+                .arg(
+                    Arg::new("subnet6")
+                        .long("subnet6")
+                        .help("also hand out IPv6 addresses from this unique local (fc00::/7) subnet")
                 )
                 .arg(
-                    Arg::with_name("dns")
-                        .short("d")
+                    Arg::new("dns")
+                        .short('d')
                         .long("dns")
+                        .action(ArgAction::Append)
                         .default_value("8.8.8.8")
-                        .help("set dns for client, default is 8.8.8.8")
-                        .takes_value(true)
+                        .help("set dns for client, may be repeated, default is 8.8.8.8")
+                )
+                .arg(
+                    Arg::new("dns-proxy")
+                        .long("dns-proxy")
+                        .action(ArgAction::SetTrue)
+                        .help("answer client DNS queries on the tunnel address, forwarding them to the --dns servers")
+                )
+                .arg(
+                    Arg::new("dns-domain")
+                        .long("dns-domain")
+                        .requires("dns-proxy")
+                        .help("set the domain the DNS proxy serves client names under, default is vpn")
+                )
+                .arg(
+                    Arg::new("search-domain")
+                        .long("search-domain")
+                        .action(ArgAction::Append)
+                        .help("push a DNS search domain to clients, may be repeated")
+                )
+                .arg(
+                    Arg::new("split-dns")
+                        .long("split-dns")
+                        .action(ArgAction::Append)
+                        .help("have clients resolve only names in this domain through the --dns servers, may be repeated")
+                )
+                .arg(
+                    Arg::new("push-route")
+                        .long("push-route")
+                        .action(ArgAction::Append)
+                        .help("have clients route this prefix through the tunnel, may be repeated")
+                )
+                .arg(
+                    Arg::new("push-exclude")
+                        .long("push-exclude")
+                        .action(ArgAction::Append)
+                        .help("have clients route this prefix around the tunnel, may be repeated")
+                )
+                .arg(
+                    Arg::new("kdf-memory")
+                        .long("kdf-memory")
+                        .default_value("65536")
+                        .help("set the Argon2id memory cost in KiB")
+                )
+                .arg(
+                    Arg::new("kdf-iterations")
+                        .long("kdf-iterations")
+                        .default_value("3")
+                        .help("set the Argon2id number of iterations")
+                )
+                .arg(
+                    Arg::new("kdf-parallelism")
+                        .long("kdf-parallelism")
+                        .default_value("1")
+                        .help("set the Argon2id degree of parallelism")
+                )
+                .arg(
+                    Arg::new("legacy-kdf")
+                        .long("legacy-kdf")
+                        .action(ArgAction::SetTrue)
+                        .help("also accept clients using the old PBKDF2 key derivation")
+                )
+                .arg(
+                    Arg::new("clients")
+                        .short('c')
+                        .long("clients")
+                        .help("only accept clients listed with their public keys in this file")
+                )
+                .arg(
+                    Arg::new("accept-routes")
+                        .long("accept-routes")
+                        .action(ArgAction::SetTrue)
+                        .help("route the prefixes clients advertise with --advertise to them")
+                )
+                .arg(
+                    Arg::new("isolate-clients")
+                        .long("isolate-clients")
+                        .action(ArgAction::SetTrue)
+                        .help("drop traffic between clients instead of forwarding it")
+                )
+                .arg(
+                    Arg::new("masquerade")
+                        .long("masquerade")
+                        .action(ArgAction::SetTrue)
+                        .help("masquerade client traffic leaving the server, removing the rules on exit")
+                )
+                .arg(
+                    Arg::new("egress")
+                        .long("egress")
+                        .requires("masquerade")
+                        .help("set the interface to masquerade on, default is the one of the default route")
+                )
+                .arg(
+                    Arg::new("state-file")
+                        .long("state-file")
+                        .help("note system changes in this file to undo them after a crash, default is /var/lib/e-net/server.state")
                 )
         )
         .subcommand(
-            SubCommand::with_name("client")
-                .help("server mode")
+            Command::new("client")
+                .about("client mode")
                 .arg(
-                    Arg::with_name("server")
-                        .short("s")
+                    Arg::new("server")
+                        .short('s')
                         .long("server")
+                        .required(true)
                         .help("set the remote server address")
-                        .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("port")
-                        .short("p")
+                    Arg::new("port")
+                        .short('p')
                         .long("port")
+                        .default_value("9527")
                         .help("set the remote port")
-                        .takes_value(true)
                 )
                 .arg(
-                    Arg::with_name("key")
-                        .short("k")
+                    Arg::new("key")
+                        .short('k')
                         .long("key")
-                        .help("set the key for encryption communication")
-                        .takes_value(true)
+                        .conflicts_with("key-file")
+                        .help("set the key for encryption communication, visible to other users")
+                )
+                .arg(
+                    Arg::new("key-file")
+                        .short('f')
+                        .long("key-file")
+                        .help("read the key for encryption communication from a file")
                 )
                 .arg(
-                    Arg::with_name("no-default-route")
-                        .short("n")
-                        .long("no-default-remote")
+                    Arg::new("no-default-route")
+                        .short('n')
+                        .long("no-default-route")
+                        .action(ArgAction::SetTrue)
                         .help("do not set default route")
+                )
+                .arg(
+                    Arg::new("ipv6-default-route")
+                        .long("ipv6-default-route")
+                        .action(ArgAction::SetTrue)
+                        .help("route all IPv6 traffic through the tunnel if the server hands out IPv6 addresses")
+                )
+                .arg(
+                    Arg::new("route")
+                        .long("route")
+                        .action(ArgAction::Append)
+                        .help("route this prefix through the tunnel instead of all traffic, may be repeated, 0.0.0.0/0 or ::/0 take all traffic again")
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .action(ArgAction::Append)
+                        .help("route this prefix through the original gateway even if it is in a tunneled one, may be repeated")
+                )
+                .arg(
+                    Arg::new("kill-switch")
+                        .long("kill-switch")
+                        .action(ArgAction::SetTrue)
+                        .help("block all traffic but the tunnel's until interrupted, reconnecting when the tunnel fails, Linux only")
+                )
+                .arg(
+                    Arg::new("legacy-kdf")
+                        .long("legacy-kdf")
+                        .action(ArgAction::SetTrue)
+                        .help("derive the key with the old PBKDF2 scheme to reach servers without Argon2id")
+                )
+                .arg(
+                    Arg::new("identity")
+                        .short('i')
+                        .long("identity")
+                        .help("authenticate with the private key in this file, made with genkey")
+                )
+                .arg(
+                    Arg::new("advertise")
+                        .long("advertise")
+                        .action(ArgAction::Append)
+                        .requires("identity")
+                        .help("ask the server to route this prefix behind the client to it, may be repeated")
+                )
+                .arg(
+                    Arg::new("state-file")
+                        .long("state-file")
+                        .help("note system changes in this file to undo them after a crash, default is /var/lib/e-net/client.state")
                 ),
         )
+        .subcommand(
+            Command::new("genkey")
+                .about("generate a key")
+                .arg(
+                    Arg::new("output")
+                        .help("write the key to a new file readable only by its owner instead of stdout")
+                )
+        )
+        .subcommand(
+            Command::new("pubkey")
+                .about("print the public key of a key")
+                .arg(
+                    Arg::new("input")
+                        .help("read the key from a file instead of stdin")
+                )
+        )
         .get_matches();
-    if let Some(matches) = matches.subcommand_matches("client"){
-        let ip_str = matches
-            .value_of("server")
-            .ok_or_else(|| "can't find client host value")
-            .unwrap();
-        let port_str = matches
-            .value_of("port")
-            .ok_or_else(|| "can't find client port value")
-            .unwrap();
-        let key_str = matches
-            .value_of("key")
-            .ok_or_else(|| "can't find client key value")
-            .unwrap();
-        let port =port_str.parse::<u16>().map_err(|e|e.to_string())?;
-        let default_route = match matches.is_present("no-default-remote"){
-            false => true,
-            true => false,
-        };
-        Ok(Args::Client(Client{ remote_addr:ip_str.to_string(), key:key_str.to_string(), port, default_route, }))
-    } else if let Some(matches) = matches.subcommand_matches("server") {
-        let ip_str = matches
-            .value_of("bind")
-            .ok_or_else(|| "can't find server host value")
-            .unwrap();
-        let port_str = matches
-            .value_of("port")
-            .ok_or_else(|| "can't find server port value")
-            .unwrap();
-        let key_str = matches
-            .value_of("key")
-            .ok_or_else(|| "can't find server key value")
-            .unwrap();
-        let dns = matches
-            .value_of("dns")
-            .ok_or_else(|| "can't find dns value")
-            .unwrap();
-        let dns = IpAddr::V4(Ipv4Addr::from_str(dns).map_err(|e|e.to_string())?);
-        let port = port_str.parse::<u16>().map_err(|e |e.to_string())?;
-        Ok(Args::Server(Server{ bind_addr:ip_str.to_string(), port, key:key_str.to_string(), dns }))
-    } else {
-        unimplemented!()
+    match matches.subcommand() {
+        Some(("client", matches)) => {
+            let ip_str = matches
+                .get_one::<String>("server")
+                .ok_or("can't find client host value")?;
+            let port_str = matches
+                .get_one::<String>("port")
+                .ok_or("can't find client port value")?;
+            let key_str = get_key(matches)?;
+            let port = port_str.parse::<u16>().map_err(|e|e.to_string())?;
+            let default_route = !matches.get_flag("no-default-route");
+            let default_route6 = matches.get_flag("ipv6-default-route");
+            let legacy_kdf = matches.get_flag("legacy-kdf");
+            let identity = matches
+                .get_one::<String>("identity")
+                .map(|path| keys::read_key_file(path))
+                .transpose()?;
+            let advertise = get_prefixes(matches,"advertise")?;
+            let split = Split{ include:get_prefixes(matches,"route")?, exclude:get_prefixes(matches,"exclude")? };
+            split.check(&[])?;
+            Ok(Args::Client(Client{
+                remote_addr:ip_str.to_string(),
+                key:key_str,
+                port,
+                default_route,
+                default_route6,
+                legacy_kdf,
+                identity,
+                advertise,
+                split,
+                kill_switch:matches.get_flag("kill-switch"),
+                state:get_state(matches,"client.state")
+            }))
+        }
+        Some(("server", matches)) => {
+            let bind_addr = parse_bind(
+                matches
+                    .get_one::<String>("bind")
+                    .ok_or("can't find server host value")?
+            )?;
+            let port_str = matches
+                .get_one::<String>("port")
+                .ok_or("can't find server port value")?;
+            let key_str = get_key(matches)?;
+            let subnet = matches
+                .get_one::<String>("subnet")
+                .ok_or("can't find subnet value")?
+                .parse::<Subnet>()?;
+            let subnet6 = matches
+                .get_one::<String>("subnet6")
+                .map(|subnet6| subnet6.parse::<Subnet<Ipv6Addr>>())
+                .transpose()?;
+            if let Some(subnet6) = subnet6.filter(|subnet6| !subnet6.gateway().is_unique_local()) {
+                return Err(format!("{} is not a unique local IPv6 subnet",subnet6));
+            }
+            let servers = matches
+                .get_many::<String>("dns")
+                .ok_or("can't find dns value")?
+                .map(|server| IpAddr::from_str(server).map_err(|e|format!("{}: {}",server,e)))
+                .collect::<Result<Vec<IpAddr>,String>>()?;
+            let search = get_domains(matches,"search-domain")?;
+            let domains = get_domains(matches,"split-dns")?;
+            let dns = dns::Config{ servers, search, domains };
+            let dns_proxy = if matches.get_flag("dns-proxy") {
+                let domain = matches.get_one::<String>("dns-domain").map_or("vpn",|domain| domain.as_str());
+                dns::check_domain(domain)?;
+                Some(domain.to_string())
+            } else {
+                None
+            };
+            let split = Split{
+                include:get_prefixes(matches,"push-route")?,
+                exclude:get_prefixes(matches,"push-exclude")?
+            };
+            let mut tunnels = vec![Prefix::from(subnet)];
+            tunnels.extend(subnet6.map(Prefix::from));
+            split.check(&tunnels)?;
+            let port = port_str.parse::<u16>().map_err(|e |e.to_string())?;
+            let kdf = KdfParams{
+                memory_kib:get_u32(matches,"kdf-memory")?,
+                iterations:get_u32(matches,"kdf-iterations")?,
+                parallelism:get_u32(matches,"kdf-parallelism")?
+            };
+            kdf.check()?;
+            let legacy_kdf = matches.get_flag("legacy-kdf");
+            let clients = matches.get_one::<String>("clients").cloned();
+            let accept_routes = matches.get_flag("accept-routes");
+            let isolate_clients = matches.get_flag("isolate-clients");
+            let masquerade = matches.get_flag("masquerade");
+            let egress = matches.get_one::<String>("egress").cloned();
+            Ok(Args::Server(Server{
+                bind_addr,
+                port,
+                key:key_str,
+                subnet,
+                subnet6,
+                dns,
+                dns_proxy,
+                split,
+                kdf,
+                legacy_kdf,
+                clients,
+                accept_routes,
+                isolate_clients,
+                masquerade,
+                egress,
+                state:get_state(matches,"server.state")
+            }))
+        }
+        Some(("genkey", matches)) => Ok(Args::GenKey{ output:matches.get_one::<String>("output").cloned() }),
+        Some(("pubkey", matches)) => Ok(Args::PubKey{ input:matches.get_one::<String>("input").cloned() }),
+        _ => Err(String::from("no subcommand given"))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::time::SystemTime;
+
+use crate::crypto::KEY_LEN;
+use crate::keys;
+
+/// Client identified by the static key it authenticated the handshake with.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Identity {
+    pub name:String,
+    pub public_key:[u8;KEY_LEN]
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}",self.name)
+    }
+}
+
+/// Clients allowed to connect, read from a file with one `name public-key` pair per line.
+///
+/// Blank lines and lines starting with `#` are ignored. The file is read again whenever its
+/// modification time changes, so clients can be added or revoked while the server runs.
+pub struct ClientTable {
+    path:String,
+    modified:Option<SystemTime>,
+    names:HashMap<[u8;KEY_LEN],String>
+}
+
+impl ClientTable {
+    pub fn load(path:&str) -> Result<ClientTable,String> {
+        let mut table = ClientTable{ path:path.to_string(), modified:None, names:HashMap::new() };
+        table.reload()?;
+        Ok(table)
+    }
+
+    fn modified(&self) -> Result<SystemTime,String> {
+        fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e|format!("{}: {}",self.path,e))
+    }
+
+    fn reload(&mut self) -> Result<(),String> {
+        self.modified = Some(self.modified()?);
+        let content = fs::read_to_string(&self.path).map_err(|e|format!("{}: {}",self.path,e))?;
+        self.names = parse(&content).map_err(|e|format!("{}: {}",self.path,e))?;
+        Ok(())
+    }
+
+    /// Reads the file again if it changed, returning whether it did. On error the previous
+    /// entries stay in effect until the file changes again.
+    pub fn reload_if_changed(&mut self) -> Result<bool,String> {
+        if self.modified == Some(self.modified()?) {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Looks up the client owning `public_key`.
+    pub fn authorize(&self,public_key:&[u8;KEY_LEN]) -> Option<Identity> {
+        self.names.get(public_key).map(|name| Identity{ name:name.clone(), public_key:*public_key })
+    }
+
+    /// Whether `identity` is still allowed to connect under the same name.
+    pub fn contains(&self,identity:&Identity) -> bool {
+        self.names.get(&identity.public_key) == Some(&identity.name)
+    }
+}
+
+fn parse(content:&str) -> Result<HashMap<[u8;KEY_LEN],String>,String> {
+    let mut names = HashMap::new();
+    for (number,line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields:Vec<&str> = line.split_whitespace().collect();
+        let (name,public_key) = match fields[..] {
+            [name,public_key] => (name,public_key),
+            _ => return Err(format!("line {}: expected `name public-key`",number + 1))
+        };
+        let public_key = keys::decode(public_key).map_err(|e|format!("line {}: {}",number + 1,e))?;
+        if let Some(other) = names.insert(public_key,name.to_string()) {
+            return Err(format!("line {}: key already used by {}",number + 1,other));
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::clients::*;
+
+    #[test]
+    fn parse_test() {
+        let laptop = keys::encode(&[1;KEY_LEN]);
+        let phone = keys::encode(&[2;KEY_LEN]);
+        let names = parse(&format!("# clients\n\nlaptop {}\n  phone\t{}  \n",laptop,phone)).unwrap();
+        assert_eq!(names.len(),2);
+        assert_eq!(names[&[2;KEY_LEN]],"phone");
+        assert!(parse("laptop").is_err());
+        assert!(parse("laptop not-a-key").is_err());
+        assert!(parse(&format!("laptop {} extra",laptop)).is_err());
+        assert!(parse(&format!("laptop {}\nphone {}",laptop,laptop)).is_err());
+    }
+
+    #[test]
+    fn reload_test() {
+        let path = env::temp_dir().join(format!("e-net-{}-clients",process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path,format!("laptop {}\n",keys::encode(&[1;KEY_LEN]))).unwrap();
+        let mut table = ClientTable::load(path).unwrap();
+        let laptop = table.authorize(&[1;KEY_LEN]).unwrap();
+        assert_eq!(laptop.name,"laptop");
+        assert!(table.contains(&laptop));
+        assert!(table.authorize(&[2;KEY_LEN]).is_none());
+        assert!(!table.reload_if_changed().unwrap());
+        thread::sleep(Duration::from_millis(10));
+        fs::write(path,format!("phone {}\n",keys::encode(&[2;KEY_LEN]))).unwrap();
+        assert!(table.reload_if_changed().unwrap());
+        assert!(!table.contains(&laptop));
+        assert!(table.authorize(&[2;KEY_LEN]).is_some());
+        thread::sleep(Duration::from_millis(10));
+        fs::write(path,"phone").unwrap();
+        assert!(table.reload_if_changed().is_err());
+        assert!(!table.reload_if_changed().unwrap());
+        assert!(table.authorize(&[2;KEY_LEN]).is_some());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+use std::num::NonZeroU32;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use ring::{aead, hkdf, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const KEY_LEN:usize = 32;
+pub const SALT_LEN:usize = 16;
+
+const CLIENT_TO_SERVER:&[u8] = b"e-net client to server";
+const SERVER_TO_CLIENT:&[u8] = b"e-net server to client";
+
+#[derive(Clone,Copy)]
+pub enum Role {
+    Client,
+    Server
+}
+
+/// Traffic keys of one session, one per direction.
+pub struct SessionKeys {
+    pub send:aead::LessSafeKey,
+    pub recv:aead::LessSafeKey
+}
+
+/// Static key proving the client's identity in a handshake.
+pub enum ClientKey<'a> {
+    /// The client's own private key, used on the client.
+    Private(&'a StaticSecret),
+    /// The public key of the connecting client, used on the server.
+    Public(&'a PublicKey)
+}
+
+/// Our half of an ephemeral X25519 exchange.
+///
+/// The private key is consumed by [`Handshake::finish`], so it never outlives the handshake
+/// and a later compromise of the pre-shared secret does not expose recorded sessions.
+///
+/// The key is an x25519-dalek `StaticSecret` rather than ring's `EphemeralPrivateKey`,
+/// which can only be used in a single agreement: an authenticated handshake agrees with it
+/// twice, with the peer's ephemeral key and with the client's static key. ring also has no
+/// way to load the client's stored identity key, so both come from the same crate.
+pub struct Handshake {
+    private_key:StaticSecret,
+    public_key:PublicKey
+}
+
+impl Handshake {
+    pub fn new(rng:&SystemRandom) -> Result<Handshake,String> {
+        let private_key = StaticSecret::from(random_key(rng)?);
+        let public_key = PublicKey::from(&private_key);
+        Ok(Handshake{ private_key, public_key })
+    }
+
+    pub fn public_key(&self) -> &[u8;KEY_LEN] {
+        self.public_key.as_bytes()
+    }
+
+    /// Completes the exchange with the peer's public key and derives the session keys.
+    ///
+    /// The pre-shared secret is mixed in as the HKDF salt, so only holders of the secret
+    /// end up with matching keys.
+    pub fn finish(self,psk:&[u8;KEY_LEN],role:Role,peer_public_key:&[u8]) -> Result<SessionKeys,String> {
+        self.derive(psk,role,peer_public_key,None)
+    }
+
+    /// Like [`Handshake::finish`], but also mixes in an exchange between the client's static
+    /// key and the server's ephemeral key, so only the holder of the client's private key
+    /// ends up with matching keys.
+    pub fn finish_authenticated(
+        self,
+        psk:&[u8;KEY_LEN],
+        role:Role,
+        peer_public_key:&[u8],
+        client_key:ClientKey
+    ) -> Result<SessionKeys,String> {
+        let static_shared = match client_key {
+            ClientKey::Private(private_key) => agree(private_key,&parse_public_key(peer_public_key)?)?,
+            ClientKey::Public(public_key) => agree(&self.private_key,public_key)?
+        };
+        self.derive(psk,role,peer_public_key,Some(static_shared))
+    }
+
+    fn derive(
+        self,
+        psk:&[u8;KEY_LEN],
+        role:Role,
+        peer_public_key:&[u8],
+        static_shared:Option<[u8;KEY_LEN]>
+    ) -> Result<SessionKeys,String> {
+        let own_public_key = self.public_key.as_bytes();
+        let (client_public_key,server_public_key) = match role {
+            Role::Client => (own_public_key.as_slice(),peer_public_key),
+            Role::Server => (peer_public_key,own_public_key.as_slice())
+        };
+        let mut shared = agree(&self.private_key,&parse_public_key(peer_public_key)?)?.to_vec();
+        if let Some(static_shared) = static_shared {
+            shared.extend_from_slice(&static_shared);
+        }
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, psk).extract(&shared);
+        let client_to_server = expand_key(&prk,CLIENT_TO_SERVER,client_public_key,server_public_key)?;
+        let server_to_client = expand_key(&prk,SERVER_TO_CLIENT,client_public_key,server_public_key)?;
+        Ok(match role {
+            Role::Client => SessionKeys{ send:client_to_server, recv:server_to_client },
+            Role::Server => SessionKeys{ send:server_to_client, recv:client_to_server }
+        })
+    }
+}
+
+pub fn parse_public_key(public_key:&[u8]) -> Result<PublicKey,String> {
+    let public_key:[u8;KEY_LEN] = public_key
+        .try_into()
+        .map_err(|_| format!("invalid public key length {}",public_key.len()))?;
+    Ok(PublicKey::from(public_key))
+}
+
+/// X25519 agreement, rejecting low order public keys that would force a known result.
+fn agree(private_key:&StaticSecret,public_key:&PublicKey) -> Result<[u8;KEY_LEN],String> {
+    let shared = private_key.diffie_hellman(public_key);
+    if !shared.was_contributory() {
+        return Err(String::from("x25519: low order public key"));
+    }
+    Ok(shared.to_bytes())
+}
+
+fn expand_key(
+    prk:&hkdf::Prk,
+    label:&[u8],
+    client_public_key:&[u8],
+    server_public_key:&[u8]
+) -> Result<aead::LessSafeKey,String> {
+    let info = [label,client_public_key,server_public_key];
+    let okm = prk.expand(&info, &aead::AES_256_GCM).map_err(|_| "hkdf::expand")?;
+    Ok(aead::LessSafeKey::new(aead::UnboundKey::from(okm)))
+}
+
+/// Argon2id cost parameters used to turn the password into the pre-shared secret.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct KdfParams {
+    pub memory_kib:u32,
+    pub iterations:u32,
+    pub parallelism:u32
+}
+
+impl KdfParams {
+    /// Weakest parameters a client agrees to derive its secret with, so that a forged
+    /// handshake cannot make it seal a request under a cheaply guessable key.
+    pub const MIN:KdfParams = KdfParams{ memory_kib:19 * 1024, iterations:2, parallelism:1 };
+
+    pub fn check(&self) -> Result<(),String> {
+        let min = KdfParams::MIN;
+        if self.memory_kib < min.memory_kib || self.iterations < min.iterations || self.parallelism < min.parallelism {
+            return Err(format!("KDF parameters {:?} are weaker than {:?}",self,min));
+        }
+        Ok(())
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams{ memory_kib:64 * 1024, iterations:3, parallelism:1 }
+    }
+}
+
+pub fn random_key(rng:&SystemRandom) -> Result<[u8;KEY_LEN],String> {
+    let mut key = [0u8;KEY_LEN];
+    rng.fill(&mut key).map_err(|_| "ring::rand::fill")?;
+    Ok(key)
+}
+
+pub fn random_salt(rng:&SystemRandom) -> Result<[u8;SALT_LEN],String> {
+    let mut salt = [0u8;SALT_LEN];
+    rng.fill(&mut salt).map_err(|_| "ring::rand::fill")?;
+    Ok(salt)
+}
+
+/// Derives the pre-shared secret from `password` with Argon2id.
+pub fn derive_psk(password:&str,salt:&[u8;SALT_LEN],params:&KdfParams) -> Result<[u8;KEY_LEN],String> {
+    let params = Params::new(params.memory_kib,params.iterations,params.parallelism,Some(KEY_LEN))
+        .map_err(|e|e.to_string())?;
+    let mut key = [0;KEY_LEN];
+    Argon2::new(Algorithm::Argon2id,Version::V0x13,params)
+        .hash_password_into(password.as_bytes(),salt,&mut key)
+        .map_err(|e|e.to_string())?;
+    Ok(key)
+}
+
+/// Derives the pre-shared secret the way releases before Argon2id did: PBKDF2 with 1024
+/// iterations and an all-zero salt. Only kept so old clients can be migrated.
+pub fn derive_legacy_psk(password:&str) -> [u8;KEY_LEN] {
+    let mut key = [0;KEY_LEN];
+    let salt = vec![0;64];
+    let pbkdf2_iterations : NonZeroU32 = NonZeroU32::new(1024).unwrap();
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        pbkdf2_iterations,
+        &salt,
+        password.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+/// Key protecting the handshake messages themselves.
+pub fn handshake_key(psk:&[u8;KEY_LEN]) -> aead::LessSafeKey {
+    aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_256_GCM, psk).unwrap())
+}
+
+/// Seals `plaintext` under a fresh random 96-bit nonce and returns `nonce || ciphertext || tag`.
+///
+/// Used for handshake messages: the handshake key is shared by every client, so there is
+/// no single counter that could be kept unique for it.
+pub fn seal(key:&aead::LessSafeKey,rng:&SystemRandom,aad:&[u8],plaintext:&[u8]) -> Result<Vec<u8>,String> {
+    let mut nonce_bytes = [0u8;aead::NONCE_LEN];
+    rng.fill(&mut nonce_bytes).map_err(|_| "ring::rand::fill")?;
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        aead::Nonce::assume_unique_for_key(nonce_bytes),
+        aead::Aad::from(aad),
+        &mut in_out
+    ).map_err(|_| "aead::seal")?;
+    let mut sealed = Vec::with_capacity(aead::NONCE_LEN + in_out.len());
+    sealed.extend_from_slice(&nonce_bytes);
+    sealed.extend_from_slice(&in_out);
+    Ok(sealed)
+}
+
+/// Opens a body produced by [`seal`] in place and returns the plaintext.
+pub fn open<'a>(key:&aead::LessSafeKey,aad:&[u8],sealed:&'a mut [u8]) -> Result<&'a mut [u8],String> {
+    if sealed.len() < aead::NONCE_LEN + key.algorithm().tag_len() {
+        return Err(format!("datagram too short: {} bytes",sealed.len()));
+    }
+    let (nonce_bytes,in_out) = sealed.split_at_mut(aead::NONCE_LEN);
+    let nonce = aead::Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| "aead::Nonce")?;
+    key.open_in_place(nonce,aead::Aad::from(aad),in_out).map_err(|_| String::from("aead::open"))
+}
+
+fn counter_nonce(counter:u64) -> aead::Nonce {
+    let mut nonce = [0u8;aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+/// Seals `plaintext` using `counter` as the nonce and returns `ciphertext || tag`.
+///
+/// Traffic keys belong to a single session and direction, so a counter that never repeats
+/// keeps nonces unique and doubles as the packet number checked by the replay window.
+pub fn seal_counted(key:&aead::LessSafeKey,counter:u64,aad:&[u8],plaintext:&[u8]) -> Result<Vec<u8>,String> {
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(counter_nonce(counter),aead::Aad::from(aad),&mut in_out)
+        .map_err(|_| "aead::seal")?;
+    Ok(in_out)
+}
+
+/// Opens a body produced by [`seal_counted`] in place and returns the plaintext.
+pub fn open_counted<'a>(
+    key:&aead::LessSafeKey,
+    counter:u64,
+    aad:&[u8],
+    sealed:&'a mut [u8]
+) -> Result<&'a mut [u8],String> {
+    if sealed.len() < key.algorithm().tag_len() {
+        return Err(format!("datagram too short: {} bytes",sealed.len()));
+    }
+    key.open_in_place(counter_nonce(counter),aead::Aad::from(aad),sealed)
+        .map_err(|_| String::from("aead::open"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::*;
+
+    const TEST_PARAMS:KdfParams = KdfParams{ memory_kib:64, iterations:1, parallelism:1 };
+
+    fn test_psk(password:&str) -> [u8;KEY_LEN] {
+        derive_psk(password,&[7;SALT_LEN],&TEST_PARAMS).unwrap()
+    }
+
+    fn handshake(client_psk:&[u8;KEY_LEN],server_psk:&[u8;KEY_LEN]) -> (SessionKeys,SessionKeys) {
+        let rng = SystemRandom::new();
+        let client = Handshake::new(&rng).unwrap();
+        let server = Handshake::new(&rng).unwrap();
+        let client_public_key = client.public_key().to_vec();
+        let server_public_key = server.public_key().to_vec();
+        (
+            client.finish(client_psk,Role::Client,&server_public_key).unwrap(),
+            server.finish(server_psk,Role::Server,&client_public_key).unwrap()
+        )
+    }
+
+    #[test]
+    fn seal_open_test() {
+        let key = handshake_key(&test_psk("password"));
+        let rng = SystemRandom::new();
+        let mut sealed = seal(&key,&rng,b"header",b"hello").unwrap();
+        assert_eq!(sealed.len(),aead::NONCE_LEN + 5 + key.algorithm().tag_len());
+        assert_eq!(open(&key,b"header",&mut sealed.clone()).unwrap(),b"hello");
+        assert!(open(&key,b"other header",&mut sealed).is_err());
+        let mut tampered = seal(&key,&rng,b"header",b"hello").unwrap();
+        tampered[0] ^= 1;
+        assert!(open(&key,b"header",&mut tampered).is_err());
+        assert!(open(&key,b"header",&mut [0u8;8]).is_err());
+    }
+
+    #[test]
+    fn seal_open_counted_test() {
+        let key = handshake_key(&test_psk("password"));
+        let mut sealed = seal_counted(&key,7,b"header",b"hello").unwrap();
+        assert_eq!(sealed.len(),5 + key.algorithm().tag_len());
+        assert_eq!(open_counted(&key,7,b"header",&mut sealed.clone()).unwrap(),b"hello");
+        assert!(open_counted(&key,8,b"header",&mut sealed.clone()).is_err());
+        assert!(open_counted(&key,7,b"other header",&mut sealed).is_err());
+        assert!(open_counted(&key,7,b"header",&mut [0u8;4]).is_err());
+    }
+
+    #[test]
+    fn handshake_test() {
+        let rng = SystemRandom::new();
+        let psk = test_psk("password");
+        let (client,server) = handshake(&psk,&psk);
+        let mut sealed = seal(&client.send,&rng,b"",b"ping").unwrap();
+        assert_eq!(open(&server.recv,b"",&mut sealed).unwrap(),b"ping");
+        let mut sealed = seal(&server.send,&rng,b"",b"pong").unwrap();
+        assert_eq!(open(&client.recv,b"",&mut sealed).unwrap(),b"pong");
+        let mut sealed = seal(&client.send,&rng,b"",b"ping").unwrap();
+        assert!(open(&client.recv,b"",&mut sealed).is_err());
+    }
+
+    #[test]
+    fn handshake_per_session_test() {
+        let rng = SystemRandom::new();
+        let psk = test_psk("password");
+        let (first,_) = handshake(&psk,&psk);
+        let (_,second) = handshake(&psk,&psk);
+        let mut sealed = seal(&first.send,&rng,b"",b"ping").unwrap();
+        assert!(open(&second.recv,b"",&mut sealed).is_err());
+    }
+
+    #[test]
+    fn handshake_authenticated_test() {
+        let rng = SystemRandom::new();
+        let psk = test_psk("password");
+        let client_private_key = StaticSecret::from(random_key(&rng).unwrap());
+        let client_public_key = PublicKey::from(&client_private_key);
+        let impostor_public_key = PublicKey::from(&StaticSecret::from(random_key(&rng).unwrap()));
+        for (claimed_public_key,matching) in [(client_public_key,true),(impostor_public_key,false)] {
+            let client = Handshake::new(&rng).unwrap();
+            let server = Handshake::new(&rng).unwrap();
+            let client_ephemeral = client.public_key().to_vec();
+            let server_ephemeral = server.public_key().to_vec();
+            let client = client
+                .finish_authenticated(&psk,Role::Client,&server_ephemeral,ClientKey::Private(&client_private_key))
+                .unwrap();
+            let server = server
+                .finish_authenticated(&psk,Role::Server,&client_ephemeral,ClientKey::Public(&claimed_public_key))
+                .unwrap();
+            let mut sealed = seal(&client.send,&rng,b"",b"ping").unwrap();
+            assert_eq!(open(&server.recv,b"",&mut sealed).is_ok(),matching);
+        }
+    }
+
+    #[test]
+    fn handshake_low_order_test() {
+        let rng = SystemRandom::new();
+        let client = Handshake::new(&rng).unwrap();
+        assert!(client.finish(&test_psk("password"),Role::Client,&[0u8;KEY_LEN]).is_err());
+        let client = Handshake::new(&rng).unwrap();
+        assert!(client.finish(&test_psk("password"),Role::Client,&[0u8;4]).is_err());
+    }
+
+    #[test]
+    fn handshake_wrong_psk_test() {
+        let rng = SystemRandom::new();
+        let (client,server) = handshake(&test_psk("password"),&test_psk("wrong"));
+        let mut sealed = seal(&client.send,&rng,b"",b"ping").unwrap();
+        assert!(open(&server.recv,b"",&mut sealed).is_err());
+    }
+
+    #[test]
+    fn derive_psk_test() {
+        let psk = test_psk("password");
+        assert_eq!(psk,test_psk("password"));
+        assert_ne!(psk,test_psk("wrong"));
+        assert_ne!(psk,derive_psk("password",&[8;SALT_LEN],&TEST_PARAMS).unwrap());
+        assert_ne!(psk,derive_legacy_psk("password"));
+        assert!(derive_psk("password",&[7;SALT_LEN],&KdfParams{ memory_kib:0, ..TEST_PARAMS }).is_err());
+    }
+
+    #[test]
+    fn kdf_params_check_test() {
+        assert!(KdfParams::default().check().is_ok());
+        assert!(KdfParams::MIN.check().is_ok());
+        assert!(TEST_PARAMS.check().is_err());
+        assert!(KdfParams{ iterations:1, ..KdfParams::MIN }.check().is_err());
+    }
+}
@@ -1,23 +1,29 @@
-const MTU: &'static str = "1380";
+const MTU: u32 = 1380;
 
-use std::{fs, io, mem, process};
-use std::ffi::c_void;
-use std::fmt::format;
+use std::{fs, io};
+#[cfg(target_os = "macos")]
+use std::process;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::io::{Read, Write};
-use std::net::SocketAddr;
-use std::os::fd::{AsFd, AsRawFd, FromRawFd, RawFd};
+use std::os::fd::{AsRawFd, RawFd};
+#[cfg(target_os = "macos")]
+use std::{ffi::c_void, mem, os::fd::FromRawFd};
 #[cfg(target_os = "linux")]
-use std::path;
-use libc::{aiocb, c_int, c_short, c_ulong, connect, ctl_info, F_SETFD, fcntl, FD_CLOEXEC, getsockname, getsockopt, IFNAMSIZ, ioctl, SOCK_DGRAM, sockaddr, sockaddr_ctl, socket, socklen_t, SYSPROTO_CONTROL};
-
+use std::{net::IpAddr, path};
+#[cfg(target_os = "linux")]
+use crate::netlink;
+use libc::ioctl;
 #[cfg(target_os = "linux")]
-const IF_NAM_SIZ: usize = 16;
+use libc::{c_short, c_ulong, IFNAMSIZ};
+#[cfg(target_os = "macos")]
+use libc::{c_int, c_ulong, connect, F_SETFD, fcntl, FD_CLOEXEC, getsockopt, SOCK_DGRAM, sockaddr, sockaddr_ctl, socket, socklen_t, SYSPROTO_CONTROL};
+
 #[cfg(target_os = "linux")]
 const IFF_TUN: c_short = 0x0001;
 #[cfg(target_os = "linux")]
 const IFF_NO_PI: c_short = 0x1000;
 #[cfg(all(target_os = "linux", target_env = "musl"))]
-const TUN_SET_IFF: c_int = 0x400454ca; // TODO: use _IOW('T', 202, int)
+const TUN_SET_IFF: libc::c_int = 0x400454ca; // TODO: use _IOW('T', 202, int)
 #[cfg(all(target_os = "linux", not(target_env = "musl")))]
 const TUN_SET_IFF: c_ulong = 0x400454ca; // TODO: use _IOW('T', 202, int)
 
@@ -38,7 +44,7 @@ const U_TUN_CONTROL_NAME:&'static str = "com.apple.net.utun_control";
 
 #[cfg(target_os = "linux")]
 #[repr(C)]
-pub struct io_ctl_flags_data {
+pub struct IoCtlFlagsData {
     pub ifr_name:[u8;IFNAMSIZ],
     pub ifr_flags:c_short
 }
@@ -75,16 +81,17 @@ impl AsRawFd for Tun{
 impl Tun{
     #[cfg(target_os = "linux")]
     pub fn create(name:u8) -> Result<Tun,io::Error> {
-         let path = path::Path::new("dev/net/tun");
+        let path = path::Path::new("/dev/net/tun");
         let file = fs::OpenOptions::new().
             read(true)
             .write(true)
-            .open(&path)?;
-        let mut req = io_ctl_flags_data{
+            .open(path)?;
+        let mut req = IoCtlFlagsData{
             ifr_name:{
                 let mut buffer = [0u8;IFNAMSIZ];
                 let full_name = format!("tun{}",name);
                 buffer[..full_name.len()].clone_from_slice(full_name.as_bytes());
+                buffer
             },
             ifr_flags:IFF_TUN | IFF_NO_PI,
         };
@@ -182,44 +189,74 @@ impl Tun{
         &self.if_name
     }
 
-    pub fn up(&self,self_id:u8) {
-        let mut status = if cfg!(target_os = "linux") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg(format!("10.10.10.{}/24", self_id))
-                .status()
-                .unwrap()
-        } else if cfg!(target_os = "macos") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg(format!("10.10.10.{}", self_id))
-                .arg("10.10.10.1")
-                .status()
-                .unwrap()
-        } else {
-            unimplemented!()
-        };
-        assert!(status.success());
-        status = if cfg!(target_os = "linux") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg("mtu")
-                .arg(MTU)
-                .arg("up")
-                .status().
-                unwrap()
-        } else if cfg!(target_os = "macos") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg("mtu")
-                .arg(MTU)
-                .arg("up")
-                .status()
-                .unwrap()
-        } else {
-            unimplemented!()
-        };
-        assert!(status.success())
+    /// Makes reads return `WouldBlock` instead of waiting, so the device can be drained
+    /// after an edge-triggered readiness event.
+    pub fn set_nonblocking(&self) -> io::Result<()> {
+        let flags = unsafe { libc::fcntl(self.as_raw_fd(), libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let res = unsafe { libc::fcntl(self.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Assigns `address` with `prefix` to the device and brings it up. On macOS the device
+    /// is point to point, with `gateway` at the other end.
+    #[cfg(target_os = "linux")]
+    pub fn up(&self,address:Ipv4Addr,prefix:u8,_gateway:Ipv4Addr) -> io::Result<()> {
+        netlink::add_address(&self.if_name,IpAddr::V4(address),prefix)?;
+        netlink::set_link_up(&self.if_name,MTU)
+    }
+
+    /// Assigns `address` with `prefix` to the device and brings it up. On macOS the device
+    /// is point to point, with `gateway` at the other end.
+    #[cfg(target_os = "macos")]
+    pub fn up(&self,address:Ipv4Addr,_prefix:u8,gateway:Ipv4Addr) -> io::Result<()> {
+        let status = process::Command::new("ifconfig")
+            .arg(self.if_name.clone())
+            .arg(address.to_string())
+            .arg(gateway.to_string())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("ifconfig: {}", status)));
+        }
+        let status = process::Command::new("ifconfig")
+            .arg(self.if_name.clone())
+            .arg("mtu")
+            .arg(MTU.to_string())
+            .arg("up")
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("ifconfig: {}", status)));
+        }
+        Ok(())
+    }
+}
+
+impl Tun {
+    /// Adds an IPv6 address to a device brought up with [`Tun::up`].
+    #[cfg(target_os = "linux")]
+    pub fn add_ipv6(&self,address:Ipv6Addr,prefix:u8) -> io::Result<()> {
+        netlink::add_address(&self.if_name,IpAddr::V6(address),prefix)
+    }
+
+    /// Adds an IPv6 address to a device brought up with [`Tun::up`].
+    #[cfg(target_os = "macos")]
+    pub fn add_ipv6(&self,address:Ipv6Addr,prefix:u8) -> io::Result<()> {
+        let status = process::Command::new("ifconfig")
+            .arg(self.if_name.clone())
+            .arg("inet6")
+            .arg(address.to_string())
+            .arg("prefixlen")
+            .arg(prefix.to_string())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("ifconfig: {}", status)));
+        }
+        Ok(())
     }
 }
 
@@ -249,11 +286,11 @@ impl Write for Tun {
     }
     #[cfg(target_os = "macos")]
     fn write(&mut self,buf:&[u8]) -> io::Result<usize> {
-        let ip_v = buf[0] & 0xf;
+        let ip_v = buf[0] >> 4;
         let mut data : Vec<u8> = if ip_v == 6 {
-            vec![0,0,0,10]
+            vec![0,0,0,libc::AF_INET6 as u8]
         } else {
-            vec![0,0,0,2]
+            vec![0,0,0,libc::AF_INET as u8]
         };
         data.write_all(buf).unwrap();
         match self.handle.write(&data) {
@@ -278,11 +315,19 @@ mod tests {
         assert!(utils::is_root());
         let tun = Tun::create(10).unwrap();
         let name = tun.name();
-        let output = process::Command::new("iconfig")
+        let output = process::Command::new("ifconfig")
             .arg(name)
             .output()
             .expect("failed to create tun device");
         assert!(output.status.success());
-        tun.up(1);
+        let address = Ipv4Addr::new(10,10,10,1);
+        tun.up(address,24,address).unwrap();
+        tun.add_ipv6("fd00:e:e:10::1".parse().unwrap(),64).unwrap();
+        let output = process::Command::new("ip").args(["address","show",name]).output().unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(output.contains("mtu 1380"));
+        assert!(output.contains(",UP"));
+        assert!(output.contains("inet 10.10.10.1/24"));
+        assert!(output.contains("inet6 fd00:e:e:10::1/64"));
     }
 }
\ No newline at end of file
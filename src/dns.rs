@@ -0,0 +1,255 @@
+//! Points the system resolver at the DNS servers the server hands out.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+
+use log::{info, warn};
+
+use crate::error::Error;
+use crate::journal::{Journal, Undo};
+use crate::utils;
+
+/// Resolver configuration the system takes its DNS servers from.
+pub const RESOLV_CONF:&str = "/etc/resolv.conf";
+/// Runtime directory of systemd-resolved, holding the files it points /etc/resolv.conf at.
+const RESOLVED_DIR:&str = "/run/systemd/resolve";
+/// Where macOS looks for the resolvers of single domains, one file named after each.
+const RESOLVER_DIR:&str = "/etc/resolver";
+/// Name servers the C library reads from resolv.conf, later ones are ignored.
+const MAX_NAMESERVERS:usize = 3;
+
+/// DNS servers and search domains for a client.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Config {
+    pub servers:Vec<IpAddr>,
+    pub search:Vec<String>,
+    /// Domains resolved through the servers while other names go to the local resolver,
+    /// every name if empty.
+    pub domains:Vec<String>
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+        let servers:Vec<String> = self.servers.iter().map(|server| server.to_string()).collect();
+        write!(f,"{}",servers.join(", "))?;
+        if !self.search.is_empty() {
+            write!(f," searching {}",self.search.join(", "))?;
+        }
+        if !self.domains.is_empty() {
+            write!(f," only for {}",self.domains.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `domain` is a host name, which keeps it from breaking the files it is
+/// written to.
+pub fn check_domain(domain:&str) -> Result<(),String> {
+    let valid = domain.len() <= 253 && domain.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{}: not a domain name",domain))
+    }
+}
+
+/// Whether systemd-resolved manages the resolver, in which case /etc/resolv.conf links into
+/// its runtime directory. The link is resolved first, as it is usually relative.
+fn uses_resolved() -> bool {
+    fs::canonicalize(RESOLV_CONF).is_ok_and(|target| target.starts_with(RESOLVED_DIR))
+}
+
+/// Points the resolver at `config`. With systemd-resolved the servers are only set on
+/// `interface` and go away with it, answering every query if `default` is set and `config`
+/// names no domains. On macOS the domains get a resolver file each. Elsewhere domains can't be
+/// split, so the system DNS is left alone, and without domains /etc/resolv.conf is rewritten.
+/// Changed files are noted in `journal`.
+pub fn configure(journal:&mut Journal,interface:&str,config:&Config,default:bool) -> Result<(),Error> {
+    if uses_resolved() {
+        info!("Setting DNS of {} with systemd-resolved.", interface);
+        for command in resolvectl_commands(interface,config,default) {
+            utils::run_command(&command)?;
+        }
+        Ok(())
+    } else if cfg!(target_os = "macos") && !config.domains.is_empty() {
+        write_resolver_files(journal,RESOLVER_DIR,config)
+    } else if !config.domains.is_empty() {
+        warn!(
+            "Resolving only {} through the tunnel needs systemd-resolved, leaving the system DNS as it is.",
+            config.domains.join(", ")
+        );
+        Ok(())
+    } else {
+        write_resolv_conf(journal,RESOLV_CONF,config)
+    }
+}
+
+/// Commands setting the servers and search domains of `interface`. The `~.` routing domain
+/// sends queries for every domain to it, `~domain` only those in the domain, and the
+/// interface stops answering other queries when `config` names domains.
+fn resolvectl_commands(interface:&str,config:&Config,default:bool) -> Vec<Vec<String>> {
+    let command = |args:&[&str],values:Vec<String>| {
+        args.iter().map(|arg| arg.to_string()).chain(values).collect::<Vec<String>>()
+    };
+    let mut domains = config.search.clone();
+    domains.extend(config.domains.iter().map(|domain| format!("~{}",domain)));
+    if default && config.domains.is_empty() {
+        domains.push(String::from("~."));
+    }
+    let mut commands = vec![command(
+        &["resolvectl","dns",interface],
+        config.servers.iter().map(|server| server.to_string()).collect()
+    )];
+    if !domains.is_empty() {
+        commands.push(command(&["resolvectl","domain",interface],domains));
+    }
+    if !config.domains.is_empty() {
+        commands.push(command(&["resolvectl","default-route",interface,"false"],Vec::new()));
+    }
+    commands
+}
+
+/// Writes a resolver file for each domain of `config` into `dir`, noting the files it
+/// replaces or creates in `journal`.
+fn write_resolver_files(journal:&mut Journal,dir:&str,config:&Config) -> Result<(),Error> {
+    fs::create_dir_all(dir).map_err(Error::Io)?;
+    let mut contents = String::from("# Written by e-net, restored when it exits.\n");
+    for server in &config.servers {
+        contents += &format!("nameserver {}\n",server);
+    }
+    for domain in &config.domains {
+        let path = format!("{}/{}",dir,domain);
+        let previous = match fs::read(&path) {
+            Ok(previous) => Some(previous),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::Io(e))
+        };
+        journal.record(Undo::File{ path:path.clone(), contents:previous })?;
+        info!("Resolving {} through the tunnel.", domain);
+        fs::write(&path,&contents).map_err(Error::Io)?;
+    }
+    Ok(())
+}
+
+fn write_resolv_conf(journal:&mut Journal,path:&str,config:&Config) -> Result<(),Error> {
+    let contents = match fs::read(path) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(Error::Io(e))
+    };
+    let previous = contents.as_deref().map(String::from_utf8_lossy).unwrap_or_default().to_string();
+    journal.record(Undo::File{ path:path.to_string(), contents })?;
+    if config.servers.len() > MAX_NAMESERVERS {
+        warn!("Only the first {} DNS servers are used.", MAX_NAMESERVERS);
+    }
+    info!("Writing DNS servers to {}.", path);
+    fs::write(path,resolv_conf(config,&previous)).map_err(Error::Io)
+}
+
+/// Contents of resolv.conf for `config`, keeping the options of the `previous` contents.
+fn resolv_conf(config:&Config,previous:&str) -> String {
+    let mut contents = String::from("# Written by e-net, restored when it exits.\n");
+    for server in &config.servers {
+        contents += &format!("nameserver {}\n",server);
+    }
+    if !config.search.is_empty() {
+        contents += &format!("search {}\n",config.search.join(" "));
+    }
+    for options in previous.lines().filter(|line| line.trim_start().starts_with("options")) {
+        contents += options;
+        contents.push('\n');
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use crate::dns::*;
+
+    fn config() -> Config {
+        Config{
+            servers:vec!["10.10.10.1".parse().unwrap(),"fd00::1".parse().unwrap()],
+            search:vec![String::from("corp.example"),String::from("example")],
+            domains:Vec::new()
+        }
+    }
+
+    #[test]
+    fn check_domain_test() {
+        for valid in ["example","corp.example.com","_srv.example","a-b.c"] {
+            assert!(check_domain(valid).is_ok());
+        }
+        for invalid in ["","example.","a..b","with space","line\nbreak","x".repeat(64).as_str()] {
+            assert!(check_domain(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn resolv_conf_test() {
+        let previous = "nameserver 192.0.2.53\noptions edns0 trust-ad\nsearch lan\n";
+        assert_eq!(
+            resolv_conf(&config(),previous),
+            "# Written by e-net, restored when it exits.\n\
+             nameserver 10.10.10.1\n\
+             nameserver fd00::1\n\
+             search corp.example example\n\
+             options edns0 trust-ad\n"
+        );
+        let servers = Config{ servers:config().servers, ..Config::default() };
+        assert!(!resolv_conf(&servers,"").contains("search"));
+    }
+
+    #[test]
+    fn resolvectl_commands_test() {
+        let commands = resolvectl_commands("tun0",&config(),true);
+        assert_eq!(commands[0].join(" "),"resolvectl dns tun0 10.10.10.1 fd00::1");
+        assert_eq!(commands[1].join(" "),"resolvectl domain tun0 corp.example example ~.");
+        let servers = Config{ servers:config().servers, ..Config::default() };
+        assert_eq!(resolvectl_commands("tun0",&servers,false).len(),1);
+        let split = Config{ domains:vec![String::from("corp.example"),String::from("vpn")], ..config() };
+        let commands = resolvectl_commands("tun0",&split,true);
+        assert_eq!(commands[1].join(" "),"resolvectl domain tun0 corp.example example ~corp.example ~vpn");
+        assert_eq!(commands[2].join(" "),"resolvectl default-route tun0 false");
+        assert_eq!(split.to_string(),"10.10.10.1, fd00::1 searching corp.example, example only for corp.example, vpn");
+    }
+
+    #[test]
+    fn write_resolver_files_test() {
+        let dir = env::temp_dir().join(format!("e-net-{}-resolver",process::id()));
+        let dir = dir.to_str().unwrap();
+        fs::create_dir_all(dir).unwrap();
+        fs::write(format!("{}/vpn",dir),"nameserver 192.0.2.53\n").unwrap();
+        let split = Config{ domains:vec![String::from("corp.example"),String::from("vpn")], ..config() };
+        let mut journal = Journal::open(&format!("{}.state",dir)).unwrap();
+        write_resolver_files(&mut journal,dir,&split).unwrap();
+        for domain in ["corp.example","vpn"] {
+            let contents = fs::read_to_string(format!("{}/{}",dir,domain)).unwrap();
+            assert!(contents.ends_with("nameserver 10.10.10.1\nnameserver fd00::1\n"));
+        }
+        drop(journal);
+        assert!(fs::metadata(format!("{}/corp.example",dir)).is_err());
+        assert_eq!(fs::read_to_string(format!("{}/vpn",dir)).unwrap(),"nameserver 192.0.2.53\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_resolv_conf_test() {
+        let path = env::temp_dir().join(format!("e-net-{}-resolv.conf",process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path,"nameserver 192.0.2.53\n").unwrap();
+        let mut journal = Journal::open(&format!("{}.state",path)).unwrap();
+        write_resolv_conf(&mut journal,path,&config()).unwrap();
+        assert!(fs::read_to_string(path).unwrap().contains("nameserver 10.10.10.1\nnameserver fd00::1\n"));
+        drop(journal);
+        assert_eq!(fs::read_to_string(path).unwrap(),"nameserver 192.0.2.53\n");
+        fs::remove_file(path).unwrap();
+    }
+}
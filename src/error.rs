@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::{fmt, io};
+
+use log::warn;
+
+/// Errors of the tunnel's event loops and the system plumbing around them.
+#[derive(Debug)]
+pub enum Error {
+    /// Sending or receiving on the UDP socket failed.
+    Socket(io::Error),
+    /// Reading from or writing to the TUN device failed.
+    Tun(io::Error),
+    /// Any other system call failed, e.g. setting up polling.
+    Io(io::Error),
+    /// A datagram or message could not be parsed.
+    Decode(String),
+    /// A datagram failed authentication or did not belong to a live key.
+    Decrypt(String),
+    /// A tunneled packet could not be compressed or decompressed.
+    Compression(snap::Error),
+    /// Deriving or using keys failed.
+    Crypto(String),
+    /// A handshake or rekey was refused or could not be completed.
+    Handshake(String),
+    /// A packet from the TUN device has no session to go to.
+    Route(String),
+    /// A packet was dropped because the server's policy forbids it.
+    Policy(String),
+    /// The server's host name could not be resolved.
+    Resolve(String),
+    /// An option or file given on the command line is unusable.
+    Config(String),
+    /// An external command could not be run or reported failure.
+    Command{command:String,reason:String},
+    /// The kernel refused to change or report routes, addresses or links.
+    Netlink{request:String,error:io::Error}
+}
+
+impl Error {
+    /// Short name of the kind of failure, used to count failures by kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Socket(_) => "socket",
+            Error::Tun(_) => "tun",
+            Error::Io(_) => "io",
+            Error::Decode(_) => "decode",
+            Error::Decrypt(_) => "decrypt",
+            Error::Compression(_) => "compression",
+            Error::Crypto(_) => "crypto",
+            Error::Handshake(_) => "handshake",
+            Error::Route(_) => "route",
+            Error::Policy(_) => "policy",
+            Error::Resolve(_) => "resolve",
+            Error::Config(_) => "config",
+            Error::Command{..} => "command",
+            Error::Netlink{..} => "netlink"
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Socket(e) => write!(f,"socket: {}",e),
+            Error::Tun(e) => write!(f,"TUN device: {}",e),
+            Error::Io(e) => write!(f,"{}",e),
+            Error::Decode(e) => write!(f,"malformed message: {}",e),
+            Error::Decrypt(e) => write!(f,"rejected datagram: {}",e),
+            Error::Compression(e) => write!(f,"compression: {}",e),
+            Error::Crypto(e) => write!(f,"crypto: {}",e),
+            Error::Handshake(e) => write!(f,"handshake: {}",e),
+            Error::Route(e) => write!(f,"no route: {}",e),
+            Error::Policy(e) => write!(f,"dropped: {}",e),
+            Error::Resolve(e) => write!(f,"resolve: {}",e),
+            Error::Config(e) => write!(f,"{}",e),
+            Error::Command{command,reason} => write!(f,"{}: {}",command,reason),
+            Error::Netlink{request,error} => write!(f,"{}: {}",request,error)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Socket(e) | Error::Tun(e) | Error::Io(e) | Error::Netlink{ error:e, .. } => Some(e),
+            Error::Compression(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Where a failure in an event loop came from.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum Source {
+    Peer(SocketAddr),
+    Tun,
+    Dns
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Peer(addr) => write!(f,"{}",addr),
+            Source::Tun => write!(f,"TUN device"),
+            Source::Dns => write!(f,"DNS forwarder")
+        }
+    }
+}
+
+/// Most sources tracked at once. Beyond that the counts start over, so a flood of spoofed
+/// addresses cannot grow the table without bound.
+const MAX_SOURCES:usize = 4096;
+
+/// Counts the failures of an event loop per source and kind.
+///
+/// A failure is logged when its count reaches a power of two, so a stream of garbage
+/// datagrams shows up in the log without flooding it.
+pub struct ErrorLog {
+    counts:HashMap<(Source,&'static str),u64>
+}
+
+impl ErrorLog {
+    pub fn new() -> ErrorLog {
+        ErrorLog{ counts:HashMap::new() }
+    }
+
+    pub fn record(&mut self,source:Source,error:&Error) {
+        let key = (source,error.kind());
+        if !self.counts.contains_key(&key) && self.counts.len() >= MAX_SOURCES {
+            self.counts.clear();
+        }
+        let count = self.counts.entry(key).or_insert(0);
+        *count += 1;
+        if count.is_power_of_two() {
+            warn!("{}: {} ({} {} failures so far)", source, error, count, error.kind());
+        }
+    }
+
+    #[cfg(test)]
+    pub fn count(&self,source:Source,kind:&'static str) -> u64 {
+        self.counts.get(&(source,kind)).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::error::*;
+
+    #[test]
+    fn error_log_test() {
+        let mut errors = ErrorLog::new();
+        let peer = Source::Peer(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST),8964));
+        for _ in 0..3 {
+            errors.record(peer,&Error::Decrypt(String::from("aead::open")));
+        }
+        errors.record(Source::Tun,&Error::Route(String::from("10.10.10.9")));
+        assert_eq!(errors.count(peer,"decrypt"),3);
+        assert_eq!(errors.count(peer,"route"),0);
+        assert_eq!(errors.count(Source::Tun,"route"),1);
+    }
+
+    #[test]
+    fn error_log_bounded_test() {
+        let mut errors = ErrorLog::new();
+        for port in 0..=MAX_SOURCES as u16 {
+            let peer = Source::Peer(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST),port));
+            errors.record(peer,&Error::Decode(String::from("bad magic")));
+        }
+        assert_eq!(errors.counts.len(),1);
+    }
+}
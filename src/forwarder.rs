@@ -0,0 +1,622 @@
+//! DNS forwarder the server runs on its tunnel addresses, answering clients in the tunnel
+//! subnets only.
+//!
+//! Names under the VPN domain, e.g. `laptop.vpn`, are answered with the addresses of the
+//! connected client of that name. Other queries go to the upstream servers, trying the next
+//! one when an answer takes too long, and answers to plain queries are cached for their TTL.
+//! Only UDP is served, so clients get truncated answers as they are and retry elsewhere.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::info;
+use rand::rngs::ThreadRng;
+use rand::{thread_rng, Rng};
+
+use crate::error::{Error, ErrorLog, Source};
+use crate::route::Prefix;
+
+pub const DNS_PORT:u16 = 53;
+const HEADER_LEN:usize = 12;
+const TYPE_A:u16 = 1;
+const TYPE_AAAA:u16 = 28;
+const TYPE_OPT:u16 = 41;
+const CLASS_IN:u16 = 1;
+const RCODE_NXDOMAIN:u8 = 3;
+const RCODE_NOTIMP:u8 = 4;
+/// TTL of the records of connected clients, kept short as leases come and go.
+const RECORD_TTL:u32 = 60;
+/// Longest time an answer is cached, whatever its TTL.
+const MAX_TTL:u32 = 3600;
+/// Most answers cached, expired ones are dropped to make room and then the whole cache.
+const MAX_CACHED:usize = 4096;
+/// Most queries waiting for an upstream answer.
+const MAX_PENDING:usize = 4096;
+/// How long an upstream server has to answer before the query goes to the next one.
+const QUERY_TIMEOUT:Duration = Duration::from_secs(2);
+/// Largest DNS message taken over UDP.
+const MAX_MESSAGE_LEN:usize = 4096;
+
+/// Question of a DNS message, the name in lower case without the final dot.
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+struct Question {
+    name:String,
+    kind:u16,
+    class:u16
+}
+
+fn u16_at(message:&[u8],offset:usize) -> Result<u16,String> {
+    message
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0],bytes[1]]))
+        .ok_or(String::from("truncated message"))
+}
+
+/// Reads the name at `offset`, following compression pointers, and returns it with the
+/// offset after it.
+fn read_name(message:&[u8],mut offset:usize) -> Result<(String,usize),String> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = usize::from(*message.get(offset).ok_or("truncated name")?);
+        match len {
+            0 => {
+                offset += 1;
+                break;
+            }
+            len if len & 0xc0 == 0xc0 => {
+                end.get_or_insert(offset + 2);
+                jumps += 1;
+                if jumps > 16 {
+                    return Err(String::from("compression loop"));
+                }
+                offset = usize::from(u16_at(message,offset)? & 0x3fff);
+            }
+            len if len & 0xc0 != 0 => return Err(format!("label type {:#x}",len & 0xc0)),
+            len => {
+                let label = message.get(offset + 1..offset + 1 + len).ok_or("truncated label")?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + len;
+            }
+        }
+    }
+    Ok((labels.join("."),end.unwrap_or(offset)))
+}
+
+/// The single question of `message` and the offset after it.
+fn question(message:&[u8]) -> Result<(Question,usize),String> {
+    if u16_at(message,4)? != 1 {
+        return Err(String::from("expected exactly one question"));
+    }
+    let (name,offset) = read_name(message,HEADER_LEN)?;
+    let question = Question{ name, kind:u16_at(message,offset)?, class:u16_at(message,offset + 2)? };
+    Ok((question,offset + 4))
+}
+
+/// Whether `query` is a plain one, without EDNS or other additional records and with
+/// checking enabled, whose answers can be cached by question alone. Answers to EDNS queries
+/// may be larger or carry DNSSEC records a plain query must not get back.
+fn plain(query:&[u8]) -> bool {
+    u16_at(query,10) == Ok(0) && query[3] & 0x10 == 0
+}
+
+/// Offsets of the TTLs of every record after the question, leaving out the EDNS
+/// pseudo-record whose TTL field holds flags.
+fn ttl_offsets(message:&[u8]) -> Result<Vec<usize>,String> {
+    let (_,mut offset) = question(message)?;
+    let records = (6..12).step_by(2).map(|count| u16_at(message,count).map(u32::from)).sum::<Result<u32,String>>()?;
+    let mut offsets = Vec::new();
+    for _ in 0..records {
+        let (_,after) = read_name(message,offset)?;
+        if u16_at(message,after)? != TYPE_OPT {
+            offsets.push(after + 4);
+        }
+        offset = after + 10 + usize::from(u16_at(message,after + 8)?);
+        if offset > message.len() {
+            return Err(String::from("truncated record"));
+        }
+    }
+    Ok(offsets)
+}
+
+fn ttl_at(message:&[u8],offset:usize) -> u32 {
+    u32::from_be_bytes([message[offset],message[offset + 1],message[offset + 2],message[offset + 3]])
+}
+
+/// Answer to the query ending at `question_end` with `rcode` and the `addresses` as records
+/// of the queried type.
+fn answer(query:&[u8],question_end:usize,rcode:u8,addresses:&[IpAddr]) -> Vec<u8> {
+    let mut response = query[..question_end].to_vec();
+    response[2] = 0x84 | (query[2] & 0x79);
+    response[3] = 0x80 | rcode;
+    response[6..8].copy_from_slice(&(addresses.len() as u16).to_be_bytes());
+    response[8..12].fill(0);
+    for address in addresses {
+        let (kind,data) = match address {
+            IpAddr::V4(address) => (TYPE_A,address.octets().to_vec()),
+            IpAddr::V6(address) => (TYPE_AAAA,address.octets().to_vec())
+        };
+        response.extend_from_slice(&[0xc0,HEADER_LEN as u8]);
+        response.extend_from_slice(&kind.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        response.extend_from_slice(&data);
+    }
+    response
+}
+
+/// Answer from an upstream server, kept until its shortest TTL runs out.
+struct Cached {
+    response:Vec<u8>,
+    ttls:Vec<usize>,
+    stored:Instant,
+    ttl:u32
+}
+
+impl Cached {
+    /// The answer for a query with `id`, its TTLs lowered by the time it was cached, or
+    /// `None` once it expired.
+    fn response(&self,id:&[u8]) -> Option<Vec<u8>> {
+        let age = self.stored.elapsed().as_secs() as u32;
+        if age >= self.ttl {
+            return None;
+        }
+        let mut response = self.response.clone();
+        response[..2].copy_from_slice(id);
+        for &offset in &self.ttls {
+            let ttl = ttl_at(&response,offset).saturating_sub(age);
+            response[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(response)
+    }
+}
+
+/// Query forwarded upstream under an ID of its own.
+struct Pending {
+    client:SocketAddr,
+    listener:usize,
+    id:[u8;2],
+    question:Question,
+    query:Vec<u8>,
+    upstream:usize,
+    sent:Instant
+}
+
+/// Sockets and state of the forwarder, driven by the server's event loop.
+pub struct Forwarder {
+    listeners:Vec<mio::net::UdpSocket>,
+    /// Subnets queries are taken from, so only tunnel clients can use the forwarder.
+    clients:Vec<Prefix>,
+    socket:mio::net::UdpSocket,
+    upstreams:Vec<SocketAddr>,
+    domain:String,
+    cache:HashMap<Question,Cached>,
+    pending:HashMap<u16,Pending>,
+    first:usize,
+    rng:ThreadRng
+}
+
+fn nonblocking(socket:UdpSocket) -> Result<mio::net::UdpSocket,Error> {
+    socket.set_nonblocking(true).map_err(Error::Socket)?;
+    Ok(mio::net::UdpSocket::from_std(socket))
+}
+
+impl Forwarder {
+    /// Listens on `listen` for queries from the `clients` subnets, forwarding to `upstreams`
+    /// and answering names under `domain`.
+    pub fn bind(
+        listen:&[SocketAddr],
+        clients:Vec<Prefix>,
+        upstreams:Vec<SocketAddr>,
+        domain:&str
+    ) -> Result<Forwarder,Error> {
+        let listeners = listen
+            .iter()
+            .map(|addr| {
+                let socket = UdpSocket::bind(addr).map_err(|e|Error::Config(format!("DNS forwarder on {}: {}",addr,e)))?;
+                nonblocking(socket)
+            })
+            .collect::<Result<Vec<_>,Error>>()?;
+        let unspecified = if upstreams.iter().all(|upstream| upstream.is_ipv4()) {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let socket = nonblocking(UdpSocket::bind(SocketAddr::new(unspecified,0)).map_err(Error::Socket)?)?;
+        for addr in listen {
+            info!("DNS forwarder listening on {}.", addr);
+        }
+        Ok(Forwarder{
+            listeners,
+            clients,
+            socket,
+            upstreams,
+            domain:domain.to_ascii_lowercase(),
+            cache:HashMap::new(),
+            pending:HashMap::new(),
+            first:0,
+            rng:thread_rng()
+        })
+    }
+
+    /// Registers the upstream socket under `first` and the listeners under the tokens after
+    /// it.
+    pub fn register(&mut self,registry:&mio::Registry,first:mio::Token) -> Result<(),Error> {
+        self.first = first.0;
+        registry
+            .register(&mut self.socket,first,mio::Interest::READABLE)
+            .map_err(Error::Io)?;
+        for (index,listener) in self.listeners.iter_mut().enumerate() {
+            registry
+                .register(listener,mio::Token(first.0 + 1 + index),mio::Interest::READABLE)
+                .map_err(Error::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `token` is one of the forwarder's.
+    pub fn owns(&self,token:mio::Token) -> bool {
+        (self.first..=self.first + self.listeners.len()).contains(&token.0)
+    }
+
+    /// Handles every datagram waiting on the socket of `token`. Names under the domain are
+    /// looked up with `lookup`.
+    pub fn ready(
+        &mut self,
+        token:mio::Token,
+        lookup:impl Fn(&str) -> Vec<IpAddr>,
+        errors:&mut ErrorLog
+    ) -> Result<(),Error> {
+        let mut buf = [0u8;MAX_MESSAGE_LEN];
+        let index = token.0 - self.first;
+        loop {
+            let socket = if index == 0 { &self.socket } else { &self.listeners[index - 1] };
+            let (len,addr) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(Error::Socket(e))
+            };
+            let result = if index == 0 {
+                self.answer(&mut buf[..len],addr)
+            } else {
+                self.query(index - 1,&buf[..len],addr,&lookup)
+            };
+            if let Err(e) = result {
+                errors.record(Source::Peer(addr),&e);
+            }
+        }
+    }
+
+    fn query(
+        &mut self,
+        listener:usize,
+        query:&[u8],
+        client:SocketAddr,
+        lookup:&impl Fn(&str) -> Vec<IpAddr>
+    ) -> Result<(),Error> {
+        if !self.clients.iter().any(|subnet| subnet.contains(client.ip().to_canonical())) {
+            return Err(Error::Policy(String::from("DNS query from outside the tunnel")));
+        }
+        if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+            return Err(Error::Decode(String::from("not a DNS query")));
+        }
+        let (question,question_end) = question(query).map_err(Error::Decode)?;
+        let response = if (query[2] >> 3) & 0x0f != 0 {
+            Some(answer(query,question_end,RCODE_NOTIMP,&[]))
+        } else if let Some(host) = self.local_host(&question.name) {
+            let addresses = host.map(lookup).unwrap_or_default();
+            let records:Vec<IpAddr> = addresses
+                .iter()
+                .copied()
+                .filter(|address| match question.kind {
+                    TYPE_A => address.is_ipv4(),
+                    TYPE_AAAA => address.is_ipv6(),
+                    _ => false
+                })
+                .collect();
+            let rcode = if addresses.is_empty() && host.is_some() { RCODE_NXDOMAIN } else { 0 };
+            Some(answer(query,question_end,rcode,&records))
+        } else if plain(query) {
+            self.cache.get(&question).and_then(|cached| cached.response(&query[..2]))
+        } else {
+            None
+        };
+        if let Some(response) = response {
+            return self.listeners[listener].send_to(&response,client).map(|_| ()).map_err(Error::Socket);
+        }
+        if self.pending.len() >= MAX_PENDING {
+            return Err(Error::Policy(String::from("too many DNS queries waiting for upstream")));
+        }
+        let id = loop {
+            let id = self.rng.gen::<u16>();
+            if !self.pending.contains_key(&id) {
+                break id;
+            }
+        };
+        let mut forwarded = query.to_vec();
+        forwarded[..2].copy_from_slice(&id.to_be_bytes());
+        self.socket.send_to(&forwarded,self.upstreams[0]).map_err(Error::Socket)?;
+        self.pending.insert(id,Pending{
+            client,
+            listener,
+            id:[query[0],query[1]],
+            question,
+            query:forwarded,
+            upstream:0,
+            sent:Instant::now()
+        });
+        Ok(())
+    }
+
+    /// For a name under the domain, the client name it asks for, if it names one.
+    fn local_host<'a>(&self,name:&'a str) -> Option<Option<&'a str>> {
+        if name == self.domain {
+            return Some(None);
+        }
+        let host = name.strip_suffix(&self.domain)?.strip_suffix('.')?;
+        Some(Some(host).filter(|host| !host.contains('.')))
+    }
+
+    /// Passes an upstream answer on to the client that asked, caching it if its records can be
+    /// read.
+    fn answer(&mut self,response:&mut [u8],upstream:SocketAddr) -> Result<(),Error> {
+        if response.len() < HEADER_LEN || response[2] & 0x80 == 0 {
+            return Err(Error::Decode(String::from("not a DNS response")));
+        }
+        let id = u16::from_be_bytes([response[0],response[1]]);
+        let expected = self.pending.get(&id).map(|pending| self.upstreams[pending.upstream]);
+        let upstream = SocketAddr::new(upstream.ip().to_canonical(),upstream.port());
+        if expected != Some(upstream) {
+            return Err(Error::Decode(format!("unexpected DNS response {}",id)));
+        }
+        let (question,_) = question(response).map_err(Error::Decode)?;
+        if question != self.pending[&id].question {
+            return Err(Error::Decode(format!("DNS response {} for another question",id)));
+        }
+        let pending = self.pending.remove(&id).expect("checked above");
+        response[..2].copy_from_slice(&pending.id);
+        let truncated = response[2] & 0x02 != 0;
+        let rcode = response[3] & 0x0f;
+        let cacheable = !truncated && (rcode == 0 || rcode == RCODE_NXDOMAIN) && plain(&pending.query);
+        if let Some(ttls) = ttl_offsets(response).ok().filter(|_| cacheable) {
+            let ttl = ttls.iter().map(|&offset| ttl_at(response,offset)).min().unwrap_or(0).min(MAX_TTL);
+            if ttl > 0 {
+                self.cache_answer(pending.question,Cached{ response:response.to_vec(), ttls, stored:Instant::now(), ttl });
+            }
+        }
+        self.listeners[pending.listener].send_to(response,pending.client).map(|_| ()).map_err(Error::Socket)
+    }
+
+    fn cache_answer(&mut self,question:Question,cached:Cached) {
+        if self.cache.len() >= MAX_CACHED {
+            self.cache.retain(|_,cached| cached.stored.elapsed().as_secs() < u64::from(cached.ttl));
+            if self.cache.len() >= MAX_CACHED {
+                self.cache.clear();
+            }
+        }
+        self.cache.insert(question,cached);
+    }
+
+    /// Sends queries upstream servers took too long with to the next server, giving up on
+    /// them after the last.
+    pub fn prune(&mut self) {
+        let upstreams = self.upstreams.len();
+        self.pending.retain(|_,pending| pending.sent.elapsed() < QUERY_TIMEOUT || pending.upstream + 1 < upstreams);
+        for pending in self.pending.values_mut().filter(|pending| pending.sent.elapsed() >= QUERY_TIMEOUT) {
+            pending.upstream += 1;
+            pending.sent = Instant::now();
+            let _ = self.socket.send_to(&pending.query,self.upstreams[pending.upstream]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::forwarder::*;
+
+    /// A query for `name` of `kind` with `id`, asking for recursion.
+    fn query(id:u16,name:&str,kind:u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[1,0,0,1,0,0,0,0,0,0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&kind.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    fn forwarder(upstream:SocketAddr) -> (Forwarder,mio::Poll) {
+        let poll = mio::Poll::new().unwrap();
+        let mut forwarder = Forwarder::bind(&["127.0.0.1:0".parse().unwrap()],vec!["127.0.0.0/8".parse().unwrap()],vec![upstream],"VPN").unwrap();
+        forwarder.register(poll.registry(),mio::Token(2)).unwrap();
+        (forwarder,poll)
+    }
+
+    /// Handles the events of one turn of the event loop.
+    fn turn(forwarder:&mut Forwarder,poll:&mut mio::Poll) {
+        let mut events = mio::Events::with_capacity(8);
+        poll.poll(&mut events,Some(Duration::from_millis(200))).unwrap();
+        let mut errors = ErrorLog::new();
+        for event in events.iter() {
+            assert!(forwarder.owns(event.token()));
+            let lookup = |name:&str| match name {
+                "laptop" => vec!["10.10.10.2".parse().unwrap(),"fd00::2".parse().unwrap()],
+                _ => Vec::new()
+            };
+            forwarder.ready(event.token(),lookup,&mut errors).unwrap();
+        }
+    }
+
+    fn client() -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        client
+    }
+
+    #[test]
+    fn read_name_test() {
+        let message = query(7,"Laptop.VPN",TYPE_A);
+        assert_eq!(question(&message).unwrap(),(Question{ name:String::from("laptop.vpn"), kind:TYPE_A, class:CLASS_IN },message.len()));
+        let mut pointer = message.clone();
+        pointer.extend_from_slice(&[0xc0,12]);
+        assert_eq!(read_name(&pointer,message.len()).unwrap(),(String::from("laptop.vpn"),message.len() + 2));
+        let mut looped = message.clone();
+        looped.extend_from_slice(&[0xc0,message.len() as u8]);
+        assert!(read_name(&looped,message.len()).is_err());
+        assert!(question(&message[..message.len() - 6]).is_err());
+    }
+
+    #[test]
+    fn local_records_test() {
+        let (mut forwarder,mut poll) = forwarder("127.0.0.1:9".parse().unwrap());
+        let listener = forwarder.listeners[0].local_addr().unwrap();
+        let client = client();
+        let mut buf = [0u8;512];
+        client.send_to(&query(1,"laptop.vpn",TYPE_AAAA),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        let len = client.recv(&mut buf).unwrap();
+        let (_,end) = question(&buf[..len]).unwrap();
+        assert_eq!(&buf[..4],&[0,1,0x85,0x80]);
+        assert_eq!(u16_at(&buf,6).unwrap(),1);
+        assert_eq!(&buf[end + 12..len],&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        client.send_to(&query(2,"nobody.vpn",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(buf[3] & 0x0f,RCODE_NXDOMAIN);
+        assert_eq!(u16_at(&buf[..len],6).unwrap(),0);
+        client.send_to(&query(3,"laptop.vpn",TYPE_OPT),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        client.recv(&mut buf).unwrap();
+        assert_eq!(buf[3] & 0x0f,0);
+        assert!(forwarder.pending.is_empty());
+    }
+
+    #[test]
+    fn outside_client_test() {
+        let (mut forwarder,mut poll) = forwarder("127.0.0.1:9".parse().unwrap());
+        forwarder.clients = vec!["10.10.10.0/24".parse().unwrap()];
+        let listener = forwarder.listeners[0].local_addr().unwrap();
+        let client = client();
+        client.send_to(&query(1,"laptop.vpn",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        assert!(client.recv(&mut [0u8;512]).is_err());
+        client.send_to(&query(2,"example.com",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        assert!(forwarder.pending.is_empty());
+    }
+
+    #[test]
+    fn forward_test() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let (mut forwarder,mut poll) = forwarder(upstream.local_addr().unwrap());
+        let listener = forwarder.listeners[0].local_addr().unwrap();
+        let client = client();
+        let mut buf = [0u8;512];
+        client.send_to(&query(0x1234,"example.com",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        let (len,from) = upstream.recv_from(&mut buf).unwrap();
+        let mut response = buf[..len].to_vec();
+        response[2] |= 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xc0,12,0,1,0,1,0,0,1,44,0,4,93,184,215,14]);
+        let mut spoofed = response.clone();
+        spoofed[0] ^= 0xff;
+        upstream.send_to(&spoofed,from).unwrap();
+        upstream.send_to(&response,from).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        turn(&mut forwarder,&mut poll);
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..2],&[0x12,0x34]);
+        assert_eq!(&buf[2..len],&response[2..]);
+        client.send_to(&query(0x5678,"EXAMPLE.com",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..2],&[0x56,0x78]);
+        assert_eq!(ttl_at(&buf[..len],ttl_offsets(&buf[..len]).unwrap()[0]),300);
+        assert!(upstream.recv_from(&mut buf).is_err());
+        assert!(forwarder.pending.is_empty());
+    }
+
+    #[test]
+    fn uncacheable_test() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let (mut forwarder,mut poll) = forwarder(upstream.local_addr().unwrap());
+        let listener = forwarder.listeners[0].local_addr().unwrap();
+        let client = client();
+        let mut buf = [0u8;512];
+        client.send_to(&query(0x1234,"example.com",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        let (len,from) = upstream.recv_from(&mut buf).unwrap();
+        let mut response = buf[..len].to_vec();
+        response[2] |= 0x80;
+        response[7] = 2;
+        response.extend_from_slice(&[0xc0,12,0,1,0,1,0,0,1,44,0,4,93,184,215,14]);
+        upstream.send_to(&response,from).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        turn(&mut forwarder,&mut poll);
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[2..len],&response[2..]);
+        assert!(forwarder.pending.is_empty());
+        assert!(forwarder.cache.is_empty());
+    }
+
+    #[test]
+    fn edns_not_cached_test() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let (mut forwarder,mut poll) = forwarder(upstream.local_addr().unwrap());
+        let listener = forwarder.listeners[0].local_addr().unwrap();
+        let client = client();
+        let mut buf = [0u8;512];
+        let mut edns = query(0x1234,"example.com",TYPE_A);
+        edns[11] = 1;
+        edns.extend_from_slice(&[0,0,41,16,0,0,0,0x80,0,0,0]);
+        client.send_to(&edns,listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        let (len,from) = upstream.recv_from(&mut buf).unwrap();
+        let mut response = buf[..len].to_vec();
+        response[2] |= 0x80;
+        response[7] = 1;
+        response.truncate(len - 11);
+        response.extend_from_slice(&[0xc0,12,0,1,0,1,0,0,1,44,0,4,93,184,215,14]);
+        response.extend_from_slice(&edns[edns.len() - 11..]);
+        upstream.send_to(&response,from).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        turn(&mut forwarder,&mut poll);
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[2..len],&response[2..]);
+        assert!(forwarder.cache.is_empty());
+        client.send_to(&query(0x5678,"example.com",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        assert!(upstream.recv_from(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn failover_test() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let (mut forwarder,mut poll) = forwarder("127.0.0.1:9".parse().unwrap());
+        forwarder.upstreams.push(upstream.local_addr().unwrap());
+        let listener = forwarder.listeners[0].local_addr().unwrap();
+        client().send_to(&query(1,"example.com",TYPE_A),listener).unwrap();
+        turn(&mut forwarder,&mut poll);
+        assert!(upstream.recv_from(&mut [0u8;512]).is_err());
+        forwarder.pending.values_mut().for_each(|pending| pending.sent -= QUERY_TIMEOUT);
+        forwarder.prune();
+        assert!(upstream.recv_from(&mut [0u8;512]).is_ok());
+        forwarder.pending.values_mut().for_each(|pending| pending.sent -= QUERY_TIMEOUT);
+        forwarder.prune();
+        assert!(forwarder.pending.is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::{info, warn};
+
+use crate::error::Error;
+use crate::route::Prefix;
+use crate::utils;
+
+/// Directory the journals of running instances are kept in by default.
+pub const STATE_DIR:&str = "/var/lib/e-net";
+
+/// How to take back one change made to the system.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Undo {
+    /// Delete the route to a prefix.
+    Route(Prefix),
+    /// Send IPv4 traffic through this default gateway again.
+    DefaultRoute(String),
+    /// Set a kernel parameter back to its value.
+    Sysctl{name:String,value:String},
+    /// Put back the contents of a file, or remove it if it did not exist.
+    File{path:String,contents:Option<Vec<u8>>},
+    /// Run a command, given as program and arguments.
+    Command(Vec<String>)
+}
+
+impl Undo {
+    /// One line of tab separated fields, file contents in base64.
+    fn encode(&self) -> String {
+        let fields = match self {
+            Undo::Route(prefix) => vec![String::from("route"),prefix.to_string()],
+            Undo::DefaultRoute(gateway) => vec![String::from("default"),gateway.clone()],
+            Undo::Sysctl{name,value} => vec![String::from("sysctl"),name.clone(),value.clone()],
+            Undo::File{path,contents} => vec![
+                String::from("file"),
+                path.clone(),
+                contents.as_ref().map_or(String::from("-"),|contents| STANDARD.encode(contents))
+            ],
+            Undo::Command(command) => [vec![String::from("command")],command.clone()].concat()
+        };
+        fields.join("\t")
+    }
+
+    fn decode(line:&str) -> Result<Undo,String> {
+        let fields:Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            ["route",prefix] => Ok(Undo::Route(prefix.parse()?)),
+            ["default",gateway] => Ok(Undo::DefaultRoute(gateway.to_string())),
+            ["sysctl",name,value] => Ok(Undo::Sysctl{ name:name.to_string(), value:value.to_string() }),
+            ["file",path,"-"] => Ok(Undo::File{ path:path.to_string(), contents:None }),
+            ["file",path,contents] => Ok(Undo::File{
+                path:path.to_string(),
+                contents:Some(STANDARD.decode(contents).map_err(|e|e.to_string())?)
+            }),
+            ["command",ref command @ ..] if !command.is_empty() => {
+                Ok(Undo::Command(command.iter().map(|arg| arg.to_string()).collect()))
+            }
+            _ => Err(format!("unknown entry `{}`",line))
+        }
+    }
+
+    fn apply(&self) -> Result<(),Error> {
+        match self {
+            Undo::Route(prefix) => utils::delete_prefix_route(prefix),
+            Undo::DefaultRoute(gateway) => utils::restore_default_gateway(gateway),
+            Undo::Sysctl{name,value} => utils::set_sysctl(name,value),
+            Undo::File{path,contents:Some(contents)} => fs::write(path,contents).map_err(Error::Io),
+            Undo::File{path,contents:None} => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Io(e)),
+                _ => Ok(())
+            },
+            Undo::Command(command) => utils::run_command(command).map(|_| ())
+        }
+    }
+}
+
+/// Changes made to the system, taken back in reverse order when the journal is dropped.
+///
+/// Every change is noted in a state file before it is made. Dropping the journal on a normal
+/// exit, a signal or a panic restores the system and removes the file; after a crash the next
+/// run finds the file and restores the system first.
+///
+/// The journal holds an exclusive lock on a `.lock` file next to the state file for its whole
+/// life, so a second instance using the same file refuses to start instead of taking back the
+/// changes of the running one.
+pub struct Journal {
+    path:String,
+    entries:Vec<Undo>,
+    _lock:File
+}
+
+impl Journal {
+    /// Opens the journal kept in `path`, first taking back what an unclean exit left behind.
+    /// Fails if another running instance has it open.
+    pub fn open(path:&str) -> Result<Journal,Error> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir).map_err(Error::Io)?;
+        }
+        let lock = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{}.lock",path))
+            .map_err(Error::Io)?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::Config(format!("{} is in use by another running instance",path)));
+            }
+            Err(TryLockError::Error(e)) => return Err(Error::Io(e))
+        }
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                warn!("Found {} left by an unclean exit, restoring the system.", path);
+                let entries = content
+                    .lines()
+                    .map(Undo::decode)
+                    .collect::<Result<Vec<Undo>,String>>()
+                    .map_err(|e|Error::Config(format!("{}: {}",path,e)))?;
+                restore(&entries);
+                fs::remove_file(path).map_err(Error::Io)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::Io(e))
+        }
+        Ok(Journal{ path:path.to_string(), entries:Vec::new(), _lock:lock })
+    }
+
+    /// Notes how to take back a change that is about to be made.
+    pub fn record(&mut self,undo:Undo) -> Result<(),Error> {
+        self.entries.push(undo);
+        self.write()
+    }
+
+    /// Position of the next change, to take back the changes from there with `restore_since`.
+    pub fn mark(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Takes back the changes noted since `mark`, keeping the earlier ones.
+    pub fn restore_since(&mut self,mark:usize) -> Result<(),Error> {
+        let later = self.entries.split_off(mark.min(self.entries.len()));
+        restore(&later);
+        self.write()
+    }
+
+    fn write(&self) -> Result<(),Error> {
+        if self.entries.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Io(e)),
+                _ => Ok(())
+            };
+        }
+        let content:String = self.entries.iter().map(|undo| undo.encode() + "\n").collect();
+        let staged = format!("{}.new",self.path);
+        fs::write(&staged,content).map_err(Error::Io)?;
+        fs::rename(&staged,&self.path).map_err(Error::Io)
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        info!("Restoring the system state.");
+        restore(&self.entries);
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove {}: {}", self.path, e);
+        }
+    }
+}
+
+fn restore(entries:&[Undo]) {
+    for undo in entries.iter().rev() {
+        if let Err(e) = undo.apply() {
+            warn!("Failed to restore {:?}: {}", undo, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use crate::journal::*;
+
+    fn temp_path(name:&str) -> String {
+        env::temp_dir().join(format!("e-net-{}-{}",process::id(),name)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn encode_test() {
+        let entries = [
+            Undo::Route("192.0.2.1/32".parse().unwrap()),
+            Undo::DefaultRoute(String::from("192.0.2.254")),
+            Undo::Sysctl{ name:String::from("net.ipv4.ip_forward"), value:String::from("0") },
+            Undo::File{ path:String::from("/etc/resolv.conf"), contents:Some(b"nameserver 1.1.1.1\n".to_vec()) },
+            Undo::File{ path:String::from("/etc/resolv.conf"), contents:None },
+            Undo::Command(vec![String::from("nft"),String::from("delete"),String::from("table inet")])
+        ];
+        for undo in entries {
+            assert_eq!(Undo::decode(&undo.encode()).unwrap(),undo);
+        }
+        assert_eq!(
+            Undo::Sysctl{ name:String::from("a"), value:String::from("1") }.encode(),
+            "sysctl\ta\t1"
+        );
+        for invalid in ["","route","route\tnowhere","file\t/etc/hosts\t!!","command","unknown\tx"] {
+            assert!(Undo::decode(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn restore_test() {
+        let file = temp_path("restored");
+        let created = temp_path("created");
+        let path = temp_path("journal");
+        fs::write(&file,"original").unwrap();
+        {
+            let mut journal = Journal::open(&path).unwrap();
+            journal.record(Undo::File{ path:file.clone(), contents:Some(b"original".to_vec()) }).unwrap();
+            fs::write(&file,"changed").unwrap();
+            journal.record(Undo::File{ path:file.clone(), contents:Some(b"changed".to_vec()) }).unwrap();
+            fs::write(&file,"changed again").unwrap();
+            journal.record(Undo::File{ path:created.clone(), contents:None }).unwrap();
+            fs::write(&created,"new").unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap().lines().count(),3);
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(),"original");
+        assert!(!Path::new(&created).exists());
+        assert!(!Path::new(&path).exists());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn restore_since_test() {
+        let kept = temp_path("kept");
+        let restored = temp_path("restored-since");
+        let path = temp_path("marked");
+        let mut journal = Journal::open(&path).unwrap();
+        journal.record(Undo::File{ path:kept.clone(), contents:None }).unwrap();
+        fs::write(&kept,"kept").unwrap();
+        let mark = journal.mark();
+        journal.record(Undo::File{ path:restored.clone(), contents:None }).unwrap();
+        fs::write(&restored,"restored").unwrap();
+        journal.restore_since(mark).unwrap();
+        assert!(!Path::new(&restored).exists());
+        assert!(Path::new(&kept).exists());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(),1);
+        drop(journal);
+        assert!(!Path::new(&kept).exists());
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn locked_test() {
+        let file = temp_path("locked-change");
+        let path = temp_path("locked");
+        let mut journal = Journal::open(&path).unwrap();
+        journal.record(Undo::File{ path:file.clone(), contents:None }).unwrap();
+        fs::write(&file,"running").unwrap();
+        assert_eq!(Journal::open(&path).err().unwrap().kind(),"config");
+        assert!(Path::new(&file).exists());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(),1);
+        drop(journal);
+        assert!(!Path::new(&file).exists());
+        drop(Journal::open(&path).unwrap());
+        fs::remove_file(format!("{}.lock",path)).unwrap();
+    }
+
+    #[test]
+    fn recover_test() {
+        let file = temp_path("recovered");
+        let path = temp_path("crashed");
+        fs::write(&file,"changed").unwrap();
+        let undo = Undo::File{ path:file.clone(), contents:Some(b"original".to_vec()) };
+        fs::write(&path,undo.encode() + "\n").unwrap();
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(),"original");
+        assert!(!Path::new(&path).exists());
+        drop(journal);
+        assert!(!Path::new(&path).exists());
+        fs::write(&path,"garbage\n").unwrap();
+        assert_eq!(Journal::open(&path).err().unwrap().kind(),"config");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::rand::SystemRandom;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::crypto::{self, KEY_LEN};
+
+/// Environment variable holding the key when neither `--key` nor `--key-file` is given.
+pub const KEY_ENV:&str = "E_NET_KEY";
+
+/// Permission bits for other users, which must all be clear on a key file.
+const FORBIDDEN_MODE:u32 = 0o007;
+
+pub fn decode(key:&str) -> Result<[u8;KEY_LEN],String> {
+    let bytes = STANDARD.decode(key.trim()).map_err(|e|format!("invalid key: {}",e))?;
+    bytes
+        .try_into()
+        .map_err(|bytes:Vec<u8>| format!("invalid key: {} bytes instead of {}",bytes.len(),KEY_LEN))
+}
+
+/// Generates a base64 encoded X25519 private key.
+pub fn generate(rng:&SystemRandom) -> Result<String,String> {
+    let secret = StaticSecret::from(crypto::random_key(rng)?);
+    Ok(encode(&secret.to_bytes()))
+}
+
+pub fn encode(key:&[u8;KEY_LEN]) -> String {
+    STANDARD.encode(key)
+}
+
+/// Parses a key produced by [`generate`].
+pub fn private_key(private_key:&str) -> Result<StaticSecret,String> {
+    Ok(StaticSecret::from(decode(private_key)?))
+}
+
+/// Computes the base64 encoded public key of a key produced by [`generate`].
+pub fn public_key(private_key:&str) -> Result<String,String> {
+    Ok(encode(PublicKey::from(&self::private_key(private_key)?).as_bytes()))
+}
+
+/// Reads a key file, refusing files other users can access.
+pub fn read_key_file(path:&str) -> Result<String,String> {
+    let metadata = fs::metadata(path).map_err(|e|format!("{}: {}",path,e))?;
+    let mode = metadata.permissions().mode();
+    if mode & FORBIDDEN_MODE != 0 {
+        return Err(format!("{}: key file is accessible by other users (mode {:o})",path,mode & 0o777));
+    }
+    let key = fs::read_to_string(path).map_err(|e|format!("{}: {}",path,e))?;
+    decode(&key).map_err(|e|format!("{}: {}",path,e))?;
+    Ok(key.trim().to_string())
+}
+
+/// Writes `key` to a new file only its owner can read.
+pub fn write_key_file(path:&str,key:&str) -> Result<(),String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e|format!("{}: {}",path,e))?;
+    writeln!(file,"{}",key).map_err(|e|format!("{}: {}",path,e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use crate::keys::*;
+
+    fn temp_path(name:&str) -> String {
+        let path = env::temp_dir().join(format!("e-net-{}-{}",process::id(),name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn public_key_test() {
+        let rng = SystemRandom::new();
+        let private_key = generate(&rng).unwrap();
+        assert_eq!(decode(&private_key).unwrap().len(),KEY_LEN);
+        assert_eq!(public_key(&private_key).unwrap(),public_key(&private_key).unwrap());
+        assert_ne!(public_key(&private_key).unwrap(),public_key(&generate(&rng).unwrap()).unwrap());
+        assert!(public_key("not base64").is_err());
+        assert!(public_key(&STANDARD.encode([0u8;16])).is_err());
+    }
+
+    #[test]
+    fn key_file_test() {
+        let rng = SystemRandom::new();
+        let key = generate(&rng).unwrap();
+        let path = temp_path("key");
+        write_key_file(&path,&key).unwrap();
+        assert!(write_key_file(&path,&key).is_err());
+        assert_eq!(read_key_file(&path).unwrap(),key);
+        fs::set_permissions(&path,fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(read_key_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(read_key_file(&path).is_err());
+    }
+}
@@ -1,10 +1,130 @@
 mod cli;
+mod clients;
+mod crypto;
+mod dns;
+mod error;
+mod forwarder;
+#[allow(dead_code)]
 mod packet;
 mod utils;
 mod device;
+mod journal;
+mod keys;
+#[cfg(target_os = "linux")]
+mod netlink;
 
 mod network;
+mod pool;
+mod replay;
+mod route;
+mod session;
+mod wire;
+
+use std::{panic, process};
+use std::io::{self, Read};
+use std::sync::atomic::Ordering;
+
+use libc::c_int;
+use log::{error, info};
+use ring::rand::SystemRandom;
+
+use crate::cli::Args;
+
+const EXIT_USAGE:i32 = 2;
+const EXIT_NOT_ROOT:i32 = 3;
+const EXIT_SIGNAL:i32 = 4;
+const EXIT_RUNTIME:i32 = 5;
+
+extern "C" fn handle_signal(_signal:c_int) {
+    network::INTERRUPTED.store(true,Ordering::Relaxed);
+}
+
+fn install_signal_handlers() -> Result<(),String> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let res = unsafe {
+            libc::signal(signal, handle_signal as extern "C" fn(c_int) as libc::sighandler_t)
+        };
+        if res == libc::SIG_ERR {
+            return Err(format!("signal: {}", std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+fn genkey(output:Option<String>) -> Result<(),String> {
+    let key = keys::generate(&SystemRandom::new())?;
+    match output {
+        None => println!("{}", key),
+        Some(path) => keys::write_key_file(&path, &key)?
+    }
+    Ok(())
+}
+
+fn pubkey(input:Option<String>) -> Result<(),String> {
+    let private_key = match input {
+        None => {
+            let mut key = String::new();
+            io::stdin().read_to_string(&mut key).map_err(|e| e.to_string())?;
+            key
+        }
+        Some(path) => keys::read_key_file(&path)?
+    };
+    println!("{}", keys::public_key(&private_key)?);
+    Ok(())
+}
 
 fn main() {
-    println!("Hello, world!");
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = match cli::get_args() {
+        Ok(args) => args,
+        Err(e) => {
+            error!("Invalid arguments: {}", e);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let args = match args {
+        Args::GenKey{output} => {
+            if let Err(e) = genkey(output) {
+                error!("Failed to generate key: {}", e);
+                process::exit(EXIT_RUNTIME);
+            }
+            return;
+        }
+        Args::PubKey{input} => {
+            if let Err(e) = pubkey(input) {
+                error!("Failed to compute public key: {}", e);
+                process::exit(EXIT_RUNTIME);
+            }
+            return;
+        }
+        args => args
+    };
+    if !utils::is_root() {
+        error!("e-net must be run as root.");
+        process::exit(EXIT_NOT_ROOT);
+    }
+    if let Err(e) = install_signal_handlers() {
+        error!("Failed to install signal handlers: {}", e);
+        process::exit(EXIT_RUNTIME);
+    }
+    let result = panic::catch_unwind(|| match args {
+        Args::Client(client) => network::connect(&client),
+        Args::Server(server) => network::serve(&server),
+        Args::GenKey{..} | Args::PubKey{..} => unreachable!(),
+    });
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("{}", e);
+            process::exit(EXIT_RUNTIME);
+        }
+        Err(_) => {
+            error!("e-net terminated abnormally.");
+            process::exit(EXIT_RUNTIME);
+        }
+    }
+    if network::INTERRUPTED.load(Ordering::Relaxed) {
+        info!("Interrupted, shutting down.");
+        process::exit(EXIT_SIGNAL);
+    }
 }
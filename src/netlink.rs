@@ -0,0 +1,323 @@
+//! Route, address and link management over rtnetlink, so the system can be configured
+//! without `ip`, `route` or `ifconfig` installed.
+
+use std::ffi::CString;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use libc::c_int;
+
+use crate::route::Prefix;
+
+/// Length of `struct nlmsghdr`.
+const HEADER_LEN:usize = 16;
+/// Length of `struct rtmsg`.
+const RTMSG_LEN:usize = 12;
+/// Sequence number of every request, each request has a socket of its own.
+const SEQUENCE:u32 = 1;
+
+/// A route of the main table as the kernel reports it.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Route {
+    pub destination:Prefix,
+    pub gateway:Option<IpAddr>,
+    pub interface:Option<String>
+}
+
+/// Message to the kernel under construction: header, family specific message and attributes.
+struct Request {
+    buf:Vec<u8>
+}
+
+impl Request {
+    fn new(kind:u16,flags:c_int,message:&[u8]) -> Request {
+        let mut buf = vec![0u8;HEADER_LEN];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | flags) as u16).to_ne_bytes());
+        buf[8..12].copy_from_slice(&SEQUENCE.to_ne_bytes());
+        buf.extend_from_slice(message);
+        Request{ buf }
+    }
+
+    fn attribute(mut self,kind:u16,data:&[u8]) -> Request {
+        self.buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()),0);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+fn align(len:usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(address:IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8
+    }
+}
+
+fn octets(address:IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec()
+    }
+}
+
+fn address(family:u8,data:&[u8]) -> Option<IpAddr> {
+    match family as c_int {
+        libc::AF_INET => <[u8;4]>::try_from(data).ok().map(|octets| IpAddr::V4(Ipv4Addr::from(octets))),
+        libc::AF_INET6 => <[u8;16]>::try_from(data).ok().map(|octets| IpAddr::V6(Ipv6Addr::from(octets))),
+        _ => None
+    }
+}
+
+/// Splits the attributes following a family specific message into their kinds and data.
+fn attributes(mut data:&[u8]) -> Vec<(u16,&[u8])> {
+    let mut attributes = Vec::new();
+    while data.len() >= 4 {
+        let len = usize::from(u16::from_ne_bytes([data[0],data[1]]));
+        if len < 4 || len > data.len() {
+            break;
+        }
+        attributes.push((u16::from_ne_bytes([data[2],data[3]]),&data[4..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    attributes
+}
+
+/// Sends `request` to the kernel, returning the messages of a dump or nothing once the
+/// request is acknowledged. A refusal comes back as the error the kernel gives.
+fn send(request:Vec<u8>) -> io::Result<Vec<(u16,Vec<u8>)>> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK,libc::SOCK_RAW | libc::SOCK_CLOEXEC,libc::NETLINK_ROUTE) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let sent = unsafe { libc::send(socket.as_raw_fd(),request.as_ptr().cast(),request.len(),0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut messages = Vec::new();
+    let mut buf = vec![0u8;65536];
+    loop {
+        let len = unsafe { libc::recv(socket.as_raw_fd(),buf.as_mut_ptr().cast(),buf.len(),0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut data = &buf[..len as usize];
+        while data.len() >= HEADER_LEN {
+            let len = u32::from_ne_bytes([data[0],data[1],data[2],data[3]]) as usize;
+            if len < HEADER_LEN || len > data.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,"truncated netlink message"));
+            }
+            let kind = u16::from_ne_bytes([data[4],data[5]]);
+            let payload = &data[HEADER_LEN..len];
+            match kind as c_int {
+                libc::NLMSG_DONE => return Ok(messages),
+                libc::NLMSG_ERROR => {
+                    let error = payload
+                        .get(..4)
+                        .map(|error| i32::from_ne_bytes([error[0],error[1],error[2],error[3]]))
+                        .ok_or(io::Error::new(io::ErrorKind::InvalidData,"truncated netlink error"))?;
+                    if error == 0 {
+                        return Ok(messages);
+                    }
+                    return Err(io::Error::from_raw_os_error(-error));
+                }
+                _ => messages.push((kind,payload.to_vec()))
+            }
+            data = &data[align(len).min(data.len())..];
+        }
+    }
+}
+
+/// Index of the network interface called `name`.
+pub fn interface_index(name:&str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|e|io::Error::new(io::ErrorKind::InvalidInput,e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index)
+    }
+}
+
+fn interface_name(index:u32) -> Option<String> {
+    let mut name = [0u8;libc::IFNAMSIZ];
+    if unsafe { libc::if_indextoname(index,name.as_mut_ptr().cast()) }.is_null() {
+        return None;
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..len]).to_string())
+}
+
+/// Brings `interface` up with `mtu`.
+pub fn set_link_up(interface:&str,mtu:u32) -> io::Result<()> {
+    let mut message = [0u8;16];
+    message[4..8].copy_from_slice(&interface_index(interface)?.to_ne_bytes());
+    message[8..12].copy_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+    message[12..16].copy_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+    let request = Request::new(libc::RTM_NEWLINK,libc::NLM_F_ACK,&message)
+        .attribute(libc::IFLA_MTU,&mtu.to_ne_bytes());
+    send(request.finish()).map(|_| ())
+}
+
+/// Assigns `address` with `prefix` to `interface`.
+pub fn add_address(interface:&str,address:IpAddr,prefix:u8) -> io::Result<()> {
+    let mut message = [0u8;8];
+    message[0] = family(address);
+    message[1] = prefix;
+    message[4..8].copy_from_slice(&interface_index(interface)?.to_ne_bytes());
+    let request = Request::new(libc::RTM_NEWADDR,libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL,&message)
+        .attribute(libc::IFA_LOCAL,&octets(address))
+        .attribute(libc::IFA_ADDRESS,&octets(address));
+    send(request.finish()).map(|_| ())
+}
+
+fn route_message(destination:&Prefix,scope:u8,kind:u8) -> [u8;RTMSG_LEN] {
+    let mut message = [0u8;RTMSG_LEN];
+    message[0] = family(destination.network());
+    message[1] = destination.length();
+    message[4] = libc::RT_TABLE_MAIN;
+    message[5] = libc::RTPROT_BOOT;
+    message[6] = scope;
+    message[7] = kind;
+    message
+}
+
+/// Routes `destination` in the main table through `gateway`, which must be of the same
+/// family, leaving through `interface` if given.
+pub fn add_route(destination:&Prefix,gateway:IpAddr,interface:Option<&str>) -> io::Result<()> {
+    let message = route_message(destination,libc::RT_SCOPE_UNIVERSE,libc::RTN_UNICAST);
+    let mut request = Request::new(libc::RTM_NEWROUTE,libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL,&message)
+        .attribute(libc::RTA_DST,&octets(destination.network()))
+        .attribute(libc::RTA_GATEWAY,&octets(gateway));
+    if let Some(interface) = interface {
+        request = request.attribute(libc::RTA_OIF,&interface_index(interface)?.to_ne_bytes());
+    }
+    send(request.finish()).map(|_| ())
+}
+
+/// Deletes the first route to `destination` in the main table, whatever its gateway.
+pub fn delete_route(destination:&Prefix) -> io::Result<()> {
+    let message = route_message(destination,libc::RT_SCOPE_NOWHERE,0);
+    let request = Request::new(libc::RTM_DELROUTE,libc::NLM_F_ACK,&message)
+        .attribute(libc::RTA_DST,&octets(destination.network()));
+    send(request.finish()).map(|_| ())
+}
+
+/// Reads a route from the payload of an `RTM_NEWROUTE` message, skipping routes of other
+/// tables and types.
+fn parse_route(payload:&[u8]) -> Option<Route> {
+    if payload.len() < RTMSG_LEN || payload[7] != libc::RTN_UNICAST {
+        return None;
+    }
+    let family = payload[0];
+    let mut table = u32::from(payload[4]);
+    let mut network = match family as c_int {
+        libc::AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        libc::AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => return None
+    };
+    let mut gateway = None;
+    let mut interface = None;
+    for (kind,data) in attributes(&payload[RTMSG_LEN..]) {
+        match kind {
+            libc::RTA_DST => network = address(family,data)?,
+            libc::RTA_GATEWAY => gateway = address(family,data),
+            libc::RTA_OIF => interface = <[u8;4]>::try_from(data).ok().map(u32::from_ne_bytes),
+            libc::RTA_TABLE => table = <[u8;4]>::try_from(data).ok().map(u32::from_ne_bytes)?,
+            _ => {}
+        }
+    }
+    if table != u32::from(libc::RT_TABLE_MAIN) {
+        return None;
+    }
+    Some(Route{
+        destination:Prefix::new(network,payload[1]).ok()?,
+        gateway,
+        interface:interface.and_then(interface_name)
+    })
+}
+
+/// Routes of the main table of one address family.
+pub fn routes(ipv6:bool) -> io::Result<Vec<Route>> {
+    let mut message = [0u8;RTMSG_LEN];
+    message[0] = if ipv6 { libc::AF_INET6 as u8 } else { libc::AF_INET as u8 };
+    let messages = send(Request::new(libc::RTM_GETROUTE,libc::NLM_F_DUMP,&message).finish())?;
+    Ok(messages
+        .iter()
+        .filter(|(kind,_)| *kind == libc::RTM_NEWROUTE)
+        .filter_map(|(_,payload)| parse_route(payload))
+        .collect())
+}
+
+/// The default route through a gateway of one address family, if there is one.
+pub fn default_route(ipv6:bool) -> io::Result<Option<Route>> {
+    Ok(routes(ipv6)?
+        .into_iter()
+        .find(|route| route.destination.length() == 0 && route.gateway.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use crate::netlink::*;
+
+    #[test]
+    fn request_test() {
+        let destination:Prefix = "192.168.7.0/24".parse().unwrap();
+        let message = route_message(&destination,libc::RT_SCOPE_UNIVERSE,libc::RTN_UNICAST);
+        let request = Request::new(libc::RTM_NEWROUTE,libc::NLM_F_ACK,&message)
+            .attribute(libc::RTA_DST,&octets(destination.network()))
+            .attribute(libc::RTA_OIF,&u32::MAX.to_ne_bytes())
+            .finish();
+        assert_eq!(request.len(),HEADER_LEN + RTMSG_LEN + 8 + 8);
+        assert_eq!(u32::from_ne_bytes(request[0..4].try_into().unwrap()) as usize,request.len());
+        assert_eq!(u16::from_ne_bytes(request[6..8].try_into().unwrap()),5);
+        assert_eq!(&request[HEADER_LEN..HEADER_LEN + 8],&[libc::AF_INET as u8,24,0,0,254,3,0,1]);
+        let payload = &request[HEADER_LEN..];
+        assert_eq!(attributes(&payload[RTMSG_LEN..]),vec![
+            (libc::RTA_DST,&[192,168,7,0][..]),
+            (libc::RTA_OIF,&u32::MAX.to_ne_bytes()[..])
+        ]);
+        assert_eq!(parse_route(payload).unwrap(),Route{ destination, gateway:None, interface:None });
+    }
+
+    #[test]
+    fn parse_route_test() {
+        let mut message = route_message(&"::/0".parse().unwrap(),libc::RT_SCOPE_UNIVERSE,libc::RTN_UNICAST);
+        message[4] = 0;
+        let gateway:IpAddr = "fe80::1".parse().unwrap();
+        let mut request = Request::new(libc::RTM_NEWROUTE,0,&message)
+            .attribute(libc::RTA_GATEWAY,&octets(gateway))
+            .attribute(libc::RTA_TABLE,&u32::from(libc::RT_TABLE_MAIN).to_ne_bytes())
+            .finish();
+        let route = parse_route(&request[HEADER_LEN..]).unwrap();
+        assert_eq!(route.destination.to_string(),"::/0");
+        assert_eq!(route.gateway,Some(gateway));
+        request[HEADER_LEN + 7] = 2;
+        assert_eq!(parse_route(&request[HEADER_LEN..]),None);
+        assert_eq!(parse_route(&request[HEADER_LEN..HEADER_LEN + 4]),None);
+    }
+
+    #[test]
+    fn default_route_test() {
+        let output = process::Command::new("ip").args(["-4","route","list","0/0"]).output().unwrap();
+        let output = String::from_utf8_lossy(&output.stdout).to_string();
+        let route = default_route(false).unwrap().unwrap();
+        assert!(output.contains(&format!("via {} dev {}",route.gateway.unwrap(),route.interface.unwrap())));
+        assert!(routes(false).unwrap().iter().any(|route| route.destination.length() > 0));
+        assert_eq!(interface_index("lo").unwrap(),1);
+        assert!(interface_index("e-net-missing").is_err());
+    }
+}
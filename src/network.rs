@@ -1,395 +1,1718 @@
-use std::fmt::format;
-use std::hint::unreachable_unchecked;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::num::NonZeroU32;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::os::fd::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use bincode::{deserialize, serialize};
-use libc::proc_kmsgbuf;
 use log::{info, warn};
 use rand::{Rng, thread_rng};
-use ring::{aead, pbkdf2};
-use serde::__private::de::IdentifierDeserializer;
-use serde_derive::{Deserialize, Serialize};
+use rand::rngs::ThreadRng;
+use ring::aead;
+use ring::rand::SystemRandom;
 use transient_hashmap::TransientHashMap;
+use x25519_dalek::{PublicKey, StaticSecret};
 
-use crate::{device, utils};
-use crate::utils::{DefaultGateway, enable_ipv4_forwarding, get_public_ip};
+use crate::{cli, crypto, device, dns, keys, packet, utils};
+use crate::clients::{ClientTable, Identity};
+use crate::crypto::{ClientKey, Handshake, Role, KEY_LEN};
+use crate::error::{Error, ErrorLog, Source};
+use crate::forwarder::{Forwarder, DNS_PORT};
+use crate::journal::Journal;
+use crate::pool::{AddressPool, Subnet};
+use crate::route::{Prefix, RoutingTable, Split};
+use crate::session::{Id, RekeyLimits, Session, Token};
+use crate::wire::{Header, Ipv6Lease, KdfOffer, Message, MessageType, Versions, HEADER_LEN, KDF_REQUEST_LEN, MIN_VERSION};
+use crate::utils::{KillSwitch, Masquerade, enable_ipv4_forwarding, enable_ipv6_forwarding, get_public_ip};
 
 pub static INTERRUPTED:AtomicBool = AtomicBool::new(false);
 pub static CONNECTED:AtomicBool = AtomicBool::new(false);
 pub static LISTENING:AtomicBool = AtomicBool::new(false);
-const KEY_LEN:usize = 32;
 
-type Id = u8;
+/// Pre-shared secret and the handshake key derived from it.
+struct Secret {
+    psk:[u8;KEY_LEN],
+    key:aead::LessSafeKey,
+    legacy:bool
+}
+
+impl Secret {
+    fn new(psk:[u8;KEY_LEN],legacy:bool) -> Secret {
+        Secret{ psk, key:crypto::handshake_key(&psk), legacy }
+    }
+}
+
+/// Builds a datagram sent in the clear outside of a session.
+fn encode_clear(version:u8,message_type:MessageType,body:&[u8]) -> Vec<u8> {
+    let mut datagram = Header::new(version,message_type).encode().to_vec();
+    datagram.extend_from_slice(body);
+    datagram
+}
+
+/// Encodes and seals a handshake message, producing the datagram
+/// `header || nonce || ciphertext || tag`.
+fn encrypt_handshake(key:&aead::LessSafeKey,rng:&SystemRandom,version:u8,msg:&Message) -> Result<Vec<u8>,Error> {
+    let header = Header::new(version,msg.message_type()).encode();
+    let mut datagram = header.to_vec();
+    datagram.extend_from_slice(&crypto::seal(key,rng,&header,&msg.encode()).map_err(Error::Crypto)?);
+    Ok(datagram)
+}
+
+fn decrypt_handshake(key:&aead::LessSafeKey,datagram:&mut [u8]) -> Result<Message,Error> {
+    let header = Header::decode(datagram).map_err(Error::Decode)?;
+    let (aad,sealed) = datagram.split_at_mut(HEADER_LEN);
+    let decrypted = crypto::open(key,aad,sealed).map_err(Error::Decrypt)?;
+    Message::decode(header.message_type,decrypted).map_err(Error::Decode)
+}
+
+/// Opens a handshake datagram with the first of `secrets` that authenticates it.
+fn decrypt_handshake_any<'a>(secrets:&'a [Secret],datagram:&[u8]) -> Result<(Message,&'a Secret),Error> {
+    for secret in secrets {
+        if let Ok(msg) = decrypt_handshake(&secret.key,&mut datagram.to_vec()) {
+            return Ok((msg,secret));
+        }
+    }
+    Err(Error::Decrypt(String::from("handshake not sealed with a known secret")))
+}
+
+fn encrypt_message(session:&mut Session,id:Id,msg:&Message) -> Result<Vec<u8>,Error> {
+    session.seal(id,msg.message_type(),&msg.encode()).map_err(Error::Crypto)
+}
+
+/// Opens and decodes a session message, returning `None` for replayed packets.
+fn decrypt_message(session:&mut Session,datagram:&mut [u8]) -> Result<Option<Message>,Error> {
+    let header = Header::decode(datagram).map_err(Error::Decode)?;
+    match session.open(datagram).map_err(Error::Decrypt)? {
+        None => Ok(None),
+        Some(decrypted) => Message::decode(header.message_type,decrypted).map(Some).map_err(Error::Decode)
+    }
+}
 
-type Token = u64;
+fn send_datagram(socket:&mio::net::UdpSocket,datagram:&[u8],addr:SocketAddr) -> Result<(),Error> {
+    let sent_len = socket.send_to(datagram,addr).map_err(Error::Socket)?;
+    if sent_len < datagram.len() {
+        return Err(Error::Socket(io::Error::other(format!(
+            "datagram truncated to {} of {} bytes",
+            sent_len,
+            datagram.len()
+        ))));
+    }
+    Ok(())
+}
 
-fn generate_add_nonce(_secret: &str) -> (aead::Aad<[u8; 0]>, aead::Nonce) {
-    let nonce = aead::Nonce::assume_unique_for_key([0;12]);
-    let aad = aead::Aad::empty();
-    (aad,nonce)
+/// Decompresses a tunneled packet and writes it to the TUN device.
+fn write_packet(tun:&mut impl Write,decoder:&mut snap::raw::Decoder,data:&[u8]) -> Result<(),Error> {
+    let packet = decoder.decompress_vec(data).map_err(Error::Compression)?;
+    write_tun(tun,&packet)
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-enum Message {
-    Request,
-    Response {id:Id,token:Token,dns:String},
-    Data{id:Id,token:Token,data:Vec<u8>}
+fn write_tun(tun:&mut impl Write,packet:&[u8]) -> Result<(),Error> {
+    let written = tun.write(packet).map_err(Error::Tun)?;
+    if written < packet.len() {
+        return Err(Error::Tun(io::Error::other(format!(
+            "packet truncated to {} of {} bytes",
+            written,
+            packet.len()
+        ))));
+    }
+    Ok(())
+}
+
+/// Answers a rekey message received inside `session`, returning the reply to send if any.
+///
+/// Finishing a rekey replies with a keepalive in the new epoch, so the peer switches to it
+/// within a round trip even if nothing else is sent before the old epoch is dropped.
+fn handle_rekey(session:&mut Session,rng:&SystemRandom,id:Id,msg:Message) -> Result<Option<Vec<u8>>,Error> {
+    match msg {
+        Message::Rekey{public_key} => match session.accept_rekey(rng,&public_key).map_err(Error::Handshake)? {
+            None => Ok(None),
+            Some(public_key) => encrypt_message(session,id,&Message::RekeyAck{ public_key }).map(Some)
+        },
+        Message::RekeyAck{public_key} => {
+            session.finish_rekey(&public_key).map_err(Error::Handshake)?;
+            encrypt_message(session,id,&Message::Keepalive).map(Some)
+        }
+        _ => Ok(None)
+    }
+}
+
+/// Starts a rekey if the session's keys are worn out, returning the message to send.
+fn rekey_if_needed(session:&mut Session,rng:&SystemRandom,id:Id) -> Result<Option<Vec<u8>>,Error> {
+    if !session.needs_rekey() {
+        return Ok(None);
+    }
+    let public_key = session.start_rekey(rng).map_err(Error::Crypto)?;
+    encrypt_message(session,id,&Message::Rekey{ public_key }).map(Some)
+}
+
+/// Works out who sent a handshake request from the client key it carries.
+///
+/// Without a client table every client is accepted, identified by its key if it sent one.
+fn authorize(clients:Option<&ClientTable>,public_key:Option<&[u8]>) -> Result<Option<Identity>,Error> {
+    let public_key = match public_key {
+        None if clients.is_some() => return Err(Error::Handshake(String::from("no client key"))),
+        None => return Ok(None),
+        Some(public_key) => crypto::parse_public_key(public_key).map_err(Error::Handshake)?.to_bytes()
+    };
+    match clients {
+        None => Ok(Some(Identity{ name:keys::encode(&public_key), public_key })),
+        Some(clients) => clients
+            .authorize(&public_key)
+            .map(Some)
+            .ok_or_else(|| Error::Handshake(format!("unknown client key {}",keys::encode(&public_key))))
+    }
+}
+
+/// Removes the sessions of clients no longer in `clients`, returning their ids.
+fn revoke_sessions(clients:&ClientTable,sessions:&mut TransientHashMap<Id,Session>) -> Vec<Id> {
+    let revoked:Vec<Id> = sessions
+        .iter()
+        .filter(|(_,session)| !session.identity.as_ref().is_some_and(|identity| clients.contains(identity)))
+        .map(|(id,_)| *id)
+        .collect();
+    for id in &revoked {
+        if let Some(session) = sessions.remove(id) {
+            let client = session.identity.map_or(String::from("anonymous client"),|identity| identity.to_string());
+            info!("Revoked {} at {}.", client, session.addr);
+        }
+    }
+    revoked
+}
+
+/// How often the server checks the client table for changes when idle.
+const RELOAD_INTERVAL:Duration = Duration::from_secs(5);
+
+/// How often the client checks for a signal when idle.
+const SIGNAL_INTERVAL:Duration = Duration::from_secs(1);
+
+/// How long the client waits for the server before sending a keepalive.
+const KEEPALIVE_INTERVAL:Duration = Duration::from_secs(10);
+
+/// How long the client hears nothing from the server before it takes the tunnel for dead.
+const PEER_TIMEOUT:Duration = Duration::from_secs(30);
+
+/// How long the client waits before setting up a failed tunnel again behind the kill switch.
+const RECONNECT_INTERVAL:Duration = Duration::from_secs(5);
+
+/// How far the timestamp of a handshake request may be off the server's clock. Ephemeral
+/// keys of accepted requests are remembered for twice as long, so a captured request can't
+/// be replayed while its timestamp is still taken.
+const HANDSHAKE_WINDOW:Duration = Duration::from_secs(120);
+
+/// Seconds since the Unix epoch, as carried by handshake requests.
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0,|elapsed| elapsed.as_secs())
 }
 
 const TUN:mio::Token = mio::Token(0);
 const SOCK:mio::Token = mio::Token(1);
+/// First token of the DNS forwarder, which takes one for each of its sockets.
+const DNS:mio::Token = mio::Token(2);
+
+/// How long the client waits for each answer of the server before giving up on an address.
+const HANDSHAKE_TIMEOUT:Duration = Duration::from_secs(3);
 
-fn resolve(host:&str) -> Result<IpAddr,String> {
-    let ip_list = dns_lookup::lookup_host(host).map_err(|_| "dns_lookup::lookup_host")?;
-    Ok(ip_list.first().unwrap().clone())
+/// Resolves `host` to all of its addresses in the order they should be tried.
+fn resolve(host:&str) -> Result<Vec<IpAddr>,Error> {
+    let ip_list = dns_lookup::lookup_host(host).map_err(|e|Error::Resolve(format!("{}: {}",host,e)))?;
+    if ip_list.is_empty() {
+        return Err(Error::Resolve(format!("{}: no address",host)));
+    }
+    Ok(interleave(ip_list))
 }
 
-fn create_tun_attempt() -> device::Tun{
-    fn attempt(id:u8) -> device::Tun{
-        match id {
-            255 => panic!("unable to create TUN device."),
-            _ => match device::Tun::create(id) {
-                Ok(tun) => tun,
-                Err(_) => attempt(id + 1)
-            },
+/// Alternates between address families, starting with the resolver's preferred one, so an
+/// unreachable family delays the handshake by one attempt at a time.
+fn interleave(ip_list:Vec<IpAddr>) -> Vec<IpAddr> {
+    let mut unique = Vec::new();
+    for ip in ip_list {
+        if !unique.contains(&ip) {
+            unique.push(ip);
+        }
+    }
+    let preferred_v6 = unique.first().is_some_and(IpAddr::is_ipv6);
+    let (mut preferred,mut other):(Vec<IpAddr>,Vec<IpAddr>) = unique
+        .into_iter()
+        .partition(|ip| ip.is_ipv6() == preferred_v6);
+    let mut ordered = Vec::new();
+    preferred.reverse();
+    other.reverse();
+    while let Some(ip) = preferred.pop() {
+        ordered.push(ip);
+        ordered.extend(other.pop());
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
+}
+
+/// Binds a UDP socket to `addr`. The IPv6 wildcard address also accepts IPv4 peers, whatever
+/// the system's default for dual-stack sockets is.
+fn bind(addr:SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP)
+    )?;
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+fn create_tun_attempt() -> Result<device::Tun,Error> {
+    fn attempt(id:u8) -> Result<device::Tun,Error> {
+        match device::Tun::create(id) {
+            Ok(tun) => Ok(tun),
+            Err(_) if id < 254 => attempt(id + 1),
+            Err(e) => Err(Error::Tun(e))
         }
     }
     attempt(0)
 }
 
-fn derive_keys(password:&str) -> aead::LessSafeKey{
-    let mut key = [0;KEY_LEN];
-    let salt = vec![0;64];
-    let pbkdf2_iterations : NonZeroU32 = NonZeroU32::new(1024).unwrap();
-    pbkdf2::derive(
-        pbkdf2::PBKDF2_HMAC_SHA256,
-        pbkdf2_iterations,
-        &salt,
-        password.as_bytes(),
-        &mut key,
-    );
-    let less_safe_key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_256_GCM, &key).unwrap());
-    less_safe_key
+/// Asks the server for the salt and Argon2id parameters of its pre-shared secret.
+fn request_kdf_offer(socket:&UdpSocket,addr:&SocketAddr) -> Result<KdfOffer,Error> {
+    let mut request = encode_clear(MIN_VERSION,MessageType::KdfRequest,&[]);
+    request.resize(KDF_REQUEST_LEN,0);
+    let sent_len = socket.send_to(&request,addr).map_err(Error::Socket)?;
+    if sent_len < request.len() {
+        return Err(Error::Socket(io::Error::other(format!(
+            "KDF request truncated to {} of {} bytes",
+            sent_len,
+            request.len()
+        ))));
+    }
+    let mut buf = [0u8;KDF_REQUEST_LEN];
+    let (len , _recv_addr) = socket.recv_from(&mut buf).map_err(Error::Socket)?;
+    let header = Header::decode(&buf[0..len]).map_err(Error::Decode)?;
+    if header.message_type != MessageType::KdfOffer {
+        return Err(Error::Handshake(format!("invalid KDF answer {:?} from {}",header.message_type,addr)));
+    }
+    let offer = KdfOffer::decode(&buf[HEADER_LEN..len]).map_err(Error::Decode)?;
+    offer.params.check().map_err(Error::Handshake)?;
+    Ok(offer)
+}
+
+/// What the server handed out to the client in its response.
+struct Assignment {
+    id:Id,
+    address:Ipv4Addr,
+    prefix:u8,
+    gateway:Ipv4Addr,
+    ipv6:Option<Ipv6Lease>,
+    dns:dns::Config,
+    split:Split
 }
 
 fn initiate(
     socket:&UdpSocket,
     addr:&SocketAddr,
-    secret:&str
-) -> Result<(Id,Token,String),String>{
-    let key = derive_keys(secret);
-    let req_msg = Message::Request;
-    let mut encoded_req_msg:Vec<u8> = serialize(&req_msg).map_err(|e|e.to_string())?;
-    let mut encrypted_req_msg = encoded_req_msg.clone();
-    encoded_req_msg.resize(encoded_req_msg.len() + key.algorithm().tag_len(),0);
-    let (aad,nonce) = generate_add_nonce(secret);
-    key.seal_in_place_append_tag(nonce,aad,&mut encrypted_req_msg).unwrap();
+    secret:&str,
+    legacy_kdf:bool,
+    identity:Option<&StaticSecret>,
+    routes:&[Prefix]
+) -> Result<(Assignment,Session),Error>{
+    let psk = if legacy_kdf {
+        warn!("Using the legacy PBKDF2 key derivation.");
+        crypto::derive_legacy_psk(secret)
+    } else {
+        let offer = request_kdf_offer(socket,addr)?;
+        info!("Deriving key with Argon2id {:?}.", offer.params);
+        crypto::derive_psk(secret,&offer.salt,&offer.params).map_err(Error::Crypto)?
+    };
+    let key = crypto::handshake_key(&psk);
+    let rng = SystemRandom::new();
+    let handshake = Handshake::new(&rng).map_err(Error::Crypto)?;
+    let req_msg = Message::Request{
+        versions:Versions::SUPPORTED,
+        public_key:*handshake.public_key(),
+        timestamp:unix_time(),
+        identity:identity.map(|private_key| PublicKey::from(private_key).to_bytes()),
+        routes:routes.to_vec()
+    };
+    let encrypted_req_msg = encrypt_handshake(&key,&rng,MIN_VERSION,&req_msg)?;
     let mut remaining_len = encrypted_req_msg.len();
     while remaining_len > 0{
-        let send_bytes = socket.send_to(&encrypted_req_msg,addr).map_err(|e|e.to_string())?;
+        let send_bytes = socket.send_to(&encrypted_req_msg,addr).map_err(Error::Socket)?;
         remaining_len -= send_bytes;
     }
     info!("request sent to {} .",addr);
     let mut buf = [0u8;1600];
-    let (len , _recv_addr) = socket.recv_from(&mut buf).map_err(|e|e.to_string())?;
+    let (len , _recv_addr) = socket.recv_from(&mut buf).map_err(Error::Socket)?;
     info!("Response received from {}.", addr);
-    let (aad,nonce) = generate_add_nonce(secret);
-    let decrypted_buf = key.open_in_place(nonce,aad,&mut buf[0..len]).unwrap();
-    let d_len = decrypted_buf.len();
-    let resp_msg:Message = deserialize(&decrypted_buf[0..d_len]).map_err(|e|e.to_string())?;
+    if Header::decode(&buf[0..len]).map_err(Error::Decode)?.message_type == MessageType::Unsupported {
+        let versions = Versions::decode(&buf[HEADER_LEN..len]).map_err(Error::Decode)?;
+        return Err(Error::Handshake(format!(
+            "server speaks protocol versions {} to {}, we speak {} to {}",
+            versions.min, versions.max, Versions::SUPPORTED.min, Versions::SUPPORTED.max
+        )));
+    }
+    let resp_msg = decrypt_handshake(&key,&mut buf[0..len])?;
     match resp_msg {
-        Message::Response { id,token,dns } => Ok((id,token,dns)),
-        _ => Err(format!("invalid message {:?} from {} " , resp_msg,addr))
+        Message::Response { version,id,token,public_key,address,prefix,gateway,ipv6,dns,split } => {
+            if Versions::SUPPORTED.negotiate(&Versions{ min:version, max:version }).is_none() {
+                return Err(Error::Handshake(format!("server chose unsupported protocol version {}",version)));
+            }
+            let keys = match identity {
+                None => handshake.finish(&psk,Role::Client,&public_key),
+                Some(private_key) => handshake.finish_authenticated(
+                    &psk,
+                    Role::Client,
+                    &public_key,
+                    ClientKey::Private(private_key)
+                )
+            }.map_err(Error::Handshake)?;
+            let subnet = Subnet::new(address,prefix).map_err(Error::Handshake)?;
+            if !subnet.contains(gateway) || gateway == address {
+                return Err(Error::Handshake(format!("gateway {} unusable for {}/{}",gateway,address,prefix)));
+            }
+            if let Some(ipv6) = ipv6 {
+                let subnet6 = Subnet::new(ipv6.address,ipv6.prefix).map_err(Error::Handshake)?;
+                if !subnet6.contains(ipv6.gateway) || ipv6.gateway == ipv6.address {
+                    return Err(Error::Handshake(format!(
+                        "gateway {} unusable for {}/{}",
+                        ipv6.gateway, ipv6.address, ipv6.prefix
+                    )));
+                }
+            }
+            let assignment = Assignment{ id, address, prefix, gateway, ipv6, dns, split };
+            Ok((assignment,Session::new(token,*addr,version,Role::Client,&psk,keys,RekeyLimits::default())))
+        }
+        Message::Reject{reason} => Err(Error::Handshake(format!("server rejected the request: {}",reason))),
+        _ => Err(Error::Handshake(format!("invalid message {:?} from {} " , resp_msg,addr)))
     }
 }
 
-pub fn connect(host:&str,port:u16,default:bool,secret:&str) {
+/// State of the client's side of an established tunnel.
+struct Connection {
+    id:Id,
+    session:Session,
+    remote_addr:SocketAddr,
+    rng:SystemRandom,
+    encoder:snap::raw::Encoder,
+    decoder:snap::raw::Decoder,
+    /// When the last authenticated datagram came from the server.
+    last_received:Instant,
+    last_keepalive:Instant
+}
+
+impl Connection {
+    fn new(id:Id,session:Session,remote_addr:SocketAddr) -> Connection {
+        let now = Instant::now();
+        Connection{
+            id,
+            session,
+            remote_addr,
+            rng:SystemRandom::new(),
+            encoder:snap::raw::Encoder::new(),
+            decoder:snap::raw::Decoder::new(),
+            last_received:now,
+            last_keepalive:now
+        }
+    }
+
+    /// Sends a keepalive once the server has been quiet for a while, failing when it has been
+    /// quiet for too long.
+    fn keepalive(&mut self,socket:&mio::net::UdpSocket,now:Instant) -> Result<(),Error> {
+        let quiet = now.saturating_duration_since(self.last_received);
+        if quiet >= PEER_TIMEOUT {
+            return Err(Error::Socket(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("nothing received from {} for {} seconds",self.remote_addr,quiet.as_secs())
+            )));
+        }
+        if quiet >= KEEPALIVE_INTERVAL && now.saturating_duration_since(self.last_keepalive) >= KEEPALIVE_INTERVAL {
+            let datagram = encrypt_message(&mut self.session,self.id,&Message::Keepalive)?;
+            send_datagram(socket,&datagram,self.remote_addr)?;
+            self.last_keepalive = now;
+        }
+        Ok(())
+    }
+
+    /// Handles a datagram from the server, writing tunneled packets to `tun`.
+    fn receive(&mut self,socket:&mio::net::UdpSocket,tun:&mut impl Write,datagram:&mut [u8]) -> Result<(),Error> {
+        let header = Header::decode(datagram).map_err(Error::Decode)?;
+        if header.session != self.id {
+            return Err(Error::Decrypt(format!("datagram for session {}",header.session)));
+        }
+        let msg = match decrypt_message(&mut self.session,datagram)? {
+            None => return Ok(()),
+            Some(msg) => msg
+        };
+        self.last_received = Instant::now();
+        match msg {
+            Message::Rekey{..} | Message::RekeyAck{..} => {
+                if let Some(reply) = handle_rekey(&mut self.session,&self.rng,self.id,msg)? {
+                    send_datagram(socket,&reply,self.remote_addr)?;
+                }
+                Ok(())
+            }
+            Message::Keepalive => Ok(()),
+            Message::Data{data} => write_packet(tun,&mut self.decoder,&data),
+            _ => Err(Error::Decode(format!("unexpected {:?}",msg.message_type())))
+        }
+    }
+
+    /// Sends a packet read from the TUN device to the server.
+    fn send(&mut self,socket:&mio::net::UdpSocket,packet:&[u8]) -> Result<(),Error> {
+        let msg = Message::Data{ data:self.encoder.compress_vec(packet).map_err(Error::Compression)? };
+        let datagram = encrypt_message(&mut self.session,self.id,&msg)?;
+        send_datagram(socket,&datagram,self.remote_addr)?;
+        if let Some(rekey) = rekey_if_needed(&mut self.session,&self.rng,self.id)? {
+            send_datagram(socket,&rekey,self.remote_addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tries the handshake with each address of the server in turn. Only addresses that cannot
+/// be reached are skipped; once the server answers, its answer decides.
+fn initiate_any(
+    remote_ips:&[IpAddr],
+    port:u16,
+    secret:&str,
+    legacy_kdf:bool,
+    identity:Option<&StaticSecret>,
+    routes:&[Prefix]
+) -> Result<(UdpSocket,SocketAddr,Assignment,Session),Error> {
+    let mut last_error = Error::Resolve(String::from("no address to try"));
+    for &remote_ip in remote_ips {
+        let remote_addr = SocketAddr::new(remote_ip,port);
+        info!("Trying server {}.", remote_addr);
+        let local_ip = match remote_ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let attempt = UdpSocket::bind(SocketAddr::new(local_ip,0))
+            .and_then(|socket| socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map(|_| socket))
+            .map_err(Error::Socket)
+            .and_then(|socket| {
+                let (assignment,session) = initiate(&socket,&remote_addr,secret,legacy_kdf,identity,routes)?;
+                Ok((socket,remote_addr,assignment,session))
+            });
+        match attempt {
+            Err(Error::Socket(e)) => {
+                warn!("{} unreachable: {}", remote_addr, e);
+                last_error = Error::Socket(e);
+            }
+            result => return result
+        }
+    }
+    Err(last_error)
+}
+
+pub fn connect(config:&cli::Client) -> Result<(),Error> {
     info!("Working in client mode.");
-    let remote_ip = resolve(host).unwrap();
-    let remote_addr = SocketAddr::new(remote_ip, port);
+    // Opened before the TUN device so the system is restored after the device is gone.
+    let mut journal = Journal::open(&config.state)?;
+    let remote_ips = resolve(&config.remote_addr)?;
+    let identity = config.identity.as_deref().map(keys::private_key).transpose().map_err(Error::Config)?;
+    if let Some(private_key) = &identity {
+        info!("Identity: {}", keys::encode(PublicKey::from(private_key).as_bytes()));
+    }
+    if !config.kill_switch {
+        return tunnel(config,&mut journal,&remote_ips,identity.as_ref(),None);
+    }
+    // Installed once with the addresses resolved up front, as the rules block name lookups
+    // outside the tunnel.
+    let kill_switch = KillSwitch::install(&mut journal,remote_ips.iter().map(|ip| SocketAddr::new(*ip,config.port)).collect())?;
+    let mark = journal.mark();
+    loop {
+        match panic::catch_unwind(AssertUnwindSafe(|| tunnel(config,&mut journal,&remote_ips,identity.as_ref(),Some(&kill_switch)))) {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => warn!("Tunnel failed: {}.", e),
+            Err(_) => warn!("Tunnel terminated abnormally.")
+        }
+        CONNECTED.store(false,Ordering::Relaxed);
+        journal.restore_since(mark)?;
+        info!("Reconnecting in {} seconds, blocking traffic until then.", RECONNECT_INTERVAL.as_secs());
+        let failed = Instant::now();
+        while failed.elapsed() < RECONNECT_INTERVAL {
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            thread::sleep(SIGNAL_INTERVAL);
+        }
+    }
+}
+
+/// Sets up the tunnel and carries traffic through it until interrupted, noting system
+/// changes in `journal`. Traffic through the TUN device is let past `kill_switch`.
+fn tunnel(
+    config:&cli::Client,
+    journal:&mut Journal,
+    remote_ips:&[IpAddr],
+    identity:Option<&StaticSecret>,
+    kill_switch:Option<&KillSwitch>
+) -> Result<(),Error> {
+    let (socket,remote_addr,assignment,session) = initiate_any(
+        remote_ips,
+        config.port,
+        &config.key,
+        config.legacy_kdf,
+        identity,
+        &config.advertise
+    )?;
     info!("Remote server: {}", remote_addr);
-    let local_addr:SocketAddr = "0.0.0.0".parse::<SocketAddr>().unwrap();
-    let socket = UdpSocket::bind(&local_addr).unwrap();
-    let key = derive_keys(secret);
-    let (id,token,dns) = initiate(&socket, &remote_addr, &secret).unwrap();
+    let Assignment{ id, address, prefix, gateway, ipv6, dns, mut split } = assignment;
     info!(
-        "Session established with token {}. Assigned IP address: 10.10.10.{}. dns: {}",
-        token, id, dns
+        "Session {} established with token {}. Assigned IP address: {}. dns: {}",
+        id, session.token, address, dns
     );
+    for prefix in &split.include {
+        info!("Server routes {} through the tunnel.", prefix);
+    }
+    for prefix in &split.exclude {
+        info!("Server routes {} around the tunnel.", prefix);
+    }
+    split.merge(&config.split);
+    let mut tunnels = vec![Prefix::new(IpAddr::V4(address),prefix).map_err(Error::Config)?];
+    if let Some(ipv6) = ipv6 {
+        tunnels.push(Prefix::new(IpAddr::V6(ipv6.address),ipv6.prefix).map_err(Error::Config)?);
+    }
+    split.check(&tunnels).map_err(Error::Config)?;
+    for route in &config.advertise {
+        info!("Server routes {} to this client.", route);
+    }
+    if config.advertise.iter().any(|route| route.network().is_ipv4()) {
+        enable_ipv4_forwarding(journal)?;
+    }
+    if config.advertise.iter().any(|route| route.network().is_ipv6()) {
+        enable_ipv6_forwarding(journal)?;
+    }
     info!("Bringing up TUN device.");
-    let mut tun = create_tun_attempt();
+    let mut tun = create_tun_attempt()?;
     let tun_rawfd = tun.as_raw_fd();
-    tun.up(id);
-    let mut tunfd = &tun_rawfd;
+    tun.up(address,prefix,gateway).map_err(Error::Tun)?;
+    if let Some(ipv6) = ipv6 {
+        tun.add_ipv6(ipv6.address,ipv6.prefix).map_err(Error::Tun)?;
+        info!("Assigned IPv6 address: {}/{}.", ipv6.address, ipv6.prefix);
+    }
+    tun.set_nonblocking().map_err(Error::Tun)?;
+    let mut tunfd = mio::unix::SourceFd(&tun_rawfd);
     info!(
-        "TUN device {} initialized. Internal IP: 10.10.10.{}/24.",
+        "TUN device {} initialized. Internal IP: {}/{}.",
         tun.name(),
-        id
+        address,
+        prefix
     );
-    info!("setting dns to {}", dns);
-    utils::set_dns(&dns).unwrap();
-    let mut poll = mio::Poll::new().unwrap();
+    if let Some(kill_switch) = kill_switch {
+        kill_switch.allow(journal,tun.name())?;
+    }
+    info!("Setting DNS to {}.", dns);
+    dns::configure(journal,tun.name(),&dns,config.default_route)?;
+    let mut poll = mio::Poll::new().map_err(Error::Io)?;
     info!("Setting up TUN device for polling.");
     poll.registry()
         .register(
             &mut tunfd,
             TUN,
-            mio::Interest::READABLE | mio::Interest::WRITABLE,
+            mio::Interest::READABLE,
         )
-        .unwrap();
+        .map_err(Error::Io)?;
     info!("Setting up socket for polling.");
+    socket.set_nonblocking(true).map_err(Error::Socket)?;
     let mut sockfd = mio::net::UdpSocket::from_std(socket);
     poll.registry()
         .register(&mut sockfd, SOCK, mio::Interest::READABLE)
-        .unwrap();
+        .map_err(Error::Io)?;
     let mut events = mio::Events::with_capacity(1024);
     let mut buf = [0u8;1600];
-    let _gw = DefaultGateway::create("10.10.10.1",&format!("{}",remote_addr.ip()),default);
-    let mut encoder = snap::raw::Encoder::new();
-    let mut decoder = snap::raw::Decoder::new();
+    if ipv6.is_none() && config.default_route6 {
+        warn!("The server hands out no IPv6 address, IPv6 traffic is not routed through the tunnel.");
+    }
+    utils::set_tunnel_routes(
+        journal,
+        IpAddr::V4(gateway),
+        ipv6.map(|ipv6| IpAddr::V6(ipv6.gateway)),
+        remote_addr.ip(),
+        config.default_route,
+        config.default_route6,
+        &split
+    )?;
+    let mut connection = Connection::new(id,session,remote_addr);
+    let mut errors = ErrorLog::new();
     CONNECTED.store(true,Ordering::Relaxed);
     info!("Ready for transmission.");
     loop {
         if INTERRUPTED.load(Ordering::Relaxed) {
             break;
         }
-        poll.poll(&mut events, None).unwrap();
+        connection.keepalive(&sockfd,Instant::now())?;
+        if let Err(e) = poll.poll(&mut events,Some(SIGNAL_INTERVAL)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(Error::Io(e));
+        }
         for event in events.iter() {
             match event.token(){
                 SOCK => {
-                    let (len , addr) = sockfd.recv_from(&mut buf).unwrap();
-                    let (aad,nonce) = generate_add_nonce(secret);
-                    let decrypted_buf = key.open_in_place(nonce,aad,&mut buf[0..len]).unwrap();
-                    let d_len = decrypted_buf.len();
-                    let msg:Message = deserialize(&decrypted_buf[0..d_len]).unwrap();
-                    match msg {
-                        Message::Request | Message::Response{
-                            id:_,
-                            token:_,
-                            dns:_,
-                        } => {
-                            warn!("Invalid message {:?} from {}", msg, addr);
-                        }
-                        Message::Data {
-                            id:_,
-                            token:server_token,
-                            data,
-                        } => {
-                            if token == server_token {
-                                let decompressed_data = decoder.decompress_vec(&data).unwrap();
-                                let data_len = decompressed_data.len();
-                                let mut sent_len = 0;
-                                while sent_len < data_len {
-                                    sent_len += tun.write(&decompressed_data[sent_len..data_len]).unwrap();
-                                }
-                            } else {
-                                warn!(
-                                    "Token mismatched. Received: {}. Expected: {}",
-                                    server_token, token
-                                );
-                            }
+                    loop {
+                        let (len , addr) = match sockfd.recv_from(&mut buf) {
+                            Ok(received) => received,
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(Error::Socket(e))
+                        };
+                        if let Err(e) = connection.receive(&sockfd,&mut tun,&mut buf[0..len]) {
+                            errors.record(Source::Peer(addr),&e);
                         }
                     }
                 }
                 TUN => {
-                    let len:usize = tun.read(&mut buf).unwrap();
-                    let data = &buf[0..len];
-                    let msg = Message::Data {
-                        id,
-                        token,
-                        data:encoder.compress_vec(data).unwrap()
-                    };
-                    let encode_msg = serialize(&msg).unwrap();
-                    let mut encrypted_msg = encode_msg.clone();
-                    encrypted_msg.resize(encode_msg.len() + key.algorithm().tag_len(),0);
-                    let (aad,nonce) = generate_add_nonce(secret);
-                    key.seal_in_place_append_tag(nonce,aad,&mut encrypted_msg).unwrap();
-                    let mut sent_len = 0;
-                    while sent_len < encrypted_msg.len() {
-                        sent_len += sockfd.send_to(&encrypted_msg[sent_len..encrypted_msg.len()],remote_addr).unwrap()
+                    loop {
+                        let len:usize = match tun.read(&mut buf) {
+                            Ok(len) => len,
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(Error::Tun(e))
+                        };
+                        if let Err(e) = connection.send(&sockfd,&buf[0..len]) {
+                            errors.record(Source::Tun,&e);
+                        }
                     }
                 }
                 _ => unreachable!()
             }
         }
     }
+    Ok(())
+}
+
+/// Tells an authenticated client why its request is refused, returning the error to record.
+fn reject(
+    socket:&mio::net::UdpSocket,
+    addr:SocketAddr,
+    secret:&Secret,
+    rng:&SystemRandom,
+    version:u8,
+    reason:String
+) -> Error {
+    let reject = Message::Reject{ reason:reason.clone() };
+    let sent = encrypt_handshake(&secret.key,rng,version,&reject).and_then(|datagram| send_datagram(socket,&datagram,addr));
+    match sent {
+        Ok(()) => Error::Handshake(reason),
+        Err(e) => e
+    }
+}
+
+/// Sends the protocol versions the server speaks to a client it shares none with.
+fn send_unsupported(socket:&mio::net::UdpSocket,addr:SocketAddr) -> Result<(),Error> {
+    let answer = encode_clear(MIN_VERSION,MessageType::Unsupported,&Versions::SUPPORTED.encode());
+    send_datagram(socket,&answer,addr)
 }
 
+/// What the server lets clients do besides reaching it and the internet.
+#[derive(Clone,Copy,Debug)]
+struct Policy {
+    /// Whether clients may advertise prefixes behind them.
+    accept_routes:bool,
+    /// Whether packets from one client to another are dropped instead of forwarded.
+    isolate_clients:bool
+}
+
+/// Settings the server hands every client along with its addresses.
+#[derive(Clone,Debug,Default)]
+struct Push {
+    dns:dns::Config,
+    split:Split
+}
+
+/// State of the server: its secrets, the client table and the sessions of connected clients.
+struct Server {
+    push:Push,
+    secrets:Vec<Secret>,
+    encoded_offer:Vec<u8>,
+    clients:Option<ClientTable>,
+    sessions:TransientHashMap<Id,Session>,
+    pool:AddressPool,
+    pool6:Option<AddressPool<Ipv6Addr>>,
+    routes:RoutingTable,
+    policy:Policy,
+    /// Prefixes advertised by each session, routed to it by the kernel as well.
+    advertised:HashMap<Id,Vec<Prefix>>,
+    /// When each client ephemeral key of a recent request was seen, so that a replayed
+    /// request gets no lease, kept for twice [`HANDSHAKE_WINDOW`] whether the session lasts
+    /// or not.
+    handshakes:HashMap<[u8;KEY_LEN],Instant>,
+    rng:ThreadRng,
+    sys_rng:SystemRandom,
+    encoder:snap::raw::Encoder,
+    decoder:snap::raw::Decoder
+}
+
+impl Server {
+    fn new(
+        subnet:Subnet,
+        subnet6:Option<Subnet<Ipv6Addr>>,
+        push:Push,
+        secrets:Vec<Secret>,
+        offer:&KdfOffer,
+        clients:Option<ClientTable>,
+        policy:Policy
+    ) -> Server {
+        Server{
+            push,
+            secrets,
+            encoded_offer:offer.encode(),
+            clients,
+            sessions:TransientHashMap::new(60),
+            pool:AddressPool::new(subnet),
+            pool6:subnet6.map(AddressPool::new),
+            routes:RoutingTable::new(),
+            policy,
+            advertised:HashMap::new(),
+            handshakes:HashMap::new(),
+            rng:thread_rng(),
+            sys_rng:SystemRandom::new(),
+            encoder:snap::raw::Encoder::new(),
+            decoder:snap::raw::Decoder::new()
+        }
+    }
+
+    /// Frees the addresses of expired sessions and drops the sessions of revoked clients.
+    fn prune(&mut self) {
+        self.handshakes.retain(|_,seen| seen.elapsed() < 2 * HANDSHAKE_WINDOW);
+        let mut ended = self.sessions.prune();
+        if let Some(clients) = self.clients.as_mut() {
+            match clients.reload_if_changed() {
+                Ok(false) => {}
+                Ok(true) => {
+                    info!("Client table changed, dropping revoked sessions.");
+                    ended.append(&mut revoke_sessions(clients,&mut self.sessions));
+                }
+                Err(e) => warn!("Failed to reload client table: {}", e)
+            }
+        }
+        for id in ended {
+            self.release(id);
+        }
+    }
+
+    /// Addresses leased to the sessions of the client called `name`.
+    fn host_addresses(&self,name:&str) -> Vec<IpAddr> {
+        self.sessions
+            .iter()
+            .filter(|(_,session)| session.identity.as_ref().is_some_and(|identity| identity.name.eq_ignore_ascii_case(name)))
+            .flat_map(|(id,_)| {
+                let address = self.pool.leased(*id).map(IpAddr::V4);
+                let address6 = self.pool6.as_ref().and_then(|pool6| pool6.leased(*id)).map(IpAddr::V6);
+                address.into_iter().chain(address6)
+            })
+            .collect()
+    }
+
+    /// Gives back the addresses leased to session `id` and drops its routes.
+    fn release(&mut self,id:Id) {
+        self.routes.remove_session(id);
+        for route in self.advertised.remove(&id).unwrap_or_default() {
+            if let Err(e) = utils::delete_prefix_route(&route) {
+                warn!("Failed to remove route {} of session {}: {}", route, id, e);
+            }
+        }
+        self.pool.release(id);
+        if let Some(pool6) = self.pool6.as_mut() {
+            pool6.release(id);
+        }
+    }
+
+    /// Handles a datagram from `addr`, writing tunneled packets to `tun`.
+    fn receive(
+        &mut self,
+        socket:&mio::net::UdpSocket,
+        tun:&mut impl Write,
+        addr:SocketAddr,
+        datagram:&mut [u8]
+    ) -> Result<(),Error> {
+        let header = Header::decode(datagram).map_err(Error::Decode)?;
+        match header.message_type {
+            MessageType::KdfRequest => {
+                if datagram.len() < KDF_REQUEST_LEN {
+                    return Err(Error::Decode(String::from("short KDF request")));
+                }
+                let answer = encode_clear(header.version,MessageType::KdfOffer,&self.encoded_offer);
+                send_datagram(socket,&answer,addr)
+            }
+            MessageType::Request => self.accept(socket,addr,header.version,datagram),
+            MessageType::Data | MessageType::Rekey | MessageType::RekeyAck | MessageType::Keepalive => {
+                let id = header.session;
+                let session = self
+                    .sessions
+                    .get_mut(&id)
+                    .ok_or_else(|| Error::Decrypt(format!("unknown session {}",id)))?;
+                let msg = match decrypt_message(session,datagram)? {
+                    None => return Ok(()),
+                    Some(msg) => msg
+                };
+                match msg {
+                    Message::Rekey{..} | Message::RekeyAck{..} => {
+                        if let Some(reply) = handle_rekey(session,&self.sys_rng,id,msg)? {
+                            send_datagram(socket,&reply,session.addr)?;
+                        }
+                        Ok(())
+                    }
+                    Message::Keepalive => {
+                        let reply = encrypt_message(session,id,&Message::Keepalive)?;
+                        send_datagram(socket,&reply,session.addr)
+                    }
+                    Message::Data{data} => {
+                        let packet = self.decoder.decompress_vec(&data).map_err(Error::Compression)?;
+                        self.forward(socket,tun,id,&packet)
+                    }
+                    _ => Err(Error::Decode(format!("unexpected {:?}",msg.message_type())))
+                }
+            }
+            MessageType::KdfOffer | MessageType::Unsupported | MessageType::Response | MessageType::Reject => {
+                Err(Error::Decode(format!("unexpected {:?}",header.message_type)))
+            }
+        }
+    }
+
+    /// Passes a packet from session `id` on. Packets for other clients go straight to them
+    /// unless clients are isolated, everything else to the TUN device.
+    fn forward(
+        &mut self,
+        socket:&mio::net::UdpSocket,
+        tun:&mut impl Write,
+        id:Id,
+        packet:&[u8]
+    ) -> Result<(),Error> {
+        let destination = packet::destination(packet).ok();
+        match destination.and_then(|destination| self.routes.lookup(destination).map(|target| (destination,target))) {
+            Some((destination,target)) if target != id => {
+                if self.policy.isolate_clients {
+                    return Err(Error::Policy(format!("session {} may not reach {} of session {}",id,destination,target)));
+                }
+                self.send(socket,packet)
+            }
+            _ => write_tun(tun,packet)
+        }
+    }
 
-pub fn serve(port:u16,secret:&str,dns:IpAddr) {
+    /// Checks that the prefixes a client advertises can be routed to it, returning why not
+    /// otherwise.
+    fn check_routes(&self,routes:&[Prefix]) -> Result<(),String> {
+        if !routes.is_empty() && !self.policy.accept_routes {
+            return Err(String::from("advertised routes are not accepted"));
+        }
+        let mut tunnel = vec![Prefix::from(self.pool.subnet())];
+        tunnel.extend(self.pool6.as_ref().map(|pool6| Prefix::from(pool6.subnet())));
+        for (index,route) in routes.iter().enumerate() {
+            if route.length() == 0 {
+                return Err(format!("{} would replace the default route",route));
+            }
+            if route.network().is_ipv6() && self.pool6.is_none() {
+                return Err(format!("{} needs IPv6 inside the tunnel",route));
+            }
+            if let Some(subnet) = tunnel.iter().find(|subnet| subnet.overlaps(route)) {
+                return Err(format!("{} overlaps the tunnel subnet {}",route,subnet));
+            }
+            if routes[..index].contains(route) {
+                return Err(format!("{} advertised twice",route));
+            }
+            if let Some(id) = self.routes.get(*route) {
+                return Err(format!("{} is already routed to session {}",route,id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a handshake request, starting a session for the client.
+    fn accept(
+        &mut self,
+        socket:&mio::net::UdpSocket,
+        addr:SocketAddr,
+        version:u8,
+        datagram:&mut [u8]
+    ) -> Result<(),Error> {
+        if Versions::SUPPORTED.negotiate(&Versions{ min:version, max:version }).is_none() {
+            send_unsupported(socket,addr)?;
+            return Err(Error::Handshake(format!("unsupported protocol version {}",version)));
+        }
+        let (msg,secret) = decrypt_handshake_any(&self.secrets,datagram)?;
+        let (versions,public_key,timestamp,identity,routes) = match msg {
+            Message::Request{versions,public_key,timestamp,identity,routes} => {
+                (versions,public_key,timestamp,identity,routes)
+            }
+            _ => return Err(Error::Decode(format!("unexpected {:?}",msg.message_type())))
+        };
+        let version = match Versions::SUPPORTED.negotiate(&versions) {
+            Some(version) => version,
+            None => {
+                send_unsupported(socket,addr)?;
+                return Err(Error::Handshake(format!("no common protocol version in {:?}",versions)));
+            }
+        };
+        if unix_time().abs_diff(timestamp) > HANDSHAKE_WINDOW.as_secs() {
+            return Err(Error::Handshake(format!("request timestamp {} is out of the window",timestamp)));
+        }
+        if self.handshakes.contains_key(&public_key) {
+            return Err(Error::Handshake(String::from("replayed request")));
+        }
+        self.handshakes.insert(public_key,Instant::now());
+        let identity = authorize(self.clients.as_ref(),identity.as_ref().map(|identity| &identity[..]))?;
+        if let Err(reason) = self.check_routes(&routes) {
+            return Err(reject(socket,addr,secret,&self.sys_rng,version,reason));
+        }
+        let handshake = Handshake::new(&self.sys_rng).map_err(Error::Crypto)?;
+        let server_public_key = *handshake.public_key();
+        let keys = match &identity {
+            None => handshake.finish(&secret.psk,Role::Server,&public_key),
+            Some(identity) => handshake.finish_authenticated(
+                &secret.psk,
+                Role::Server,
+                &public_key,
+                ClientKey::Public(&PublicKey::from(identity.public_key))
+            )
+        }.map_err(Error::Handshake)?;
+        let client_id = loop {
+            let id = self.rng.gen::<Id>();
+            if id != 0 && !self.sessions.contains_key(&id) {
+                break id;
+            }
+        };
+        let address = match self.pool.allocate(client_id) {
+            Some(address) => address,
+            None => {
+                let reason = format!("address pool {} exhausted",self.pool.subnet());
+                return Err(reject(socket,addr,secret,&self.sys_rng,version,reason));
+            }
+        };
+        let ipv6 = match self.pool6.as_mut() {
+            None => None,
+            Some(pool6) => match pool6.allocate(client_id) {
+                Some(address) => Some(Ipv6Lease{
+                    address,
+                    prefix:pool6.subnet().prefix(),
+                    gateway:pool6.subnet().gateway()
+                }),
+                None => {
+                    self.pool.release(client_id);
+                    let reason = format!("address pool {} exhausted",pool6.subnet());
+                    return Err(reject(socket,addr,secret,&self.sys_rng,version,reason));
+                }
+            }
+        };
+        let client_token:Token = self.rng.gen::<Token>();
+        let reply = Message::Response {
+            version,
+            id:client_id,
+            token:client_token,
+            public_key:server_public_key,
+            address,
+            prefix:self.pool.subnet().prefix(),
+            gateway:self.pool.subnet().gateway(),
+            ipv6,
+            dns:self.push.dns.clone(),
+            split:self.push.split.clone()
+        };
+        let encrypted_reply = match encrypt_handshake(&secret.key,&self.sys_rng,version,&reply) {
+            Ok(encrypted_reply) => encrypted_reply,
+            Err(e) => {
+                self.release(client_id);
+                return Err(e);
+            }
+        };
+        let mut session = Session::new(
+            client_token,
+            addr,
+            version,
+            Role::Server,
+            &secret.psk,
+            keys,
+            RekeyLimits::default()
+        );
+        let client = identity.as_ref().map_or(String::from("anonymous client"),|identity| identity.to_string());
+        session.identity = identity;
+        self.sessions.insert(client_id,session);
+        self.routes.insert(Prefix::host(IpAddr::V4(address)),client_id);
+        if let Some(ipv6) = ipv6 {
+            self.routes.insert(Prefix::host(IpAddr::V6(ipv6.address)),client_id);
+        }
+        for route in routes {
+            let gateway = match route.network() {
+                IpAddr::V4(_) => IpAddr::V4(address),
+                IpAddr::V6(_) => IpAddr::V6(ipv6.expect("checked IPv6 routes need an IPv6 address").address)
+            };
+            if let Err(e) = utils::add_prefix_route(&route,gateway) {
+                let error = reject(socket,addr,secret,&self.sys_rng,version,format!("failed to route {}: {}",route,e));
+                self.sessions.remove(&client_id);
+                self.release(client_id);
+                return Err(error);
+            }
+            self.advertised.entry(client_id).or_default().push(route);
+            self.routes.insert(route,client_id);
+            info!("Routing {} to session {}.", route, client_id);
+        }
+        info!(
+            "Got request from {} at {}. Assigning IP address {} to session {}.",
+            client, addr, address, client_id
+        );
+        if secret.legacy {
+            warn!("Client {} uses the legacy PBKDF2 key derivation.", addr);
+        }
+        send_datagram(socket,&encrypted_reply,addr)
+    }
+
+    /// Sends a packet read from the TUN device to the client it is addressed to.
+    fn send(&mut self,socket:&mio::net::UdpSocket,packet:&[u8]) -> Result<(),Error> {
+        let destination = packet::destination(packet).map_err(Error::Decode)?;
+        let id = self.routes.lookup(destination);
+        let session = id.and_then(|id| self.sessions.get_mut(&id).map(|session| (id,session)));
+        let (client_id,session) = session.ok_or_else(|| Error::Route(destination.to_string()))?;
+        let msg = Message::Data{ data:self.encoder.compress_vec(packet).map_err(Error::Compression)? };
+        let datagram = encrypt_message(session,client_id,&msg)?;
+        send_datagram(socket,&datagram,session.addr)?;
+        if let Some(rekey) = rekey_if_needed(session,&self.sys_rng,client_id)? {
+            send_datagram(socket,&rekey,session.addr)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn serve(config:&cli::Server) -> Result<(),Error> {
+    let subnet = config.subnet;
+    let subnet6 = config.subnet6;
+    let kdf = config.kdf;
     if cfg!(not(target_os = "linux")){
-        panic!("Server mode is only available in Linux!");
+        return Err(Error::Config(String::from("Server mode is only available in Linux!")));
     }
     info!("Working in server mode.");
-    let public_ip = get_public_ip().unwrap();
-    info!("Public IP: {}", public_ip);
+    let mut journal = Journal::open(&config.state)?;
+    match get_public_ip() {
+        Ok(public_ip) => info!("Public IP: {}", public_ip),
+        Err(e) => warn!("Unable to determine public IP: {}", e),
+    }
     info!("Enabling kernel's IPv4 forwarding.");
-    enable_ipv4_forwarding().unwrap();
+    enable_ipv4_forwarding(&mut journal)?;
+    if subnet6.is_some() {
+        enable_ipv6_forwarding(&mut journal)?;
+    }
     info!("Bringing up TUN device.");
-    let mut tun = create_tun_attempt();
-    tun.up(1);
+    let mut tun = create_tun_attempt()?;
+    tun.up(subnet.gateway(),subnet.prefix(),subnet.gateway()).map_err(Error::Tun)?;
+    if let Some(subnet6) = subnet6 {
+        tun.add_ipv6(subnet6.gateway(),subnet6.prefix()).map_err(Error::Tun)?;
+        info!("IPv6 subnet: {}.", subnet6);
+    }
+    tun.set_nonblocking().map_err(Error::Tun)?;
     let tun_raw_fd = tun.as_raw_fd();
     let mut tun_fd = mio::unix::SourceFd(&tun_raw_fd);
     info!(
-        "TUN device {} initialized. Internal IP: 10.10.10.1/24.",
-        tun.name()
+        "TUN device {} initialized. Internal IP: {}/{}.",
+        tun.name(),
+        subnet.gateway(),
+        subnet.prefix()
     );
-    let addr = format!("0.0.0.0:{}",port).parse().unwrap();
-    let mut sock_fd = mio::net::UdpSocket::bind(addr).unwrap();
-    info!("Listening on: 0.0.0.0:{}.", port);
-    let mut poll = mio::Poll::new().unwrap();
+    let mut subnets = vec![Prefix::from(subnet)];
+    subnets.extend(subnet6.map(Prefix::from));
+    if config.masquerade {
+        Masquerade::install(&mut journal,tun.name(),subnets.clone(),config.egress.as_deref())?;
+    }
+    let addr = SocketAddr::new(config.bind_addr,config.port);
+    let socket = bind(addr).map_err(Error::Socket)?;
+    socket.set_nonblocking(true).map_err(Error::Socket)?;
+    let mut sock_fd = mio::net::UdpSocket::from_std(socket);
+    info!("Listening on: {}.", addr);
+    let mut poll = mio::Poll::new().map_err(Error::Io)?;
     poll.registry()
         .register(&mut sock_fd, SOCK, mio::Interest::READABLE)
-        .unwrap();
+        .map_err(Error::Io)?;
     poll.registry()
         .register(&mut tun_fd, TUN, mio::Interest::READABLE)
-        .unwrap();
+        .map_err(Error::Io)?;
     let mut events = mio::Events::with_capacity(1024);
-    let mut rng = thread_rng();
-    let mut available_ids:Vec<Id> = (2..254).collect();
-    let mut client_info:TransientHashMap<Id,(Token,SocketAddr)> = TransientHashMap::new(60);
     let mut buf = [0u8;1600];
-    let mut encoder = snap::raw::Encoder::new();
-    let mut decoder = snap::raw::Decoder::new();
-    let key = derive_keys(secret);
+    let offer = KdfOffer{ salt:crypto::random_salt(&SystemRandom::new()).map_err(Error::Crypto)?, params:kdf };
+    info!("Deriving key with Argon2id {:?}.", kdf);
+    let psk = crypto::derive_psk(&config.key,&offer.salt,&kdf).map_err(Error::Crypto)?;
+    let mut secrets = vec![Secret::new(psk,false)];
+    if config.legacy_kdf {
+        info!("Also accepting clients using the legacy PBKDF2 key derivation.");
+        secrets.push(Secret::new(crypto::derive_legacy_psk(&config.key),true));
+    }
+    let clients = match &config.clients {
+        None => None,
+        Some(path) => {
+            info!("Only accepting clients listed in {}.", path);
+            Some(ClientTable::load(path).map_err(Error::Config)?)
+        }
+    };
+    let mut forwarder = None;
+    let mut dns = config.dns.clone();
+    if let Some(domain) = &config.dns_proxy {
+        let mut servers = vec![IpAddr::V4(subnet.gateway())];
+        servers.extend(subnet6.map(|subnet6| IpAddr::V6(subnet6.gateway())));
+        let listen:Vec<SocketAddr> = servers.iter().map(|server| SocketAddr::new(*server,DNS_PORT)).collect();
+        let upstreams = config.dns.servers.iter().map(|server| SocketAddr::new(*server,DNS_PORT)).collect();
+        let mut dns_proxy = Forwarder::bind(&listen,subnets,upstreams,domain)?;
+        dns_proxy.register(poll.registry(),DNS)?;
+        forwarder = Some(dns_proxy);
+        dns.servers = servers;
+        dns.search.push(domain.clone());
+        if !dns.domains.is_empty() {
+            dns.domains.push(domain.clone());
+        }
+    }
+    let push = Push{ dns, split:config.split.clone() };
+    let mut server = Server::new(subnet,subnet6,push,secrets,&offer,clients,Policy{
+        accept_routes:config.accept_routes,
+        isolate_clients:config.isolate_clients
+    });
+    let mut errors = ErrorLog::new();
     LISTENING.store(true,Ordering::Relaxed);
     info!("Ready for transmission.");
     loop{
         if INTERRUPTED.load(Ordering::Relaxed) {
             break;
         }
-        available_ids.append(&mut client_info.prune());
-        poll.poll(&mut events,None).unwrap();
+        server.prune();
+        if let Some(forwarder) = forwarder.as_mut() {
+            forwarder.prune();
+        }
+        if let Err(e) = poll.poll(&mut events,Some(RELOAD_INTERVAL)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(Error::Io(e));
+        }
         for event in events.iter(){
             match event.token(){
                 SOCK =>{
-                    let (len , addr) = sock_fd.recv_from(&mut buf).unwrap();
-                    let (aad,nonce) = generate_add_nonce(secret);
-                    let decrypted_buf = key.open_in_place(nonce,aad,&mut buf[0..len]).unwrap();
-                    let d_len = decrypted_buf.len();
-                    let msg:Message = deserialize(&decrypted_buf[0..d_len]).unwrap();
-                    match msg {
-                        Message::Request => {
-                            let client_id:Id = available_ids.pop().unwrap();
-                            let client_token:Token = rng.gen::<Token>();
-                            client_info.insert(client_id,(client_token,addr));
-                            info!(
-                                "Got request from {}. Assigning IP address: 10.10.10.{}.",
-                                addr, client_id
-                            );
-                            let reply = Message::Response {
-                                id:client_id,
-                                token:client_token,
-                                dns:dns.to_string()
-                            };
-                            let mut encoded_reply = serialize(&reply).unwrap();
-                            let mut encrypted_reply = encoded_reply.clone();
-                            encrypted_reply.resize(encoded_reply.len() + key.algorithm().tag_len(),0);
-                            let (aad , nonce) = generate_add_nonce(secret);
-                            key.seal_in_place_append_tag(nonce,aad,&mut encoded_reply ).unwrap();
-                            let mut sent_len = 0;
-                            while sent_len < encoded_reply.len() {
-                                sent_len += sock_fd
-                                    .send_to(
-                                        &encrypted_reply[sent_len..encrypted_reply.len()],
-                                        addr
-                                    ).unwrap();
-                            }
+                    loop {
+                        let (len , addr) = match sock_fd.recv_from(&mut buf) {
+                            Ok(received) => received,
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(Error::Socket(e))
+                        };
+                        if let Err(e) = server.receive(&sock_fd,&mut tun,addr,&mut buf[0..len]) {
+                            errors.record(Source::Peer(addr),&e);
                         }
-                        Message::Response {
-                            id:_,
-                            token:_,
-                            dns:_
-                        } => warn!("invalid message {:?} from {}",msg,addr),
-                        Message::Data {id,token,data} => match client_info.get(&id) {
-                            None => warn!("Unknown IP packet from TUN for client {}" , id),
-                            Some(&(t,_)) => {
-                                if t != token {
-                                    warn!(
-                                        "Unknown data with mismatched token {} from id {}. \
-                                               Expected: {}",
-                                        token, id, t
-                                    );
-                                } else {
-                                    let decompressed_data = decoder.decompress_vec(&data).unwrap();
-                                    let data_len = decompressed_data.len();
-                                    let mut sent_len = 0;
-                                    while sent_len < data_len {
-                                        sent_len += tun
-                                            .write(&decompressed_data[sent_len..data_len])
-                                            .unwrap()
-                                    }
-                                }
-                            }
-                        },
                     }
                 }
                 TUN => {
-                    let len:usize = tun.read(&mut buf).unwrap();
-                    let data = &buf[0..len];
-                    let client_id:u8 = data[19];
-                    match client_info.get(&client_id) {
-                        None => warn!("Unknown IP packet from TUN for client {}.", client_id),
-                        Some(&(token,addr)) => {
-                            let msg = Message::Data {
-                                id:client_id,
-                                token,
-                                data:encoder.compress_vec(data).unwrap()
-                            };
-                            let encoded_msg = serialize(&msg).unwrap();
-                            let mut encrypted_msg = encoded_msg.clone();
-                            encrypted_msg.resize(encoded_msg.len() + key.algorithm().tag_len(),0);
-                            let (aad,nonce) = generate_add_nonce(secret);
-                            key.seal_in_place_append_tag(nonce,aad,&mut encrypted_msg).unwrap();
-                            let mut sent_len = 0;
-                            while sent_len < encrypted_msg.len() {
-                                sent_len += sock_fd.send_to(&encrypted_msg[sent_len..encrypted_msg.len()],addr).unwrap();
-
-                            }
+                    loop {
+                        let len:usize = match tun.read(&mut buf) {
+                            Ok(len) => len,
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(Error::Tun(e))
+                        };
+                        if let Err(e) = server.send(&sock_fd,&buf[0..len]) {
+                            errors.record(Source::Tun,&e);
                         }
                     }
                 }
-                _ => unreachable!()
+                token => match forwarder.as_mut() {
+                    Some(forwarder) if forwarder.owns(token) => {
+                        if let Err(e) = forwarder.ready(token,|name| server.host_addresses(name),&mut errors) {
+                            errors.record(Source::Dns,&e);
+                        }
+                    }
+                    _ => unreachable!()
+                }
             }
         }
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+    use std::{env, fs, process};
     use std::net::Ipv4Addr;
     #[cfg(target_os = "linux")]
-    use std::thread;
+    use std::{thread, time};
 
+    use crate::crypto::KdfParams;
     use crate::network::*;
+    use crate::wire::VERSION;
 
     #[test]
     fn resolve_test(){
         assert_eq!(
             resolve("127.0.0.1").unwrap(),
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+            vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]
+        );
+        assert_eq!(resolve("::1").unwrap(),vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+    }
+
+    #[test]
+    fn interleave_test(){
+        let ips = |ips:&[&str]| ips.iter().map(|ip| ip.parse::<IpAddr>().unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            interleave(ips(&["2001:db8::1","2001:db8::2","2001:db8::1","192.0.2.1"])),
+            ips(&["2001:db8::1","192.0.2.1","2001:db8::2"])
+        );
+        assert_eq!(
+            interleave(ips(&["192.0.2.1","192.0.2.2","2001:db8::1","2001:db8::2","2001:db8::3"])),
+            ips(&["192.0.2.1","2001:db8::1","192.0.2.2","2001:db8::2","2001:db8::3"])
+        );
+    }
+
+    #[test]
+    fn initiate_any_test(){
+        let silent = UdpSocket::bind("[::1]:0").unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let ips = [IpAddr::V6(Ipv6Addr::LOCALHOST),IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let start = std::time::Instant::now();
+        let error = initiate_any(&ips,port,"password",true,None,&[]).err().unwrap();
+        assert_eq!(error.kind(),"socket");
+        assert!(start.elapsed() >= HANDSHAKE_TIMEOUT * 2);
+        assert!(silent.recv(&mut [0;1600]).unwrap() > HEADER_LEN);
+    }
+
+    fn session_pair() -> (Session,Session) {
+        let rng = SystemRandom::new();
+        let psk = crypto::derive_legacy_psk("password");
+        let client = Handshake::new(&rng).unwrap();
+        let server = Handshake::new(&rng).unwrap();
+        let client_public_key = client.public_key().to_vec();
+        let server_public_key = server.public_key().to_vec();
+        let addr = "127.0.0.1:8964".parse::<SocketAddr>().unwrap();
+        let client_keys = client.finish(&psk,Role::Client,&server_public_key).unwrap();
+        let server_keys = server.finish(&psk,Role::Server,&client_public_key).unwrap();
+        (
+            Session::new(0,addr,VERSION,Role::Client,&psk,client_keys,RekeyLimits::default()),
+            Session::new(0,addr,VERSION,Role::Server,&psk,server_keys,RekeyLimits::default())
+        )
+    }
+
+    #[test]
+    fn decrypt_handshake_any_test() {
+        let rng = SystemRandom::new();
+        let secrets = [
+            Secret::new([1;KEY_LEN],false),
+            Secret::new(crypto::derive_legacy_psk("password"),true)
+        ];
+        let msg = Message::Request{ versions:Versions::SUPPORTED, public_key:[1;KEY_LEN], timestamp:1, identity:None, routes:Vec::new() };
+        let sealed = encrypt_handshake(&secrets[1].key,&rng,VERSION,&msg).unwrap();
+        let (opened,secret) = decrypt_handshake_any(&secrets,&sealed).unwrap();
+        assert_eq!(opened,msg);
+        assert!(secret.legacy);
+        let other = Secret::new([2;KEY_LEN],false);
+        let sealed = encrypt_handshake(&other.key,&rng,VERSION,&msg).unwrap();
+        assert!(decrypt_handshake_any(&secrets,&sealed).is_err());
+    }
+
+    #[test]
+    fn unique_nonce_test() {
+        let key = crypto::handshake_key(&crypto::derive_legacy_psk("password"));
+        let rng = SystemRandom::new();
+        let mut nonces = HashSet::new();
+        for _ in 0..10000 {
+            let msg = Message::Request{ versions:Versions::SUPPORTED, public_key:[0;KEY_LEN], timestamp:1, identity:None, routes:Vec::new() };
+            let sealed = encrypt_handshake(&key,&rng,VERSION,&msg).unwrap();
+            assert!(nonces.insert(sealed[HEADER_LEN..HEADER_LEN + aead::NONCE_LEN].to_vec()));
+        }
+        let (mut client,_) = session_pair();
+        let mut nonces = HashSet::new();
+        for _ in 0..10000 {
+            let msg = Message::Data{ data:vec![] };
+            let sealed = encrypt_message(&mut client,2,&msg).unwrap();
+            assert!(nonces.insert(Header::decode(&sealed).unwrap().counter));
+        }
+    }
+
+    #[test]
+    fn rekey_message_test() {
+        let rng = SystemRandom::new();
+        let (mut client,mut server) = session_pair();
+        let public_key = client.start_rekey(&rng).unwrap();
+        let mut rekey = encrypt_message(&mut client,2,&Message::Rekey{ public_key }).unwrap();
+        let msg = decrypt_message(&mut server,&mut rekey).unwrap().unwrap();
+        let mut ack = handle_rekey(&mut server,&rng,2,msg).unwrap().unwrap();
+        let msg = decrypt_message(&mut client,&mut ack).unwrap().unwrap();
+        let mut keepalive = handle_rekey(&mut client,&rng,2,msg).unwrap().unwrap();
+        assert_eq!(Header::decode(&keepalive).unwrap().epoch,1);
+        assert_eq!(decrypt_message(&mut server,&mut keepalive).unwrap(),Some(Message::Keepalive));
+        let mut reply = encrypt_message(&mut server,2,&Message::Keepalive).unwrap();
+        assert_eq!(Header::decode(&reply).unwrap().epoch,1);
+        assert_eq!(decrypt_message(&mut client,&mut reply).unwrap(),Some(Message::Keepalive));
+        let data = Message::Data{ data:vec![1,2,3] };
+        let mut sealed = encrypt_message(&mut client,2,&data).unwrap();
+        assert_eq!(decrypt_message(&mut server,&mut sealed.clone()).unwrap(),Some(data));
+        assert_eq!(decrypt_message(&mut server,&mut sealed).unwrap(),None);
+    }
+
+    #[test]
+    fn receive_garbage_test() {
+        let socket = mio::net::UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = "127.0.0.1:8964".parse::<SocketAddr>().unwrap();
+        let mut server = test_server(24,64);
+        let mut tun = Vec::new();
+        let mut receive = |server:&mut Server,datagram:&[u8]| {
+            server.receive(&socket,&mut tun,addr,&mut datagram.to_vec()).unwrap_err().kind()
+        };
+        assert_eq!(receive(&mut server,&[0;7]),"decode");
+        assert_eq!(receive(&mut server,&encode_clear(VERSION,MessageType::Request,&[0;64])),"decrypt");
+        assert_eq!(receive(&mut server,&encode_clear(VERSION,MessageType::Data,&[0;64])),"decrypt");
+        assert_eq!(receive(&mut server,&encode_clear(VERSION,MessageType::Response,&[])),"decode");
+        let (mut client,session) = session_pair();
+        server.sessions.insert(2,session);
+        server.routes.insert(Prefix::host(IpAddr::V4(server.pool.allocate(2).unwrap())),2);
+        server.routes.insert(Prefix::host(IpAddr::V6(server.pool6.as_mut().unwrap().allocate(2).unwrap())),2);
+        server.routes.insert("192.168.1.0/24".parse().unwrap(),2);
+        let garbage = encrypt_message(&mut client,2,&Message::Data{ data:vec![0xff;8] }).unwrap();
+        assert_eq!(receive(&mut server,&garbage),"compression");
+        let mut forged = encrypt_message(&mut client,2,&Message::Data{ data:vec![] }).unwrap();
+        forged[HEADER_LEN] ^= 1;
+        assert_eq!(receive(&mut server,&forged),"decrypt");
+        let packet = [0x45;20];
+        let data = Message::Data{ data:snap::raw::Encoder::new().compress_vec(&packet).unwrap() };
+        let mut sealed = encrypt_message(&mut client,2,&data).unwrap();
+        server.receive(&socket,&mut tun,addr,&mut sealed).unwrap();
+        assert_eq!(tun,packet);
+        assert_eq!(server.send(&socket,&[0x45;19]).unwrap_err().kind(),"decode");
+        assert_eq!(server.send(&socket,&[0x65;20]).unwrap_err().kind(),"decode");
+        assert_eq!(server.send(&socket,&[0x45;20]).unwrap_err().kind(),"route");
+        assert_eq!(server.send(&socket,&[0x65;40]).unwrap_err().kind(),"route");
+        let mut packet = [0x45;20];
+        packet[16..20].copy_from_slice(&[10,10,10,2]);
+        server.send(&socket,&packet).unwrap();
+        packet[16..20].copy_from_slice(&[192,168,1,77]);
+        server.send(&socket,&packet).unwrap();
+        let mut packet = [0x65;40];
+        packet[24..40].copy_from_slice(&"fd00:e:e::2".parse::<Ipv6Addr>().unwrap().octets());
+        server.send(&socket,&packet).unwrap();
+        server.release(2);
+        assert_eq!(server.send(&socket,&packet).unwrap_err().kind(),"route");
+    }
+
+    #[test]
+    fn forward_test() {
+        let socket = mio::net::UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut server = test_server(24,64);
+        let mut tun = Vec::new();
+        let (mut sender,session) = session_pair();
+        server.sessions.insert(2,session);
+        server.routes.insert(Prefix::host(IpAddr::V4(Ipv4Addr::new(10,10,10,2))),2);
+        let (mut receiver,mut session) = session_pair();
+        session.addr = peer.local_addr().unwrap();
+        server.sessions.insert(3,session);
+        server.routes.insert(Prefix::host(IpAddr::V4(Ipv4Addr::new(10,10,10,3))),3);
+        let mut packet = [0x45;20];
+        packet[16..20].copy_from_slice(&[10,10,10,3]);
+        let data = Message::Data{ data:snap::raw::Encoder::new().compress_vec(&packet).unwrap() };
+        let mut sealed = encrypt_message(&mut sender,2,&data).unwrap();
+        server.receive(&socket,&mut tun,"127.0.0.1:8964".parse().unwrap(),&mut sealed).unwrap();
+        assert!(tun.is_empty());
+        let mut buf = [0;1600];
+        let len = peer.recv(&mut buf).unwrap();
+        let forwarded = decrypt_message(&mut receiver,&mut buf[..len]).unwrap().unwrap();
+        assert_eq!(forwarded,Message::Data{ data:snap::raw::Encoder::new().compress_vec(&packet).unwrap() });
+        packet[16..20].copy_from_slice(&[10,10,10,1]);
+        let data = Message::Data{ data:snap::raw::Encoder::new().compress_vec(&packet).unwrap() };
+        let mut sealed = encrypt_message(&mut sender,2,&data).unwrap();
+        server.receive(&socket,&mut tun,"127.0.0.1:8964".parse().unwrap(),&mut sealed).unwrap();
+        assert_eq!(tun,packet);
+        server.policy.isolate_clients = true;
+        packet[16..20].copy_from_slice(&[10,10,10,3]);
+        let data = Message::Data{ data:snap::raw::Encoder::new().compress_vec(&packet).unwrap() };
+        let mut sealed = encrypt_message(&mut sender,2,&data).unwrap();
+        let error = server.receive(&socket,&mut tun,"127.0.0.1:8964".parse().unwrap(),&mut sealed).unwrap_err();
+        assert_eq!(error.kind(),"policy");
+        assert_eq!(tun.len(),20);
+        assert!(peer.recv(&mut buf).is_err());
+    }
+
+    #[test]
+    fn keepalive_test() {
+        let bind = || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let sender = mio::net::UdpSocket::from_std(socket.try_clone().unwrap());
+            (socket,sender)
+        };
+        let (client_socket,client_sender) = bind();
+        let (server_socket,server_sender) = bind();
+        let mut server = test_server(24,64);
+        let (client,mut session) = session_pair();
+        session.addr = client_socket.local_addr().unwrap();
+        server.sessions.insert(2,session);
+        let mut connection = Connection::new(2,client,server_socket.local_addr().unwrap());
+        let mut tun = Vec::new();
+        let mut buf = [0;1600];
+        let start = Instant::now();
+        connection.keepalive(&client_sender,start).unwrap();
+        connection.keepalive(&client_sender,start + KEEPALIVE_INTERVAL).unwrap();
+        let (len,addr) = server_socket.recv_from(&mut buf).unwrap();
+        server.receive(&server_sender,&mut tun,addr,&mut buf[..len]).unwrap();
+        let len = client_socket.recv(&mut buf).unwrap();
+        connection.receive(&client_sender,&mut tun,&mut buf[..len]).unwrap();
+        assert!(connection.last_received > start);
+        assert!(tun.is_empty());
+        drop(server);
+        drop(server_socket);
+        let now = Instant::now();
+        connection.keepalive(&client_sender,now + KEEPALIVE_INTERVAL).unwrap();
+        assert!(client_socket.recv(&mut buf).is_err());
+        let error = connection.keepalive(&client_sender,now + PEER_TIMEOUT).unwrap_err();
+        assert_eq!(error.kind(),"socket");
+    }
+
+    fn test_server(prefix:u8,prefix6:u8) -> Server {
+        let offer = KdfOffer{ salt:[0;crypto::SALT_LEN], params:KdfParams::MIN };
+        Server::new(
+            Subnet::new(Ipv4Addr::new(10,10,10,0),prefix).unwrap(),
+            Some(Subnet::new("fd00:e:e::".parse().unwrap(),prefix6).unwrap()),
+            Push{ dns:dns::Config{ servers:vec!["8.8.8.8".parse().unwrap()], ..dns::Config::default() }, split:Split::default() },
+            vec![Secret::new(crypto::derive_legacy_psk("password"),true)],
+            &offer,
+            None,
+            Policy{ accept_routes:true, isolate_clients:false }
+        )
+    }
+
+    #[test]
+    fn check_routes_test() {
+        let mut server = test_server(24,64);
+        let routes = |routes:&[&str]| routes.iter().map(|route| route.parse::<Prefix>().unwrap()).collect::<Vec<_>>();
+        assert_eq!(server.check_routes(&routes(&["192.168.1.0/24","fd00:1::/48"])),Ok(()));
+        server.routes.insert("192.168.1.0/24".parse().unwrap(),7);
+        assert_eq!(
+            server.check_routes(&routes(&["192.168.1.0/24"])),
+            Err(String::from("192.168.1.0/24 is already routed to session 7"))
         );
+        assert_eq!(server.check_routes(&routes(&["192.168.1.0/25"])),Ok(()));
+        for invalid in [
+            &["0.0.0.0/0"][..],
+            &["10.10.0.0/16"],
+            &["10.10.10.128/25"],
+            &["fd00:e::/32"],
+            &["192.168.2.0/24","192.168.2.0/24"]
+        ] {
+            assert!(server.check_routes(&routes(invalid)).is_err());
+        }
+        server.pool6 = None;
+        assert!(server.check_routes(&routes(&["fd00:1::/48"])).is_err());
+        server.policy.accept_routes = false;
+        assert!(server.check_routes(&routes(&["192.168.2.0/24"])).is_err());
+        assert_eq!(server.check_routes(&[]),Ok(()));
+    }
+
+    #[test]
+    fn pool_exhausted_test() {
+        for (prefix,prefix6,exhausted) in [(30,64,"10.10.10.0/30"),(24,126,"fd00:e:e::/126")] {
+            pool_exhausted(prefix,prefix6,exhausted);
+        }
+    }
+
+    fn pool_exhausted(prefix:u8,prefix6:u8,exhausted:&str) {
+        let socket = mio::net::UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = client.local_addr().unwrap();
+        let mut server = test_server(prefix,prefix6);
+        let rng = SystemRandom::new();
+        let key = &server.secrets[0].key;
+        let request = |public_key| {
+            let request = Message::Request{
+                versions:Versions::SUPPORTED,
+                public_key,
+                timestamp:unix_time(),
+                identity:None,
+                routes:Vec::new()
+            };
+            encrypt_handshake(key,&rng,VERSION,&request).unwrap()
+        };
+        let (request,other) = (request([9;KEY_LEN]),request([8;KEY_LEN]));
+        let key = crypto::handshake_key(&crypto::derive_legacy_psk("password"));
+        let mut buf = [0u8;1600];
+        server.receive(&socket,&mut Vec::new(),addr,&mut request.clone()).unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        match decrypt_handshake(&key,&mut buf[..len]).unwrap() {
+            Message::Response{id,address,ipv6,..} => {
+                assert_eq!(address,Ipv4Addr::new(10,10,10,2));
+                assert_eq!(ipv6.unwrap().address,"fd00:e:e::2".parse::<Ipv6Addr>().unwrap());
+                assert_eq!(ipv6.unwrap().gateway,"fd00:e:e::1".parse::<Ipv6Addr>().unwrap());
+                assert!(server.sessions.contains_key(&id));
+            }
+            msg => panic!("unexpected {:?}",msg)
+        }
+        let error = server.receive(&socket,&mut Vec::new(),addr,&mut other.clone()).unwrap_err();
+        assert_eq!(error.kind(),"handshake");
+        let len = client.recv(&mut buf).unwrap();
+        let reason = format!("address pool {} exhausted",exhausted);
+        assert_eq!(decrypt_handshake(&key,&mut buf[..len]).unwrap(),Message::Reject{ reason });
+        assert_eq!(server.sessions.len(),1);
+        assert_eq!(server.pool.owner(Ipv4Addr::new(10,10,10,3)),None);
+    }
+
+    #[test]
+    fn replayed_request_test() {
+        let socket = mio::net::UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let addr = client.local_addr().unwrap();
+        let mut server = test_server(24,64);
+        let request = |public_key,timestamp| {
+            let request = Message::Request{ versions:Versions::SUPPORTED, public_key, timestamp, identity:None, routes:Vec::new() };
+            encrypt_handshake(&server.secrets[0].key,&SystemRandom::new(),VERSION,&request).unwrap()
+        };
+        let (stale,request) = (request([7;KEY_LEN],unix_time() - 300),request([9;KEY_LEN],unix_time()));
+        let mut buf = [0u8;1600];
+        server.receive(&socket,&mut Vec::new(),addr,&mut request.clone()).unwrap();
+        assert!(client.recv(&mut buf).is_ok());
+        for _ in 0..3 {
+            let error = server.receive(&socket,&mut Vec::new(),addr,&mut request.clone()).unwrap_err();
+            assert_eq!(error.kind(),"handshake");
+        }
+        assert!(client.recv(&mut buf).is_err());
+        assert_eq!(server.sessions.len(),1);
+        assert_eq!(server.pool.owner(Ipv4Addr::new(10,10,10,3)),None);
+        let id = *server.sessions.keys().next().unwrap();
+        server.sessions.remove(&id);
+        server.release(id);
+        server.prune();
+        assert_eq!(server.receive(&socket,&mut Vec::new(),addr,&mut request.clone()).unwrap_err().kind(),"handshake");
+        assert!(server.sessions.is_empty());
+        assert_eq!(server.receive(&socket,&mut Vec::new(),addr,&mut stale.clone()).unwrap_err().kind(),"handshake");
+        assert!(client.recv(&mut buf).is_err());
+        assert!(server.sessions.is_empty());
+        server.handshakes.values_mut().for_each(|seen| *seen -= 2 * HANDSHAKE_WINDOW);
+        server.prune();
+        assert!(server.handshakes.is_empty());
+    }
+
+    #[test]
+    fn authorize_test() {
+        let laptop = PublicKey::from(&StaticSecret::from([1;KEY_LEN])).to_bytes();
+        let phone = PublicKey::from(&StaticSecret::from([2;KEY_LEN])).to_bytes();
+        assert_eq!(authorize(None,None).unwrap(),None);
+        assert_eq!(authorize(None,Some(&laptop)).unwrap().unwrap().name,keys::encode(&laptop));
+        assert!(authorize(None,Some(&[1,2,3])).is_err());
+        let path = env::temp_dir().join(format!("e-net-{}-authorize",process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path,format!("laptop {}\n",keys::encode(&laptop))).unwrap();
+        let clients = ClientTable::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(authorize(Some(&clients),Some(&laptop)).unwrap().unwrap().name,"laptop");
+        assert!(authorize(Some(&clients),Some(&phone)).is_err());
+        assert!(authorize(Some(&clients),None).is_err());
+    }
+
+    #[test]
+    fn revoke_sessions_test() {
+        let laptop = PublicKey::from(&StaticSecret::from([1;KEY_LEN])).to_bytes();
+        let phone = PublicKey::from(&StaticSecret::from([2;KEY_LEN])).to_bytes();
+        let path = env::temp_dir().join(format!("e-net-{}-revoke",process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path,format!("laptop {}\n",keys::encode(&laptop))).unwrap();
+        let clients = ClientTable::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let mut sessions = TransientHashMap::new(60);
+        for (id,identity) in [(2,Some(("laptop",laptop))),(3,Some(("phone",phone))),(4,None)] {
+            let (mut session,_) = session_pair();
+            session.identity = identity.map(|(name,public_key)| Identity{ name:name.to_string(), public_key });
+            sessions.insert(id,session);
+        }
+        let mut revoked = revoke_sessions(&clients,&mut sessions);
+        revoked.sort();
+        assert_eq!(revoked,vec![3,4]);
+        assert!(sessions.contains_key(&2));
+        assert!(!sessions.contains_key(&3));
     }
 
     #[test]
     #[cfg(target_os = "linux")]
     fn integration_test() {
         assert!(utils::is_root());
-        thread::spawn(move || serve(8964, "password", "8.8.8.8".parse::<IpAddr>().unwrap()));
+        let rng = SystemRandom::new();
+        let identity = keys::generate(&rng).unwrap();
+        let path = env::temp_dir().join(format!("e-net-{}-integration",process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path,format!("laptop {}\n",keys::public_key(&identity).unwrap())).unwrap();
+        let config = cli::Server{
+            bind_addr:IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port:8964,
+            key:String::from("password"),
+            subnet:"10.20.0.0/16".parse::<Subnet>().unwrap(),
+            subnet6:Some("fd00:e:20::/64".parse().unwrap()),
+            dns:dns::Config{
+                servers:vec!["8.8.8.8".parse().unwrap(),"2001:4860:4860::8888".parse().unwrap()],
+                search:vec![String::from("corp.example")],
+                domains:vec![String::from("corp.example")]
+            },
+            dns_proxy:Some(String::from("vpn")),
+            split:Split{ include:Vec::new(), exclude:vec!["203.0.113.0/24".parse().unwrap()] },
+            kdf:KdfParams::MIN,
+            legacy_kdf:true,
+            clients:Some(path.clone()),
+            accept_routes:true,
+            isolate_clients:false,
+            masquerade:false,
+            egress:None,
+            state:format!("{}-server.state",path)
+        };
+        thread::spawn(move || serve(&config));
         thread::sleep(time::Duration::from_secs(1));
         assert!(LISTENING.load(Ordering::Relaxed));
         let remote_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127,0,0,1)),8964);
         let local_addr:SocketAddr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
-        let local_socket = UdpSocket::bind(&local_addr).unwrap();
-        let (id,_,_) = initiate(&local_socket,&remote_addr,"password").unwrap();
-        assert_eq!(id,253);
-        let _client = thread::spawn(move || connect("127.0.0.1",8964,false,"password"));
+        let local_socket = UdpSocket::bind(local_addr).unwrap();
+        let private_key = keys::private_key(&identity).unwrap();
+        let routes = ["192.168.77.0/24".parse().unwrap(),"fd00:77::/48".parse().unwrap()];
+        let (assignment,session) = initiate(&local_socket,&remote_addr,"password",false,Some(&private_key),&routes).unwrap();
+        assert_eq!(assignment.address,Ipv4Addr::new(10,20,0,2));
+        assert_eq!(assignment.prefix,16);
+        assert_eq!(assignment.gateway,Ipv4Addr::new(10,20,0,1));
+        assert_eq!(assignment.dns.to_string(),"10.20.0.1, fd00:e:20::1 searching corp.example, vpn only for corp.example, vpn");
+        assert_eq!(assignment.split.exclude,vec!["203.0.113.0/24".parse().unwrap()]);
+        assert_eq!(session.version,VERSION);
+        let route = process::Command::new("ip").args(["route","list","192.168.77.0/24"]).output().unwrap().stdout;
+        assert!(String::from_utf8_lossy(&route).contains("via 10.20.0.2"));
+        let route = process::Command::new("ip").args(["-6","route","list","fd00:77::/48"]).output().unwrap().stdout;
+        assert!(String::from_utf8_lossy(&route).contains("via fd00:e:20::2"));
+        let taken = initiate(&local_socket,&remote_addr,"password",true,Some(&private_key),&routes[..1]);
+        assert_eq!(taken.err().unwrap().kind(),"handshake");
+        let (other,_) = initiate(&local_socket,&remote_addr,"password",true,Some(&private_key),&[]).unwrap();
+        assert_eq!(other.address,Ipv4Addr::new(10,20,0,3));
+        assert_ne!(other.id,assignment.id);
+        let local_socket6 = UdpSocket::bind("[::]:0").unwrap();
+        let remote_addr6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST),8964);
+        let (over_ipv6,_) = initiate(&local_socket6,&remote_addr6,"password",true,Some(&private_key),&[]).unwrap();
+        assert_eq!(over_ipv6.address,Ipv4Addr::new(10,20,0,4));
+        assert_eq!(assignment.ipv6.unwrap().address,"fd00:e:20::2".parse::<Ipv6Addr>().unwrap());
+        let resolver = UdpSocket::bind("0.0.0.0:0").unwrap();
+        resolver.set_read_timeout(Some(time::Duration::from_secs(1))).unwrap();
+        let mut query = vec![0,7,1,0,0,1,0,0,0,0,0,0,6];
+        query.extend_from_slice(b"laptop\x03vpn\x00\x00\x01\x00\x01");
+        resolver.send_to(&query,"10.20.0.1:53").unwrap();
+        let mut answer = [0u8;512];
+        let len = resolver.recv(&mut answer).unwrap();
+        assert_eq!(u16::from_be_bytes([answer[6],answer[7]]),3);
+        assert!(answer[..len].windows(4).any(|address| address == [10,20,0,2]));
+        let config = cli::Client{
+            remote_addr:String::from("127.0.0.1"),
+            port:8964,
+            key:String::from("password"),
+            default_route:false,
+            default_route6:false,
+            legacy_kdf:false,
+            identity:Some(identity),
+            advertise:Vec::new(),
+            split:Split{ include:Vec::new(), exclude:vec!["198.51.100.0/24".parse().unwrap()] },
+            kill_switch:false,
+            state:format!("{}-client.state",path)
+        };
+        let client = thread::spawn(move || connect(&config));
         thread::sleep(time::Duration::from_secs(1));
         assert!(CONNECTED.load(Ordering::Relaxed));
+        assert!(fs::metadata(format!("{}-client.state",path)).is_ok());
+        let excluded = |prefix:&str| process::Command::new("ip").args(["route","list",prefix]).output().unwrap().stdout;
+        assert!(!excluded("203.0.113.0/24").is_empty());
+        assert!(!excluded("198.51.100.0/24").is_empty());
         INTERRUPTED.store(true,Ordering::Relaxed);
+        client.join().unwrap().unwrap();
+        assert!(fs::metadata(format!("{}-client.state",path)).is_err());
+        assert!(excluded("203.0.113.0/24").is_empty());
+        assert!(excluded("198.51.100.0/24").is_empty());
+        let route = process::Command::new("ip").args(["route","list","127.0.0.1"]).output().unwrap().stdout;
+        assert!(route.is_empty());
+        fs::remove_file(&path).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,8 @@
 use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::Wrapping;
 
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct IpV4Header{
     pub version_ihl:u8,
     pub type_of_service:u8,
@@ -10,12 +11,55 @@ pub struct IpV4Header{
     pub flags_fragment_offset:u16,
     pub time_to_live:u8,
     pub protocol:u8,
-    pub header_checksum:u8,
+    pub header_checksum:u16,
     pub source_address:u32,
     pub destination_address:u32
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
+pub struct Ipv6Header{
+    pub version_class_flow:u32,
+    pub payload_length:u16,
+    pub next_header:u8,
+    pub hop_limit:u8,
+    pub source_address:[u8;16],
+    pub destination_address:[u8;16]
+}
+
+/// Copies the header of type `T` at the start of `packet`, fields in network byte order.
+fn read_header<T>(packet:&[u8]) -> Result<T,String> {
+    if packet.len() < mem::size_of::<T>() {
+        return Err(format!("truncated IP header: {} bytes",packet.len()));
+    }
+    Ok(unsafe { (packet.as_ptr() as *const T).read_unaligned() })
+}
+
+impl IpV4Header {
+    pub fn parse(packet:&[u8]) -> Result<IpV4Header,String> {
+        read_header(packet)
+    }
+}
+
+impl Ipv6Header {
+    pub fn parse(packet:&[u8]) -> Result<Ipv6Header,String> {
+        read_header(packet)
+    }
+}
+
+/// Destination address of an IPv4 or IPv6 packet.
+pub fn destination(packet:&[u8]) -> Result<IpAddr,String> {
+    match packet.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            let header = IpV4Header::parse(packet)?;
+            Ok(IpAddr::V4(Ipv4Addr::from(u32::from_be(header.destination_address))))
+        }
+        Some(6) => Ok(IpAddr::V6(Ipv6Addr::from(Ipv6Header::parse(packet)?.destination_address))),
+        Some(version) => Err(format!("unknown IP version {}",version)),
+        None => Err(String::from("empty packet"))
+    }
+}
+
+#[repr(C, packed)]
 pub struct UdpHeader{
     pub source_port:u16,
     pub destination_port:u16,
@@ -23,7 +67,7 @@ pub struct UdpHeader{
     pub checksum:u16
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct TcpHeader{
     pub source_port:u16,
     pub destination_port:u16,
@@ -36,7 +80,7 @@ pub struct TcpHeader{
     pub urg_ptr:u16
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct IcmpHeader{
     pub icmp_type:u8,
     pub icmp_code:u8,
@@ -51,7 +95,7 @@ fn raw_checksum<T>(buf:*const T , len :usize) -> u16 {
     let mut ptr = buf as *const u16;
     while remain_len >= 2 {
         unsafe {
-            sum += Wrapping(*ptr);
+            sum += Wrapping(ptr.read_unaligned());
             ptr = ptr.offset(1);
         }
         remain_len -= 2;
@@ -73,7 +117,7 @@ pub fn ipv4_checksum(buf:&IpV4Header) -> u16 {
     }
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 struct IpV4PseudoHeader {
     pub source_address:u32,
     pub destination_address:u32,
@@ -97,7 +141,7 @@ pub fn udp_tcp_checksum<T>(ip:&IpV4Header,l4:&T) -> u16{
     let l4_len = (u16::from_be(ip.total_length) as usize) - mem::size_of::<IpV4Header>();
     let mut check_sum = raw_checksum(l4 as *const T , l4_len) as u32;
     check_sum += ipv4_p_hdr_checksum(ip) as u32;
-    check_sum = ((check_sum & 0xffff0000) >> 16) * (check_sum & 0xfffff);
+    check_sum = ((check_sum & 0xffff0000) >> 16) + (check_sum & 0xffff);
     check_sum = (!check_sum) & 0xffff;
     if check_sum == 0 {
         check_sum = 0xffff;
@@ -133,6 +177,22 @@ mod tests {
         assert_eq!(ipv4_checksum(&ip),!0);
     }
 
+    #[test]
+    fn destination_test() {
+        let mut ipv4 = [0u8;20];
+        ipv4[0] = 0x45;
+        ipv4[16..20].copy_from_slice(&[10,10,10,2]);
+        assert_eq!(destination(&ipv4).unwrap(),IpAddr::V4(Ipv4Addr::new(10,10,10,2)));
+        assert!(destination(&ipv4[..19]).is_err());
+        let mut ipv6 = [0u8;40];
+        ipv6[0] = 0x60;
+        ipv6[24..40].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        assert_eq!(destination(&ipv6).unwrap(),"fd00::2".parse::<IpAddr>().unwrap());
+        assert!(destination(&ipv6[..39]).is_err());
+        assert!(destination(&[0x50;40]).is_err());
+        assert!(destination(&[]).is_err());
+    }
+
     #[test]
     fn udp_tcp_check_sum_test() {
         let ip = IpV4Header{
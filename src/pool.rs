@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::session::Id;
+
+/// Offset of the first client address: the network address comes first, then the server.
+const FIRST_CLIENT:u128 = 2;
+
+/// Address family the tunnel hands out addresses in.
+pub trait Address: Copy + Eq + Hash + fmt::Display + FromStr<Err:fmt::Display> {
+    const BITS:u8;
+
+    fn to_u128(self) -> u128;
+
+    fn from_u128(bits:u128) -> Self;
+}
+
+impl Address for Ipv4Addr {
+    const BITS:u8 = 32;
+
+    fn to_u128(self) -> u128 {
+        u32::from(self).into()
+    }
+
+    fn from_u128(bits:u128) -> Ipv4Addr {
+        Ipv4Addr::from(bits as u32)
+    }
+}
+
+impl Address for Ipv6Addr {
+    const BITS:u8 = 128;
+
+    fn to_u128(self) -> u128 {
+        u128::from(self)
+    }
+
+    fn from_u128(bits:u128) -> Ipv6Addr {
+        Ipv6Addr::from(bits)
+    }
+}
+
+/// Network the tunnel's addresses are taken from, e.g. `10.10.10.0/24` or `fd00:e::/64`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Subnet<A = Ipv4Addr> {
+    network:A,
+    prefix:u8
+}
+
+impl<A:Address> Subnet<A> {
+    /// Builds the subnet of `address` with `prefix` bits, clearing the host bits. The prefix
+    /// must leave room for the server and at least one client.
+    pub fn new(address:A,prefix:u8) -> Result<Subnet<A>,String> {
+        let max_prefix = A::BITS - 2;
+        if prefix > max_prefix {
+            return Err(format!("prefix /{} leaves no room for clients, at most /{} is allowed",prefix,max_prefix));
+        }
+        let network = A::from_u128(address.to_u128() & mask::<A>(prefix));
+        Ok(Subnet{ network, prefix })
+    }
+
+    pub fn network(&self) -> A {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Address of the server, the first host of the subnet.
+    pub fn gateway(&self) -> A {
+        self.host(1)
+    }
+
+    pub fn contains(&self,address:A) -> bool {
+        address.to_u128() & mask::<A>(self.prefix) == self.network.to_u128()
+    }
+
+    /// Number of addresses, including the network and last addresses, saturating for the
+    /// largest IPv6 subnets.
+    fn size(&self) -> u128 {
+        1u128.checked_shl(u32::from(A::BITS - self.prefix)).unwrap_or(u128::MAX)
+    }
+
+    fn host(&self,offset:u128) -> A {
+        A::from_u128(self.network.to_u128() + offset)
+    }
+}
+
+impl<A:Address> fmt::Display for Subnet<A> {
+    fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}/{}",self.network,self.prefix)
+    }
+}
+
+impl<A:Address> FromStr for Subnet<A> {
+    type Err = String;
+
+    /// Parses CIDR notation such as `10.10.10.0/24`.
+    fn from_str(s:&str) -> Result<Subnet<A>,String> {
+        let (address,prefix) = s.split_once('/').ok_or(format!("{}: expected address/prefix",s))?;
+        let address = address.parse::<A>().map_err(|e|format!("{}: {}",s,e))?;
+        let prefix = prefix.parse::<u8>().map_err(|e|format!("{}: {}",s,e))?;
+        Subnet::new(address,prefix).map_err(|e|format!("{}: {}",s,e))
+    }
+}
+
+/// Bits of the network part of an address with `prefix` bits.
+pub fn mask<A:Address>(prefix:u8) -> u128 {
+    let host_bits = u32::from(A::BITS - prefix);
+    let all = u128::MAX >> (128 - u32::from(A::BITS));
+    all.checked_shr(host_bits).map_or(0,|network| network << host_bits)
+}
+
+/// Client addresses leased to sessions.
+///
+/// Addresses are handed out in order and those given back are reused first, so the pool
+/// never enumerates a large subnet up front.
+pub struct AddressPool<A = Ipv4Addr> {
+    subnet:Subnet<A>,
+    next:u128,
+    released:Vec<A>,
+    leases:HashMap<Id,A>
+}
+
+impl<A:Address> AddressPool<A> {
+    pub fn new(subnet:Subnet<A>) -> AddressPool<A> {
+        AddressPool{
+            subnet,
+            next:FIRST_CLIENT,
+            released:Vec::new(),
+            leases:HashMap::new()
+        }
+    }
+
+    pub fn subnet(&self) -> Subnet<A> {
+        self.subnet
+    }
+
+    /// Leases an address to session `id`, or returns `None` once the pool is exhausted.
+    pub fn allocate(&mut self,id:Id) -> Option<A> {
+        let address = match self.released.pop() {
+            Some(address) => address,
+            None if self.next < self.subnet.size() - 1 => {
+                self.next += 1;
+                self.subnet.host(self.next - 1)
+            }
+            None => return None
+        };
+        self.leases.insert(id,address);
+        Some(address)
+    }
+
+    /// Gives back the address leased to session `id`.
+    pub fn release(&mut self,id:Id) -> Option<A> {
+        let address = self.leases.remove(&id)?;
+        self.released.push(address);
+        Some(address)
+    }
+
+    /// Address leased to session `id`.
+    pub fn leased(&self,id:Id) -> Option<A> {
+        self.leases.get(&id).copied()
+    }
+
+    #[cfg(test)]
+    pub fn owner(&self,address:A) -> Option<Id> {
+        self.leases.iter().find(|(_,leased)| **leased == address).map(|(id,_)| *id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pool::*;
+
+    #[test]
+    fn subnet_test() {
+        let subnet = Subnet::new(Ipv4Addr::new(10,10,7,9),16).unwrap();
+        assert_eq!(subnet.gateway(),Ipv4Addr::new(10,10,0,1));
+        assert_eq!(subnet.prefix(),16);
+        assert_eq!(subnet.to_string(),"10.10.0.0/16");
+        assert!(subnet.contains(Ipv4Addr::new(10,10,255,255)));
+        assert!(!subnet.contains(Ipv4Addr::new(10,11,0,1)));
+        assert!(Subnet::new(Ipv4Addr::new(10,0,0,0),31).is_err());
+        assert_eq!(Subnet::new(Ipv4Addr::new(192,0,2,1),0).unwrap().to_string(),"0.0.0.0/0");
+        assert_eq!("10.10.7.9/16".parse::<Subnet>().unwrap(),subnet);
+        assert!(Subnet::new(Ipv4Addr::new(10,0,0,0),30).is_ok());
+        assert_eq!("172.16.0.0/12".parse::<Subnet>().unwrap().gateway(),Ipv4Addr::new(172,16,0,1));
+        for invalid in ["10.10.0.0","10.10.0/16","10.10.0.0/33","10.10.0.0/31","10.10.0.0/x"] {
+            assert!(invalid.parse::<Subnet>().is_err());
+        }
+    }
+
+    #[test]
+    fn subnet6_test() {
+        let subnet = "fd00:e:e:1::9/64".parse::<Subnet<Ipv6Addr>>().unwrap();
+        assert_eq!(subnet.to_string(),"fd00:e:e:1::/64");
+        assert_eq!(subnet.gateway(),"fd00:e:e:1::1".parse::<Ipv6Addr>().unwrap());
+        assert!(subnet.contains("fd00:e:e:1:ffff:ffff:ffff:ffff".parse().unwrap()));
+        assert!(!subnet.contains("fd00:e:e:2::1".parse().unwrap()));
+        assert!("fd00::/127".parse::<Subnet<Ipv6Addr>>().is_err());
+        assert!("10.0.0.0/8".parse::<Subnet<Ipv6Addr>>().is_err());
+        let everything = "::/0".parse::<Subnet<Ipv6Addr>>().unwrap();
+        assert!(everything.contains(Ipv6Addr::LOCALHOST));
+        assert_eq!(everything.size(),u128::MAX);
+    }
+
+    #[test]
+    fn allocate6_test() {
+        let mut pool = AddressPool::new("fd00::/64".parse::<Subnet<Ipv6Addr>>().unwrap());
+        assert_eq!(pool.allocate(7),Some("fd00::2".parse().unwrap()));
+        assert_eq!(pool.allocate(8),Some("fd00::3".parse().unwrap()));
+        assert_eq!(pool.owner("fd00::3".parse().unwrap()),Some(8));
+        assert_eq!(pool.release(7),Some("fd00::2".parse().unwrap()));
+        assert_eq!(pool.allocate(9),Some("fd00::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn allocate_test() {
+        let mut pool = AddressPool::new(Subnet::new(Ipv4Addr::new(10,10,0,0),16).unwrap());
+        for id in 0..65533 {
+            assert!(pool.allocate(id).is_some());
+        }
+        assert_eq!(pool.owner(Ipv4Addr::new(10,10,0,2)),Some(0));
+        assert_eq!(pool.owner(Ipv4Addr::new(10,10,255,254)),Some(65532));
+        assert_eq!(pool.allocate(65533),None);
+        assert_eq!(pool.release(300),Some(Ipv4Addr::new(10,10,1,46)));
+        assert_eq!(pool.owner(Ipv4Addr::new(10,10,1,46)),None);
+        assert_eq!(pool.release(300),None);
+        assert_eq!(pool.allocate(70000),Some(Ipv4Addr::new(10,10,1,46)));
+        assert_eq!(pool.owner(Ipv4Addr::new(10,10,1,46)),Some(70000));
+    }
+
+    #[test]
+    fn exhausted_test() {
+        let mut pool = AddressPool::new(Subnet::new(Ipv4Addr::new(192,168,1,0),30).unwrap());
+        assert_eq!(pool.allocate(7),Some(Ipv4Addr::new(192,168,1,2)));
+        assert_eq!(pool.allocate(8),None);
+        pool.release(7);
+        assert_eq!(pool.allocate(8),Some(Ipv4Addr::new(192,168,1,2)));
+    }
+}
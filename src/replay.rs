@@ -0,0 +1,163 @@
+/// Number of packets behind the highest counter seen that are still accepted.
+pub const WINDOW_SIZE:u64 = 2048;
+
+const WORD_BITS:u64 = u64::BITS as u64;
+const BITMAP_LEN:usize = (WINDOW_SIZE / WORD_BITS) as usize;
+
+/// Sliding-window replay filter over the packet counters of one session direction.
+///
+/// Bit `counter % WINDOW_SIZE` records whether `counter` has been accepted, for the last
+/// `WINDOW_SIZE` counters up to and including the highest one seen.
+pub struct ReplayWindow {
+    highest:Option<u64>,
+    bitmap:[u64;BITMAP_LEN],
+    duplicates:u64,
+    too_old:u64
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow{
+            highest:None,
+            bitmap:[0;BITMAP_LEN],
+            duplicates:0,
+            too_old:0
+        }
+    }
+
+    /// Returns whether a packet with `counter` may be accepted, counting the drop if not.
+    ///
+    /// Call this before authenticating the packet and [`ReplayWindow::update`] after, so
+    /// forged counters never move the window.
+    pub fn check(&mut self,counter:u64) -> bool {
+        let highest = match self.highest {
+            None => return true,
+            Some(highest) => highest
+        };
+        if counter > highest {
+            true
+        } else if highest - counter >= WINDOW_SIZE {
+            self.too_old += 1;
+            false
+        } else if self.is_set(counter) {
+            self.duplicates += 1;
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Marks an authenticated packet's `counter` as seen, sliding the window forward if needed.
+    pub fn update(&mut self,counter:u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => {}
+            Some(highest) if counter - highest < WINDOW_SIZE => {
+                for skipped in highest + 1..=counter {
+                    self.clear(skipped);
+                }
+                self.highest = Some(counter);
+            }
+            _ => {
+                self.bitmap = [0;BITMAP_LEN];
+                self.highest = Some(counter);
+            }
+        }
+        let (word,bit) = Self::position(counter);
+        self.bitmap[word] |= bit;
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    pub fn too_old(&self) -> u64 {
+        self.too_old
+    }
+
+    fn is_set(&self,counter:u64) -> bool {
+        let (word,bit) = Self::position(counter);
+        self.bitmap[word] & bit != 0
+    }
+
+    fn clear(&mut self,counter:u64) {
+        let (word,bit) = Self::position(counter);
+        self.bitmap[word] &= !bit;
+    }
+
+    fn position(counter:u64) -> (usize,u64) {
+        let index = counter % WINDOW_SIZE;
+        ((index / WORD_BITS) as usize, 1 << (index % WORD_BITS))
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> ReplayWindow {
+        ReplayWindow::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replay::*;
+
+    fn accept(window:&mut ReplayWindow,counter:u64) -> bool {
+        if window.check(counter) {
+            window.update(counter);
+            true
+        } else {
+            false
+        }
+    }
+
+    #[test]
+    fn in_order_test() {
+        let mut window = ReplayWindow::new();
+        for counter in 0..10000 {
+            assert!(accept(&mut window,counter));
+        }
+        assert_eq!(window.duplicates(),0);
+        assert_eq!(window.too_old(),0);
+    }
+
+    #[test]
+    fn duplicate_test() {
+        let mut window = ReplayWindow::new();
+        assert!(accept(&mut window,0));
+        assert!(!accept(&mut window,0));
+        assert!(accept(&mut window,5));
+        assert!(accept(&mut window,3));
+        assert!(!accept(&mut window,3));
+        assert!(!accept(&mut window,5));
+        assert_eq!(window.duplicates(),3);
+    }
+
+    #[test]
+    fn too_old_test() {
+        let mut window = ReplayWindow::new();
+        assert!(accept(&mut window,WINDOW_SIZE + 10));
+        assert!(!accept(&mut window,10));
+        assert!(accept(&mut window,11));
+        assert_eq!(window.too_old(),1);
+    }
+
+    #[test]
+    fn slide_test() {
+        let mut window = ReplayWindow::new();
+        assert!(accept(&mut window,1));
+        assert!(accept(&mut window,1 + WINDOW_SIZE - 1));
+        assert!(!accept(&mut window,1));
+        assert!(accept(&mut window,2 + WINDOW_SIZE));
+        assert!(accept(&mut window,3 + 4 * WINDOW_SIZE));
+        assert!(!accept(&mut window,3 + 4 * WINDOW_SIZE));
+        assert!(!accept(&mut window,2 + WINDOW_SIZE));
+        assert!(accept(&mut window,4 + 3 * WINDOW_SIZE));
+    }
+
+    #[test]
+    fn unauthenticated_check_test() {
+        let mut window = ReplayWindow::new();
+        assert!(accept(&mut window,100));
+        assert!(window.check(100 + 10 * WINDOW_SIZE));
+        assert!(accept(&mut window,99));
+    }
+}
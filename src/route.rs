@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::pool::{mask, Address, Subnet};
+use crate::session::Id;
+
+/// Destination prefix of either family, e.g. `192.168.1.0/24` or `fd00:1::/48`, with the host
+/// bits cleared.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub struct Prefix {
+    network:IpAddr,
+    length:u8
+}
+
+impl Prefix {
+    pub fn new(address:IpAddr,length:u8) -> Result<Prefix,String> {
+        let network = match address {
+            IpAddr::V4(address) => IpAddr::V4(network(address,length)?),
+            IpAddr::V6(address) => IpAddr::V6(network(address,length)?)
+        };
+        Ok(Prefix{ network, length })
+    }
+
+    /// Prefix matching `address` only.
+    pub fn host(address:IpAddr) -> Prefix {
+        let length = match address {
+            IpAddr::V4(_) => <Ipv4Addr as Address>::BITS,
+            IpAddr::V6(_) => <Ipv6Addr as Address>::BITS
+        };
+        Prefix{ network:address, length }
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn length(&self) -> u8 {
+        self.length
+    }
+
+    pub fn contains(&self,address:IpAddr) -> bool {
+        Prefix::new(address,self.length).is_ok_and(|prefix| prefix == *self)
+    }
+
+    /// Whether some address is in both prefixes.
+    pub fn overlaps(&self,other:&Prefix) -> bool {
+        self.contains(other.network) || other.contains(self.network)
+    }
+
+    /// The two prefixes one bit longer that make up this one, none for a host prefix.
+    pub fn halves(&self) -> Option<[Prefix;2]> {
+        let upper = match self.network {
+            IpAddr::V4(network) => IpAddr::V4(half(network,self.length)?),
+            IpAddr::V6(network) => IpAddr::V6(half(network,self.length)?)
+        };
+        let length = self.length + 1;
+        Some([Prefix{ network:self.network, length },Prefix{ network:upper, length }])
+    }
+}
+
+/// Network of the upper half of `network` with `length`.
+fn half<A:Address>(network:A,length:u8) -> Option<A> {
+    let bit = A::BITS.checked_sub(length + 1)?;
+    Some(A::from_u128(network.to_u128() | 1 << bit))
+}
+
+fn network<A:Address>(address:A,length:u8) -> Result<A,String> {
+    if length > A::BITS {
+        return Err(format!("{}/{}: prefix longer than {} bits",address,length,A::BITS));
+    }
+    Ok(A::from_u128(address.to_u128() & mask::<A>(length)))
+}
+
+impl<A:Address + Into<IpAddr>> From<Subnet<A>> for Prefix {
+    fn from(subnet:Subnet<A>) -> Prefix {
+        Prefix{ network:subnet.network().into(), length:subnet.prefix() }
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self,f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}/{}",self.network,self.length)
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    /// Parses CIDR notation such as `192.168.1.0/24`.
+    fn from_str(s:&str) -> Result<Prefix,String> {
+        let (address,length) = s.split_once('/').ok_or(format!("{}: expected address/prefix",s))?;
+        let address = address.parse::<IpAddr>().map_err(|e|format!("{}: {}",s,e))?;
+        let length = length.parse::<u8>().map_err(|e|format!("{}: {}",s,e))?;
+        Prefix::new(address,length)
+    }
+}
+
+/// Destinations a client sends through the tunnel and around it, besides the tunnel
+/// subnets and its default routes.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Split {
+    /// Prefixes routed through the tunnel. A /0 takes all traffic of its family.
+    pub include:Vec<Prefix>,
+    /// Prefixes routed through the original gateway, even if they are in an included one.
+    pub exclude:Vec<Prefix>
+}
+
+impl Split {
+    /// Adds the prefixes of `other` that are not in this one yet.
+    pub fn merge(&mut self,other:&Split) {
+        for (ours,theirs) in [(&mut self.include,&other.include),(&mut self.exclude,&other.exclude)] {
+            for prefix in theirs {
+                if !ours.contains(prefix) {
+                    ours.push(*prefix);
+                }
+            }
+        }
+    }
+
+    /// Rejects excluded prefixes that overlap one of the `tunnels` subnets, which would cut
+    /// off the tunnel itself, or cover a whole included prefix, which would never be routed.
+    pub fn check(&self,tunnels:&[Prefix]) -> Result<(),String> {
+        for excluded in &self.exclude {
+            if let Some(tunnel) = tunnels.iter().find(|tunnel| excluded.overlaps(tunnel)) {
+                return Err(format!("excluded {} overlaps the tunnel subnet {}",excluded,tunnel));
+            }
+            let covered = |included:&&Prefix| excluded.contains(included.network) && excluded.length <= included.length;
+            if let Some(included) = self.include.iter().find(covered) {
+                return Err(format!("excluded {} covers the included {}",excluded,included));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Routes of one address family, keyed by prefix length and then by network.
+struct Table<A> {
+    prefixes:Vec<HashMap<u128,Id>>,
+    family:PhantomData<A>
+}
+
+impl<A:Address> Table<A> {
+    fn new() -> Table<A> {
+        Table{ prefixes:(0..=A::BITS).map(|_| HashMap::new()).collect(), family:PhantomData }
+    }
+
+    fn insert(&mut self,network:A,length:u8,id:Id) -> Option<Id> {
+        self.prefixes[usize::from(length)].insert(network.to_u128(),id)
+    }
+
+    fn get(&self,network:A,length:u8) -> Option<Id> {
+        self.prefixes[usize::from(length)].get(&network.to_u128()).copied()
+    }
+
+    fn remove_session(&mut self,id:Id) {
+        for routes in &mut self.prefixes {
+            routes.retain(|_,owner| *owner != id);
+        }
+    }
+
+    fn lookup(&self,address:A) -> Option<Id> {
+        let address = address.to_u128();
+        self.prefixes
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_,routes)| !routes.is_empty())
+            .find_map(|(prefix,routes)| routes.get(&(address & mask::<A>(prefix as u8))).copied())
+    }
+}
+
+/// Maps destination prefixes of both address families to the sessions packets for them go
+/// to, choosing the longest matching prefix.
+pub struct RoutingTable {
+    v4:Table<Ipv4Addr>,
+    v6:Table<Ipv6Addr>
+}
+
+impl RoutingTable {
+    pub fn new() -> RoutingTable {
+        RoutingTable{ v4:Table::new(), v6:Table::new() }
+    }
+
+    /// Routes `prefix` to session `id`, returning the session it was routed to before.
+    pub fn insert(&mut self,prefix:Prefix,id:Id) -> Option<Id> {
+        match prefix.network {
+            IpAddr::V4(network) => self.v4.insert(network,prefix.length,id),
+            IpAddr::V6(network) => self.v6.insert(network,prefix.length,id)
+        }
+    }
+
+    /// Session `prefix` itself is routed to, ignoring shorter and longer prefixes.
+    pub fn get(&self,prefix:Prefix) -> Option<Id> {
+        match prefix.network {
+            IpAddr::V4(network) => self.v4.get(network,prefix.length),
+            IpAddr::V6(network) => self.v6.get(network,prefix.length)
+        }
+    }
+
+    /// Drops every route to session `id`.
+    pub fn remove_session(&mut self,id:Id) {
+        self.v4.remove_session(id);
+        self.v6.remove_session(id);
+    }
+
+    /// Session that packets for `destination` go to.
+    pub fn lookup(&self,destination:IpAddr) -> Option<Id> {
+        match destination {
+            IpAddr::V4(destination) => self.v4.lookup(destination),
+            IpAddr::V6(destination) => self.v6.lookup(destination)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::route::*;
+
+    fn ip(ip:&str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn prefix(prefix:&str) -> Prefix {
+        prefix.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_test() {
+        assert_eq!(prefix("192.168.7.9/16").to_string(),"192.168.0.0/16");
+        assert_eq!(prefix("fd00:e:1::9/48").to_string(),"fd00:e:1::/48");
+        assert_eq!(Prefix::host(ip("fd00::2")).to_string(),"fd00::2/128");
+        assert_eq!(Prefix::from("10.10.7.9/16".parse::<Subnet>().unwrap()),prefix("10.10.0.0/16"));
+        assert!(prefix("192.168.0.0/16").contains(ip("192.168.255.1")));
+        assert!(!prefix("192.168.0.0/16").contains(ip("192.169.0.1")));
+        assert!(!prefix("0.0.0.0/0").contains(ip("::1")));
+        assert!(prefix("10.0.0.0/8").overlaps(&prefix("10.10.10.0/24")));
+        assert!(prefix("10.10.10.0/24").overlaps(&prefix("10.0.0.0/8")));
+        assert!(!prefix("10.10.10.0/24").overlaps(&prefix("10.10.11.0/24")));
+        for invalid in ["192.168.0.0","192.168.0.0/33","fd00::/129","fd00::/x","host/24"] {
+            assert!(invalid.parse::<Prefix>().is_err());
+        }
+    }
+
+    #[test]
+    fn halves_test() {
+        assert_eq!(prefix("0.0.0.0/0").halves(),Some([prefix("0.0.0.0/1"),prefix("128.0.0.0/1")]));
+        assert_eq!(prefix("::/0").halves(),Some([prefix("::/1"),prefix("8000::/1")]));
+        assert_eq!(prefix("10.0.0.0/31").halves(),Some([prefix("10.0.0.0/32"),prefix("10.0.0.1/32")]));
+        assert_eq!(Prefix::host(ip("10.0.0.1")).halves(),None);
+        assert_eq!(Prefix::host(ip("fd00::1")).halves(),None);
+    }
+
+    #[test]
+    fn split_merge_test() {
+        let mut split = Split{ include:vec![prefix("10.1.0.0/16")], exclude:Vec::new() };
+        split.merge(&Split{ include:vec![prefix("10.1.0.0/16"),prefix("fd00:1::/48")], exclude:vec![prefix("10.1.2.0/24")] });
+        assert_eq!(split.include,vec![prefix("10.1.0.0/16"),prefix("fd00:1::/48")]);
+        assert_eq!(split.exclude,vec![prefix("10.1.2.0/24")]);
+    }
+
+    #[test]
+    fn split_check_test() {
+        let tunnels = [prefix("10.10.10.0/24"),prefix("fd00:e::/64")];
+        let split = Split{ include:vec![prefix("0.0.0.0/0"),prefix("10.1.0.0/16")], exclude:vec![prefix("10.1.2.0/24")] };
+        assert!(split.check(&tunnels).is_ok());
+        for exclude in ["10.10.10.0/24","10.0.0.0/8","10.10.10.7/32","fd00::/16"] {
+            let split = Split{ include:Vec::new(), exclude:vec![prefix(exclude)] };
+            assert!(split.check(&tunnels).unwrap_err().contains("tunnel subnet"));
+        }
+        let split = Split{ include:vec![prefix("192.168.1.0/24")], exclude:vec![prefix("192.168.0.0/16")] };
+        assert_eq!(split.check(&tunnels),Err(String::from("excluded 192.168.0.0/16 covers the included 192.168.1.0/24")));
+    }
+
+    #[test]
+    fn longest_prefix_test() {
+        let mut routes = RoutingTable::new();
+        assert_eq!(routes.insert(prefix("0.0.0.0/0"),1),None);
+        assert_eq!(routes.insert(prefix("192.168.7.9/16"),2),None);
+        assert_eq!(routes.insert(prefix("192.168.7.0/24"),3),None);
+        assert_eq!(routes.insert(Prefix::host(ip("192.168.7.7")),4),None);
+        assert_eq!(routes.lookup(ip("192.168.7.7")),Some(4));
+        assert_eq!(routes.lookup(ip("192.168.7.8")),Some(3));
+        assert_eq!(routes.lookup(ip("192.168.8.8")),Some(2));
+        assert_eq!(routes.lookup(ip("10.0.0.1")),Some(1));
+        assert_eq!(routes.lookup(ip("::1")),None);
+        assert_eq!(routes.get(prefix("192.168.0.0/16")),Some(2));
+        assert_eq!(routes.get(prefix("192.168.0.0/17")),None);
+    }
+
+    #[test]
+    fn longest_prefix6_test() {
+        let mut routes = RoutingTable::new();
+        assert_eq!(routes.insert(prefix("fd00:e::/32"),1),None);
+        assert_eq!(routes.insert(Prefix::host(ip("fd00:e::2")),2),None);
+        assert_eq!(routes.insert(prefix("fd00:e:1::/48"),3),None);
+        assert_eq!(routes.lookup(ip("fd00:e::2")),Some(2));
+        assert_eq!(routes.lookup(ip("fd00:e::3")),Some(1));
+        assert_eq!(routes.lookup(ip("fd00:e:1::3")),Some(3));
+        assert_eq!(routes.lookup(ip("fd00:f::1")),None);
+        assert_eq!(routes.lookup(ip("0.0.0.2")),None);
+    }
+
+    #[test]
+    fn remove_session_test() {
+        let mut routes = RoutingTable::new();
+        routes.insert(Prefix::host(ip("10.10.10.2")),7);
+        routes.insert(Prefix::host(ip("fd00::2")),7);
+        routes.insert(prefix("192.168.1.0/24"),7);
+        routes.insert(prefix("192.168.0.0/16"),8);
+        assert_eq!(routes.insert(Prefix::host(ip("10.10.10.2")),9),Some(7));
+        routes.remove_session(7);
+        assert_eq!(routes.lookup(ip("fd00::2")),None);
+        assert_eq!(routes.lookup(ip("192.168.1.1")),Some(8));
+        assert_eq!(routes.lookup(ip("10.10.10.2")),Some(9));
+    }
+}
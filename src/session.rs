@@ -0,0 +1,362 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use ring::rand::SystemRandom;
+
+use crate::clients::Identity;
+use crate::crypto::{self, Handshake, Role, SessionKeys, KEY_LEN};
+use crate::replay::ReplayWindow;
+use crate::wire::{Header, MessageType, HEADER_LEN};
+
+/// Session index carried in the header, independent of the client's address.
+pub type Id = u32;
+
+pub type Token = u64;
+
+/// How long keys replaced by a rekey are still accepted for inbound packets.
+const OVERLAP:Duration = Duration::from_secs(30);
+/// How long a rekey may stay unanswered before it is started over.
+const REKEY_TIMEOUT:Duration = Duration::from_secs(5);
+
+/// Usage after which a session's traffic keys are replaced.
+#[derive(Clone,Copy,Debug)]
+pub struct RekeyLimits {
+    pub bytes:u64,
+    pub packets:u64,
+    pub age:Duration
+}
+
+impl Default for RekeyLimits {
+    fn default() -> RekeyLimits {
+        RekeyLimits{
+            bytes:1 << 36,
+            packets:1 << 32,
+            age:Duration::from_secs(10 * 60)
+        }
+    }
+}
+
+/// One generation of traffic keys and the counters that go with it.
+struct Epoch {
+    number:u8,
+    keys:SessionKeys,
+    send_counter:u64,
+    sent_bytes:u64,
+    window:ReplayWindow,
+    created:Instant
+}
+
+impl Epoch {
+    fn new(number:u8,keys:SessionKeys) -> Epoch {
+        Epoch{
+            number,
+            keys,
+            send_counter:0,
+            sent_bytes:0,
+            window:ReplayWindow::new(),
+            created:Instant::now()
+        }
+    }
+}
+
+#[derive(Clone,Copy,PartialEq)]
+enum Slot {
+    Current,
+    Next,
+    Previous
+}
+
+/// State of an established session on either end of the tunnel.
+///
+/// Session datagrams are a header carrying the session index, key epoch and counter,
+/// followed by `ciphertext || tag`; see [`crate::wire`]. A rekey
+/// runs an ephemeral exchange inside the session: the initiator switches to the new epoch as
+/// soon as it has the peer's answer, the responder once the first packet of the new epoch
+/// arrives, which the initiator sends right away, and both keep accepting the old epoch for
+/// [`OVERLAP`].
+pub struct Session {
+    pub token:Token,
+    pub addr:SocketAddr,
+    /// Client authenticated by the handshake, only known to the server.
+    pub identity:Option<Identity>,
+    /// Protocol version negotiated in the handshake.
+    pub version:u8,
+    role:Role,
+    psk:[u8;KEY_LEN],
+    limits:RekeyLimits,
+    current:Epoch,
+    next:Option<Epoch>,
+    previous:Option<(Epoch,Instant)>,
+    pending:Option<(Handshake,Instant)>
+}
+
+impl Session {
+    pub fn new(
+        token:Token,
+        addr:SocketAddr,
+        version:u8,
+        role:Role,
+        psk:&[u8;KEY_LEN],
+        keys:SessionKeys,
+        limits:RekeyLimits
+    ) -> Session {
+        Session{
+            token,
+            addr,
+            identity:None,
+            version,
+            role,
+            psk:*psk,
+            limits,
+            current:Epoch::new(0,keys),
+            next:None,
+            previous:None,
+            pending:None
+        }
+    }
+
+    pub fn seal(&mut self,id:Id,message_type:MessageType,plaintext:&[u8]) -> Result<Vec<u8>,String> {
+        let epoch = &mut self.current;
+        let counter = epoch.send_counter;
+        epoch.send_counter += 1;
+        epoch.sent_bytes += plaintext.len() as u64;
+        let header = Header{
+            version:self.version,
+            message_type,
+            epoch:epoch.number,
+            session:id,
+            counter
+        }.encode();
+        let mut datagram = header.to_vec();
+        datagram.extend_from_slice(&crypto::seal_counted(&epoch.keys.send,counter,&header,plaintext)?);
+        Ok(datagram)
+    }
+
+    /// Opens a datagram of this session, returning `None` for replayed or too old packets.
+    pub fn open<'a>(&mut self,datagram:&'a mut [u8]) -> Result<Option<&'a mut [u8]>,String> {
+        let header = Header::decode(datagram)?;
+        if header.version != self.version {
+            return Err(format!("protocol version {} instead of {}",header.version,self.version));
+        }
+        let (counter,number) = (header.counter,header.epoch);
+        let slot = self.slot(number).ok_or_else(|| format!("unknown key epoch {}",number))?;
+        let addr = self.addr;
+        let epoch = self.epoch_mut(slot);
+        if !epoch.window.check(counter) {
+            // Logged at powers of two only, as anyone can resend captured packets.
+            let (duplicates,too_old) = (epoch.window.duplicates(),epoch.window.too_old());
+            if (duplicates + too_old).is_power_of_two() {
+                warn!(
+                    "Dropped replayed packet {} from {}. Dropped so far: {} duplicate, {} too old.",
+                    counter, addr, duplicates, too_old
+                );
+            }
+            return Ok(None);
+        }
+        let (aad,sealed) = datagram.split_at_mut(HEADER_LEN);
+        let plaintext = crypto::open_counted(&epoch.keys.recv,counter,aad,sealed)?;
+        epoch.window.update(counter);
+        if slot == Slot::Next {
+            let next = self.next.take().unwrap();
+            self.retire(next);
+        }
+        Ok(Some(plaintext))
+    }
+
+    /// Whether the current keys are worn out and no rekey is already under way.
+    pub fn needs_rekey(&self) -> bool {
+        let pending = matches!(self.pending, Some((_,started)) if started.elapsed() < REKEY_TIMEOUT);
+        let epoch = &self.current;
+        !pending && self.next.is_none() && (
+            epoch.sent_bytes >= self.limits.bytes
+                || epoch.send_counter >= self.limits.packets
+                || epoch.created.elapsed() >= self.limits.age
+        )
+    }
+
+    /// Starts a rekey and returns the public key to send to the peer.
+    pub fn start_rekey(&mut self,rng:&SystemRandom) -> Result<[u8;KEY_LEN],String> {
+        let handshake = Handshake::new(rng)?;
+        let public_key = *handshake.public_key();
+        self.pending = Some((handshake,Instant::now()));
+        info!("Rekeying session with {}.", self.addr);
+        Ok(public_key)
+    }
+
+    /// Answers a rekey started by the peer, returning the public key to reply with.
+    ///
+    /// When both ends start a rekey at once the client's wins: the server abandons its own,
+    /// while the client ignores the server's and waits for its answer instead.
+    pub fn accept_rekey(&mut self,rng:&SystemRandom,peer_public_key:&[u8]) -> Result<Option<[u8;KEY_LEN]>,String> {
+        if self.pending.is_some() {
+            match self.role {
+                Role::Client => return Ok(None),
+                Role::Server => self.pending = None
+            }
+        }
+        let handshake = Handshake::new(rng)?;
+        let public_key = *handshake.public_key();
+        let keys = handshake.finish(&self.psk,self.role,peer_public_key)?;
+        self.next = Some(Epoch::new(self.current.number.wrapping_add(1),keys));
+        Ok(Some(public_key))
+    }
+
+    /// Completes a rekey we started and switches to the new keys.
+    pub fn finish_rekey(&mut self,peer_public_key:&[u8]) -> Result<(),String> {
+        let (handshake,_) = self.pending.take().ok_or("unexpected rekey answer")?;
+        let keys = handshake.finish(&self.psk,self.role,peer_public_key)?;
+        let next = Epoch::new(self.current.number.wrapping_add(1),keys);
+        self.retire(next);
+        Ok(())
+    }
+
+    fn retire(&mut self,next:Epoch) {
+        info!("Session with {} switched to key epoch {}.", self.addr, next.number);
+        let current = std::mem::replace(&mut self.current,next);
+        self.previous = Some((current,Instant::now()));
+    }
+
+    fn slot(&mut self,number:u8) -> Option<Slot> {
+        if matches!(self.previous, Some((_,retired)) if retired.elapsed() >= OVERLAP) {
+            self.previous = None;
+        }
+        if self.current.number == number {
+            Some(Slot::Current)
+        } else if matches!(&self.next, Some(next) if next.number == number) {
+            Some(Slot::Next)
+        } else if matches!(&self.previous, Some((previous,_)) if previous.number == number) {
+            Some(Slot::Previous)
+        } else {
+            None
+        }
+    }
+
+    fn epoch_mut(&mut self,slot:Slot) -> &mut Epoch {
+        match slot {
+            Slot::Current => &mut self.current,
+            Slot::Next => self.next.as_mut().unwrap(),
+            Slot::Previous => &mut self.previous.as_mut().unwrap().0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::*;
+    use crate::wire::VERSION;
+
+    fn session_pair(limits:RekeyLimits) -> (Session,Session) {
+        let rng = SystemRandom::new();
+        let psk = crypto::derive_legacy_psk("password");
+        let client = Handshake::new(&rng).unwrap();
+        let server = Handshake::new(&rng).unwrap();
+        let client_public_key = client.public_key().to_vec();
+        let server_public_key = server.public_key().to_vec();
+        let addr = "127.0.0.1:8964".parse::<SocketAddr>().unwrap();
+        let client_keys = client.finish(&psk,Role::Client,&server_public_key).unwrap();
+        let server_keys = server.finish(&psk,Role::Server,&client_public_key).unwrap();
+        (
+            Session::new(0,addr,VERSION,Role::Client,&psk,client_keys,limits),
+            Session::new(0,addr,VERSION,Role::Server,&psk,server_keys,limits)
+        )
+    }
+
+    fn rekey(initiator:&mut Session,responder:&mut Session) {
+        let rng = SystemRandom::new();
+        let public_key = initiator.start_rekey(&rng).unwrap();
+        let reply = responder.accept_rekey(&rng,&public_key).unwrap().unwrap();
+        initiator.finish_rekey(&reply).unwrap();
+    }
+
+    #[test]
+    fn unique_counter_test() {
+        let (mut client,_) = session_pair(RekeyLimits::default());
+        let mut counters = std::collections::HashSet::new();
+        for _ in 0..10000 {
+            let sealed = client.seal(2,MessageType::Data,b"").unwrap();
+            assert!(counters.insert(Header::decode(&sealed).unwrap().counter));
+        }
+    }
+
+    #[test]
+    fn replay_test() {
+        let (mut client,mut server) = session_pair(RekeyLimits::default());
+        let sealed = client.seal(2,MessageType::Data,b"data").unwrap();
+        assert_eq!(server.open(&mut sealed.clone()).unwrap().unwrap(),b"data");
+        assert_eq!(server.open(&mut sealed.clone()).unwrap(),None);
+        assert_eq!(server.current.window.duplicates(),1);
+        let mut forged = client.seal(2,MessageType::Data,b"data").unwrap();
+        forged[HEADER_LEN] ^= 1;
+        assert!(server.open(&mut forged).is_err());
+        let mut moved = client.seal(2,MessageType::Data,b"data").unwrap();
+        moved[HEADER_LEN - 9] ^= 1;
+        assert!(server.open(&mut moved).is_err());
+        let mut downgraded = client.seal(2,MessageType::Data,b"data").unwrap();
+        downgraded[2] = VERSION + 1;
+        assert!(server.open(&mut downgraded).is_err());
+    }
+
+    #[test]
+    fn needs_rekey_test() {
+        let limits = RekeyLimits{ bytes:1 << 20, packets:3, age:Duration::from_secs(60) };
+        let (mut client,mut server) = session_pair(limits);
+        for _ in 0..3 {
+            assert!(!client.needs_rekey());
+            client.seal(2,MessageType::Data,b"data").unwrap();
+        }
+        assert!(client.needs_rekey());
+        client.start_rekey(&SystemRandom::new()).unwrap();
+        assert!(!client.needs_rekey());
+        let limits = RekeyLimits{ bytes:8, packets:1 << 20, age:Duration::from_secs(60) };
+        let (mut client,_) = session_pair(limits);
+        client.seal(2,MessageType::Data,b"12345678").unwrap();
+        assert!(client.needs_rekey());
+        server.limits.age = Duration::ZERO;
+        assert!(server.needs_rekey());
+    }
+
+    #[test]
+    fn rekey_overlap_test() {
+        let (mut client,mut server) = session_pair(RekeyLimits::default());
+        let in_flight = client.seal(2,MessageType::Data,b"old").unwrap();
+        rekey(&mut client,&mut server);
+        let fresh = client.seal(2,MessageType::Data,b"new").unwrap();
+        assert_eq!(Header::decode(&fresh).unwrap().epoch,1);
+        let reply = server.seal(2,MessageType::Data,b"still old").unwrap();
+        assert_eq!(Header::decode(&reply).unwrap().epoch,0);
+        assert_eq!(client.open(&mut reply.clone()).unwrap().unwrap(),b"still old");
+        assert_eq!(server.open(&mut fresh.clone()).unwrap().unwrap(),b"new");
+        assert_eq!(server.current.number,1);
+        assert_eq!(server.open(&mut in_flight.clone()).unwrap().unwrap(),b"old");
+        let reply = server.seal(2,MessageType::Data,b"new").unwrap();
+        assert_eq!(client.open(&mut reply.clone()).unwrap().unwrap(),b"new");
+        server.previous.as_mut().unwrap().1 -= OVERLAP;
+        assert!(server.open(&mut in_flight.clone()).is_err());
+    }
+
+    #[test]
+    fn server_rekey_test() {
+        let (mut client,mut server) = session_pair(RekeyLimits::default());
+        rekey(&mut server,&mut client);
+        let sealed = server.seal(2,MessageType::Data,b"new").unwrap();
+        assert_eq!(client.open(&mut sealed.clone()).unwrap().unwrap(),b"new");
+        let sealed = client.seal(2,MessageType::Data,b"new").unwrap();
+        assert_eq!(Header::decode(&sealed).unwrap().epoch,1);
+        assert_eq!(server.open(&mut sealed.clone()).unwrap().unwrap(),b"new");
+    }
+
+    #[test]
+    fn simultaneous_rekey_test() {
+        let rng = SystemRandom::new();
+        let (mut client,mut server) = session_pair(RekeyLimits::default());
+        let client_public_key = client.start_rekey(&rng).unwrap();
+        let server_public_key = server.start_rekey(&rng).unwrap();
+        assert_eq!(client.accept_rekey(&rng,&server_public_key).unwrap(),None);
+        let reply = server.accept_rekey(&rng,&client_public_key).unwrap().unwrap();
+        client.finish_rekey(&reply).unwrap();
+        let sealed = client.seal(2,MessageType::Data,b"new").unwrap();
+        assert_eq!(server.open(&mut sealed.clone()).unwrap().unwrap(),b"new");
+        assert!(server.finish_rekey(&client_public_key).is_err());
+    }
+}
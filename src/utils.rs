@@ -1,33 +1,88 @@
-use std::fmt::format;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::net::Ipv4Addr;
 use std::process::Command;
-use libc::stat;
-use log::info;
+use log::{info, warn};
+
+use crate::error::Error;
+use crate::journal::{Journal, Undo};
+#[cfg(target_os = "linux")]
+use crate::netlink;
+use crate::route::{Prefix, Split};
 
 pub fn is_root() -> bool {
     unsafe {
-        libc::getegid() == 0
+        libc::geteuid() == 0
+    }
+}
+
+/// Runs `program` and returns its standard output, failing if it cannot be started or exits
+/// unsuccessfully.
+fn run(program:&str,args:&[&str]) -> Result<String,Error> {
+    let command = format!("{} {}",program,args.join(" "));
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e|Error::Command{ command:command.clone(), reason:e.to_string() })?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = match stderr.trim() {
+            "" => output.status.to_string(),
+            stderr => stderr.to_string()
+        };
+        Err(Error::Command{ command, reason })
+    }
+}
+
+/// Runs a command given as program followed by its arguments.
+pub fn run_command(command:&[String]) -> Result<String,Error> {
+    let args:Vec<&str> = command[1..].iter().map(String::as_str).collect();
+    run(&command[0],&args)
+}
+
+fn get_sysctl(name:&str) -> Result<String,Error> {
+    Ok(run("sysctl",&["-n",name])?.trim().to_string())
+}
+
+pub fn set_sysctl(name:&str,value:&str) -> Result<(),Error> {
+    run("sysctl",&["-w",&format!("{}={}",name,value)])?;
+    Ok(())
+}
+
+/// Sets a kernel parameter to 1, noting its previous value in `journal` if it changes.
+fn enable_sysctl(journal:&mut Journal,name:&str) -> Result<(),Error> {
+    let value = get_sysctl(name)?;
+    if value != "1" {
+        journal.record(Undo::Sysctl{ name:name.to_string(), value })?;
+        set_sysctl(name,"1")?;
     }
+    Ok(())
 }
 
-pub fn enable_ipv4_forwarding() -> Result<(),String> {
-    let sysctl_arg = if cfg!(target_os = "linux") {
-        "net.ipv4.ip_forward=1"
+pub fn enable_ipv4_forwarding(journal:&mut Journal) -> Result<(),Error> {
+    let name = if cfg!(target_os = "linux") {
+        "net.ipv4.ip_forward"
     } else if cfg!(target_os = "macos") {
-        "net.inet.ip.forwarding=1"
+        "net.inet.ip.forwarding"
     } else {
         unimplemented!()
     };
     info!("Enabling IPv4 Forwarding.");
-    let status = Command::new("sysctl")
-        .arg("-w")
-        .arg(sysctl_arg)
-        .status()
-        .unwrap();
-    if status.success(){
-        Ok(())
+    enable_sysctl(journal,name)
+}
+
+pub fn enable_ipv6_forwarding(journal:&mut Journal) -> Result<(),Error> {
+    let name = if cfg!(target_os = "linux") {
+        "net.ipv6.conf.all.forwarding"
+    } else if cfg!(target_os = "macos") {
+        "net.inet6.ip6.forwarding"
     } else {
-        Err(format!("sysctl: {}",status))
-    }
+        unimplemented!()
+    };
+    info!("Enabling IPv6 Forwarding.");
+    enable_sysctl(journal,name)
 }
 
 pub enum RouteType{
@@ -35,175 +90,536 @@ pub enum RouteType{
     Host
 }
 
-pub struct DefaultGateway{
-    origin:String,
-    remote:String,
-    default:bool
-}
-
-impl DefaultGateway{
-    pub fn create(gateway:&str,remote:&str,default:bool) -> DefaultGateway{
-        let origin = get_default_gateway().unwrap();
-        info!("original default gateway: {}.",origin);
-        add_route(RouteType::Host,remote,&origin).unwrap();
-        if default {
-            delete_default_gateway().unwrap();
-            set_default_gateway(gateway).unwrap();
+/// Routes `remote` through the original default gateway of its family, then the default
+/// route through `gateway` if `default` is set and `split` includes nothing, and all IPv6
+/// traffic through `gateway6` if `default6` is. The included prefixes of `split` go through
+/// the tunnel gateway of their family and the excluded ones through the original gateway.
+/// Routes through the tunnel go away with the TUN device, so only the others are noted in
+/// `journal`.
+pub fn set_tunnel_routes(
+    journal:&mut Journal,
+    gateway:IpAddr,
+    gateway6:Option<IpAddr>,
+    remote:IpAddr,
+    default:bool,
+    default6:bool,
+    split:&Split
+) -> Result<(),Error> {
+    let origin = get_default_gateway()?;
+    let origin6 = get_default_gateway6()?;
+    info!("original default gateway: {}.",origin);
+    let mut include = split.include.clone();
+    if default6 {
+        include.push(Prefix::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED),0).map_err(Error::Config)?);
+    }
+    let tunnels6 = gateway6.is_some() && include.iter().any(|prefix| prefix.network().is_ipv6());
+    match (remote,&origin6) {
+        (IpAddr::V4(_),_) => {
+            journal.record(Undo::Route(Prefix::host(remote)))?;
+            add_route(RouteType::Host,&remote.to_string(),&origin)?;
+        }
+        (IpAddr::V6(_),_) if !tunnels6 => {}
+        (IpAddr::V6(_),Some(origin6)) => {
+            journal.record(Undo::Route(Prefix::host(remote)))?;
+            add_route6(&format!("{}/128",remote),origin6)?;
+        }
+        (IpAddr::V6(_),None) => warn!("No IPv6 default gateway, assuming {} stays reachable.",remote)
+    }
+    if default && !split.include.is_empty() {
+        info!("Only routing the included prefixes through the tunnel, include 0.0.0.0/0 to route all traffic.");
+    } else if default {
+        journal.record(Undo::DefaultRoute(origin.clone()))?;
+        delete_default_gateway()?;
+        set_default_gateway(&gateway.to_string())?;
+    }
+    for prefix in include {
+        let gateway = match (prefix.network(),gateway6) {
+            (IpAddr::V4(_),_) => gateway,
+            (IpAddr::V6(_),Some(gateway6)) => gateway6,
+            (IpAddr::V6(_),None) => {
+                warn!("The tunnel carries no IPv6, not routing {} through it.",prefix);
+                continue;
+            }
+        };
+        // A /0 goes in as its two halves, which override the default route without
+        // replacing it.
+        let routes = match prefix.halves() {
+            Some(halves) if prefix.length() == 0 => halves.to_vec(),
+            _ => vec![prefix]
+        };
+        for route in routes {
+            add_prefix_route(&route,gateway)?;
         }
-        DefaultGateway{
-            origin,
-            remote:String::from(remote),
-            default
+    }
+    for prefix in &split.exclude {
+        let origin = match prefix.network() {
+            IpAddr::V4(_) => &origin,
+            IpAddr::V6(_) => match &origin6 {
+                Some(origin6) => origin6,
+                None => {
+                    warn!("No IPv6 default gateway to route {} through.",prefix);
+                    continue;
+                }
+            }
+        };
+        if *prefix == Prefix::host(remote) {
+            continue;
         }
+        journal.record(Undo::Route(*prefix))?;
+        route_via(prefix,origin)?;
     }
+    Ok(())
 }
 
-fn set_default_gateway(gateway: &str) -> Result<(),String> {
+/// Makes `gateway` the IPv4 default gateway again, in place of the tunnel's if it is still
+/// there.
+pub fn restore_default_gateway(gateway:&str) -> Result<(),Error> {
+    if let Err(e) = delete_default_gateway() {
+        info!("No default route to replace: {}.",e);
+    }
+    set_default_gateway(gateway)
+}
+
+fn set_default_gateway(gateway: &str) -> Result<(),Error> {
     add_route(RouteType::Net,"default",gateway)
 }
 
-fn delete_default_gateway() -> Result<(),String> {
+fn delete_default_gateway() -> Result<(),Error> {
     delete_route(RouteType::Net,"default")
 }
 
-fn delete_route(route_type: RouteType, route: &str) -> Result<(), String> {
+/// Destination of a route given in the form `route` takes, e.g. `default` or `10.0.0.0/8`.
+#[cfg(target_os = "linux")]
+fn route_prefix(route_type:RouteType,route:&str) -> Result<Prefix,Error> {
+    match route_type {
+        RouteType::Net if route == "default" => Prefix::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED),0).map_err(Error::Config),
+        RouteType::Net => route.parse().map_err(Error::Config),
+        RouteType::Host => route
+            .parse::<IpAddr>()
+            .map(Prefix::host)
+            .map_err(|e|Error::Config(format!("{}: {}",route,e)))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn parse_gateway(gateway:&str) -> Result<IpAddr,Error> {
+    gateway.parse().map_err(|e|Error::Config(format!("gateway {}: {}",gateway,e)))
+}
+
+#[cfg(target_os = "linux")]
+fn delete_route(route_type:RouteType,route:&str) -> Result<(),Error> {
+    let prefix = route_prefix(route_type,route)?;
+    info!("Deleting route : {}",prefix);
+    netlink::delete_route(&prefix).map_err(|error|Error::Netlink{ request:format!("deleting route {}",prefix), error })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn delete_route(route_type: RouteType, route: &str) -> Result<(), Error> {
     let mode = match route_type {
         RouteType::Net => "-net",
         RouteType::Host => "-host"
     };
     info!("Deleting route : {} {}",mode,route);
-    let status = if cfg!(target_os = "linux") {
-        Command::new("route")
-            .arg("-n")
-            .arg("del")
-            .arg(mode)
-            .arg(route)
-            .status()
-            .unwrap()
-    } else if cfg!(target_os = "macos") {
-        Command::new("route")
-            .arg("-n")
-            .arg("delete")
-            .arg(mode)
-            .arg(route)
-            .status()
-            .unwrap()
+    if cfg!(target_os = "macos") {
+        run("route",&["-n","delete",mode,route])?;
     } else {
         unimplemented!()
-    };
-    if status.success(){
-        Ok(())
-    } else {
-        Err(format!("route: {}",status))
     }
+    Ok(())
 }
 
-fn add_route(route_type: RouteType, route: &str, gateway: &str) -> Result<(),String> {
+#[cfg(target_os = "linux")]
+fn add_route(route_type:RouteType,route:&str,gateway:&str) -> Result<(),Error> {
+    let prefix = route_prefix(route_type,route)?;
+    info!("Adding route: {} gateway {}.",prefix,gateway);
+    netlink::add_route(&prefix,parse_gateway(gateway)?,None)
+        .map_err(|error|Error::Netlink{ request:format!("adding route {} via {}",prefix,gateway), error })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn add_route(route_type: RouteType, route: &str, gateway: &str) -> Result<(),Error> {
     let mode = match route_type{
         RouteType::Net => "-net",
         RouteType::Host => "-host"
     };
     info!("Adding route: {} {} gateway {}.",mode,route,gateway);
-    let status = if cfg!(target_os = "linux") {
-        Command::new("route")
-            .arg("-n")
-            .arg("add")
-            .arg(mode)
-            .arg(route)
-            .arg("gw")
-            .arg(gateway)
-            .status()
-            .unwrap()
-    } else if cfg!(target_os = "macos") {
-        Command::new("route")
-            .arg("-n")
-            .arg("add")
-            .arg(mode)
-            .arg(route)
-            .arg(gateway)
-            .status()
-            .unwrap()
+    if cfg!(target_os = "macos") {
+        run("route",&["-n","add",mode,route,gateway])?;
     } else {
         unimplemented!()
+    }
+    Ok(())
+}
+
+/// Adds an IPv6 route. A link-local `gateway` carries its interface as a scope, e.g.
+/// `fe80::1%eth0`.
+#[cfg(target_os = "linux")]
+fn add_route6(route:&str,gateway:&str) -> Result<(),Error> {
+    info!("Adding IPv6 route: {} gateway {}.",route,gateway);
+    let prefix = route.parse::<Prefix>().map_err(Error::Config)?;
+    let (address,device) = match gateway.split_once('%') {
+        Some((address,device)) => (address,Some(device)),
+        None => (gateway,None)
     };
-    if status.success() {
-        Ok(())
+    netlink::add_route(&prefix,parse_gateway(address)?,device)
+        .map_err(|error|Error::Netlink{ request:format!("adding IPv6 route {} via {}",prefix,gateway), error })
+}
+
+/// Adds an IPv6 route. A link-local `gateway` carries its interface as a scope, e.g.
+/// `fe80::1%en0`.
+#[cfg(not(target_os = "linux"))]
+fn add_route6(route:&str,gateway:&str) -> Result<(),Error> {
+    info!("Adding IPv6 route: {} gateway {}.",route,gateway);
+    if cfg!(target_os = "macos") {
+        run("route",&["-n","add","-inet6",route,gateway])?;
     } else {
-        Err(format!("route: {}", status))
+        unimplemented!()
     }
+    Ok(())
 }
 
-fn get_default_gateway() -> Result<String,String> {
-    let cmd = if cfg!(target_os = "linux") {
-        "ip -4 route list 0/0 | awk '{print $3}'"
-    } else if cfg!(target_os = "macos") {
+#[cfg(target_os = "linux")]
+fn delete_route6(route:&str) -> Result<(),Error> {
+    info!("Deleting IPv6 route: {}.",route);
+    let prefix = route.parse::<Prefix>().map_err(Error::Config)?;
+    netlink::delete_route(&prefix).map_err(|error|Error::Netlink{ request:format!("deleting IPv6 route {}",prefix), error })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn delete_route6(route:&str) -> Result<(),Error> {
+    info!("Deleting IPv6 route: {}.",route);
+    if cfg!(target_os = "macos") {
+        run("route",&["-n","delete","-inet6",route])?;
+    } else {
+        unimplemented!()
+    }
+    Ok(())
+}
+
+/// Routes `prefix` through `gateway`, which must be of the same family.
+pub fn add_prefix_route(prefix:&Prefix,gateway:IpAddr) -> Result<(),Error> {
+    route_via(prefix,&gateway.to_string())
+}
+
+/// Routes `prefix` through `gateway` given as `add_route` or `add_route6` take it.
+fn route_via(prefix:&Prefix,gateway:&str) -> Result<(),Error> {
+    match prefix.network() {
+        IpAddr::V4(_) if *prefix == Prefix::host(prefix.network()) => {
+            add_route(RouteType::Host,&prefix.network().to_string(),gateway)
+        }
+        IpAddr::V4(_) => add_route(RouteType::Net,&prefix.to_string(),gateway),
+        IpAddr::V6(_) => add_route6(&prefix.to_string(),gateway)
+    }
+}
+
+pub fn delete_prefix_route(prefix:&Prefix) -> Result<(),Error> {
+    match prefix.network() {
+        IpAddr::V4(_) if *prefix == Prefix::host(prefix.network()) => {
+            delete_route(RouteType::Host,&prefix.network().to_string())
+        }
+        IpAddr::V4(_) => delete_route(RouteType::Net,&prefix.to_string()),
+        IpAddr::V6(_) => delete_route6(&prefix.to_string())
+    }
+}
+
+/// The default route through a gateway of one family.
+#[cfg(target_os = "linux")]
+fn get_default_route(ipv6:bool) -> Result<Option<netlink::Route>,Error> {
+    netlink::default_route(ipv6).map_err(|error|Error::Netlink{ request:String::from("reading default route"), error })
+}
+
+#[cfg(target_os = "linux")]
+fn get_default_gateway() -> Result<String,Error> {
+    get_default_route(false)?
+        .and_then(|route| route.gateway)
+        .map(|gateway| gateway.to_string())
+        .ok_or(Error::Config(String::from("no IPv4 default gateway")))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_default_gateway() -> Result<String,Error> {
+    let cmd = if cfg!(target_os = "macos") {
         "route -n get default | grep gateway | awk '{print $2}'"
     } else {
         unimplemented!()
     };
-    let output = Command::new("bash").
-        arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).
-            unwrap()
-            .trim_end()
-            .to_string())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
-    }
+    Ok(run("bash",&["-c",cmd])?.trim_end().to_string())
 }
 
-fn get_route_gateway(route:&str) -> Result<String,String> {
-    let cmd = format!("ip -4 route list {}",route);
-    let output = Command::new("bash")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stderr).unwrap()
-            .trim_end()
-            .to_string())
+/// Gateway of the IPv6 default route with its interface as scope, if there is one.
+#[cfg(target_os = "linux")]
+fn get_default_gateway6() -> Result<Option<String>,Error> {
+    Ok(get_default_route(true)?.and_then(|route| match (route.gateway,route.interface) {
+        (Some(gateway),Some(interface)) => Some(format!("{}%{}",gateway,interface)),
+        (gateway,_) => gateway.map(|gateway| gateway.to_string())
+    }))
+}
+
+/// Gateway of the IPv6 default route with its interface as scope, if there is one.
+#[cfg(not(target_os = "linux"))]
+fn get_default_gateway6() -> Result<Option<String>,Error> {
+    let cmd = if cfg!(target_os = "macos") {
+        "route -n get -inet6 default 2>/dev/null | grep gateway | awk '{print $2}'"
     } else {
-        Err(String::from_utf8(output.stderr).unwrap())
+        unimplemented!()
+    };
+    let gateway = run("bash",&["-c",cmd])?.trim_end().to_string();
+    Ok(Some(gateway).filter(|gateway| !gateway.is_empty()))
+}
+
+#[cfg(test)]
+fn get_route_gateway(route:&str) -> Result<String,Error> {
+    Ok(run("ip",&["-4","route","list",route])?.trim_end().to_string())
+}
+
+/// Interface of the IPv4 default route.
+#[cfg(target_os = "linux")]
+fn get_default_interface() -> Result<String,Error> {
+    get_default_route(false)?
+        .and_then(|route| route.interface)
+        .ok_or(Error::Config(String::from("no default route to take the egress interface from")))
+}
+
+/// Interface of the IPv4 default route.
+#[cfg(not(target_os = "linux"))]
+fn get_default_interface() -> Result<String,Error> {
+    Err(Error::Config(String::from("masquerading is only available in Linux")))
+}
+
+/// Prefix of the nftables table holding the masquerading rules, followed by the TUN device
+/// name so that server instances keep separate tables.
+const NAT_TABLE:&str = "e_net";
+
+/// nftables table and iptables chain holding the kill switch rules.
+const KILL_SWITCH_TABLE:&str = "e_net_kill_switch";
+const KILL_SWITCH_CHAIN:&str = "E_NET_KILL_SWITCH";
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Firewall {
+    Nftables,
+    Iptables
+}
+
+/// nftables if available, iptables otherwise.
+fn detect_firewall() -> Firewall {
+    if run("nft",&["--version"]).is_ok() { Firewall::Nftables } else { Firewall::Iptables }
+}
+
+fn command(args:&[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Masquerading of traffic from the tunnel subnets leaving through an egress interface, so
+/// clients reach the internet through the server.
+pub struct Masquerade {
+    firewall:Firewall,
+    table:String,
+    interface:String,
+    subnets:Vec<Prefix>
+}
+
+impl Masquerade {
+    /// Installs the rules with nftables if available, iptables otherwise, noting how to remove
+    /// them in `journal`. Without an `interface` the one of the default route is used.
+    pub fn install(journal:&mut Journal,tunnel:&str,subnets:Vec<Prefix>,interface:Option<&str>) -> Result<(),Error> {
+        let interface = match interface {
+            Some(interface) => interface.to_string(),
+            None => get_default_interface()?
+        };
+        let firewall = detect_firewall();
+        info!("Masquerading tunnel traffic leaving {} with {:?}.",interface,firewall);
+        let table = format!("{}_{}",NAT_TABLE,tunnel.replace(|c:char| !c.is_ascii_alphanumeric(),"_"));
+        let masquerade = Masquerade{ firewall, table, interface, subnets };
+        for command in masquerade.commands(false) {
+            journal.record(Undo::Command(command))?;
+        }
+        for command in masquerade.commands(true) {
+            run_command(&command)?;
+        }
+        Ok(())
+    }
+
+    /// Commands installing the rules, or removing them if `add` is false.
+    fn commands(&self,add:bool) -> Vec<Vec<String>> {
+        match self.firewall {
+            Firewall::Nftables if add => {
+                let mut commands = vec![
+                    command(&["nft","add","table","inet",&self.table]),
+                    command(&[
+                        "nft","add","chain","inet",&self.table,"postrouting",
+                        "{ type nat hook postrouting priority 100 ; }"
+                    ])
+                ];
+                for subnet in &self.subnets {
+                    let family = if subnet.network().is_ipv4() { "ip" } else { "ip6" };
+                    commands.push(command(&[
+                        "nft","add","rule","inet",&self.table,"postrouting",
+                        family,"saddr",&subnet.to_string(),"oifname",&self.interface,"masquerade"
+                    ]));
+                }
+                commands
+            }
+            Firewall::Nftables => vec![command(&["nft","delete","table","inet",&self.table])],
+            Firewall::Iptables => self.subnets.iter().map(|subnet| {
+                let program = if subnet.network().is_ipv4() { "iptables" } else { "ip6tables" };
+                command(&[
+                    program,"-t","nat",if add { "-A" } else { "-D" },"POSTROUTING",
+                    "-s",&subnet.to_string(),"-o",&self.interface,"-j","MASQUERADE"
+                ])
+            }).collect()
+        }
     }
 }
 
-pub fn get_public_ip() -> Result<String,String>{
-    let output = Command::new("curl")
-        .arg("ipecho.net/plain")
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
+/// Firewall rules dropping outgoing traffic other than to the server endpoints, through the
+/// allowed TUN device and on loopback, so nothing leaves outside the tunnel while it is down.
+/// IPv6 neighbour discovery and DHCP still go out, so the link itself keeps working.
+pub struct KillSwitch {
+    firewall:Firewall,
+    endpoints:Vec<SocketAddr>
+}
+
+/// nftables set of the TUN devices the kill switch lets traffic through.
+const KILL_SWITCH_DEVICES:&str = "tunnels";
+
+impl KillSwitch {
+    /// Installs the rules with nftables if available, iptables otherwise, noting how to remove
+    /// them in `journal`. No TUN device is allowed yet.
+    pub fn install(journal:&mut Journal,endpoints:Vec<SocketAddr>) -> Result<KillSwitch,Error> {
+        if !cfg!(target_os = "linux") {
+            return Err(Error::Config(String::from("the kill switch is only available in Linux")));
+        }
+        let firewall = detect_firewall();
+        info!("Blocking traffic outside the tunnel with {:?}.",firewall);
+        let kill_switch = KillSwitch{ firewall, endpoints };
+        apply(journal,kill_switch.commands(true),kill_switch.commands(false))?;
+        Ok(kill_switch)
+    }
+
+    /// Lets traffic out through the TUN device `interface`, noting how to stop it in
+    /// `journal`.
+    pub fn allow(&self,journal:&mut Journal,interface:&str) -> Result<(),Error> {
+        info!("Letting traffic through {} past the kill switch.",interface);
+        apply(journal,self.allow_commands(interface,true),self.allow_commands(interface,false))
+    }
+
+    /// Commands letting traffic through `interface`, or stopping it if `add` is false.
+    fn allow_commands(&self,interface:&str,add:bool) -> Vec<Vec<String>> {
+        match self.firewall {
+            Firewall::Nftables => vec![command(&[
+                "nft",if add { "add" } else { "delete" },"element","inet",KILL_SWITCH_TABLE,KILL_SWITCH_DEVICES,
+                &format!("{{ \"{}\" }}",interface)
+            ])],
+            Firewall::Iptables => ["iptables","ip6tables"].iter().map(|&program| {
+                command(&[program,if add { "-I" } else { "-D" },KILL_SWITCH_CHAIN,"-o",interface,"-j","ACCEPT"])
+            }).collect()
+        }
+    }
+
+    /// Commands installing the rules, or removing them if `add` is false.
+    fn commands(&self,add:bool) -> Vec<Vec<String>> {
+        match self.firewall {
+            Firewall::Nftables if add => {
+                let rule = |args:&[&str]| {
+                    command(&[&["nft","add","rule","inet",KILL_SWITCH_TABLE,"output"],args].concat())
+                };
+                let mut commands = vec![
+                    command(&["nft","add","table","inet",KILL_SWITCH_TABLE]),
+                    command(&[
+                        "nft","add","chain","inet",KILL_SWITCH_TABLE,"output",
+                        "{ type filter hook output priority 0 ; policy drop ; }"
+                    ]),
+                    command(&[
+                        "nft","add","set","inet",KILL_SWITCH_TABLE,KILL_SWITCH_DEVICES,"{ type ifname ; }"
+                    ]),
+                    rule(&["oifname","lo","accept"]),
+                    rule(&["oifname",&format!("@{}",KILL_SWITCH_DEVICES),"accept"]),
+                    rule(&[
+                        "meta","l4proto","ipv6-icmp","icmpv6","type",
+                        "{ nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert }","accept"
+                    ]),
+                    rule(&["udp","sport","68","udp","dport","67","accept"]),
+                    rule(&["udp","sport","546","udp","dport","547","accept"])
+                ];
+                for endpoint in &self.endpoints {
+                    let family = if endpoint.is_ipv4() { "ip" } else { "ip6" };
+                    commands.push(rule(&[
+                        family,"daddr",&endpoint.ip().to_string(),"udp","dport",&endpoint.port().to_string(),"accept"
+                    ]));
+                }
+                commands
+            }
+            Firewall::Nftables => vec![command(&["nft","delete","table","inet",KILL_SWITCH_TABLE])],
+            Firewall::Iptables => ["iptables","ip6tables"].iter().flat_map(|&program| {
+                let ipv6 = program == "ip6tables";
+                if !add {
+                    return vec![
+                        command(&[program,"-D","OUTPUT","-j",KILL_SWITCH_CHAIN]),
+                        command(&[program,"-F",KILL_SWITCH_CHAIN]),
+                        command(&[program,"-X",KILL_SWITCH_CHAIN])
+                    ];
+                }
+                let rule = |args:&[&str]| command(&[&[program,"-A",KILL_SWITCH_CHAIN],args].concat());
+                let mut commands = vec![
+                    command(&[program,"-N",KILL_SWITCH_CHAIN]),
+                    rule(&["-o","lo","-j","ACCEPT"])
+                ];
+                if ipv6 {
+                    for kind in ["router-solicitation","neighbour-solicitation","neighbour-advertisement"] {
+                        commands.push(rule(&["-p","ipv6-icmp","--icmpv6-type",kind,"-j","ACCEPT"]));
+                    }
+                    commands.push(rule(&["-p","udp","--sport","546","--dport","547","-j","ACCEPT"]));
+                } else {
+                    commands.push(rule(&["-p","udp","--sport","68","--dport","67","-j","ACCEPT"]));
+                }
+                for endpoint in self.endpoints.iter().filter(|endpoint| endpoint.is_ipv6() == ipv6) {
+                    commands.push(rule(&[
+                        "-d",&endpoint.ip().to_string(),"-p","udp","--dport",&endpoint.port().to_string(),"-j","ACCEPT"
+                    ]));
+                }
+                commands.push(rule(&["-j","DROP"]));
+                commands.push(command(&[program,"-I","OUTPUT","-j",KILL_SWITCH_CHAIN]));
+                commands
+            }).collect()
+        }
     }
 }
 
-pub fn set_dns(dns:&str) -> Result<String,String> {
-    let cmd = format!("echo nameserver {} > /etc/resolve.conf",dns);
-    let output = Command::new("bash")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
+/// Runs `commands`, first noting `undo` in `journal` in reverse, as the journal takes changes
+/// back from the last one.
+fn apply(journal:&mut Journal,commands:Vec<Vec<String>>,undo:Vec<Vec<String>>) -> Result<(),Error> {
+    for command in undo.into_iter().rev() {
+        journal.record(Undo::Command(command))?;
+    }
+    for command in commands {
+        run_command(&command)?;
     }
+    Ok(())
+}
+
+pub fn get_public_ip() -> Result<String,Error>{
+    run("curl",&["ipecho.net/plain"])
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{env, fs, process};
+
     use crate::utils::*;
+
+    fn journal(name:&str) -> Journal {
+        let path = env::temp_dir().join(format!("e-net-{}-{}",process::id(),name));
+        Journal::open(path.to_str().unwrap()).unwrap()
+    }
+
     #[test]
     fn enable_ipv4_forwarding_test() {
-        enable_ipv4_forwarding().unwrap();
+        let original = get_sysctl("net.ipv4.ip_forward").unwrap();
+        let mut journal = journal("forwarding");
+        enable_ipv4_forwarding(&mut journal).unwrap();
+        assert_eq!(get_sysctl("net.ipv4.ip_forward").unwrap(),"1");
+        drop(journal);
+        assert_eq!(get_sysctl("net.ipv4.ip_forward").unwrap(),original);
     }
 
     #[test]
@@ -222,11 +638,134 @@ mod tests {
         assert!(get_route_gateway("1.1.1.1").unwrap().contains(&*gw));
         delete_route(RouteType::Host,"1.1.1.1").unwrap();
         assert!(!get_route_gateway("1.1.1.1").unwrap().contains(&*gw));
+        let missing = delete_route(RouteType::Host,"1.1.1.1").err().unwrap();
+        assert_eq!(missing.to_string(),"deleting route 1.1.1.1/32: No such process (os error 3)");
     }
 
     #[test]
-    fn set_dns_test() {
+    #[cfg(target_os = "linux")]
+    fn set_tunnel_routes_test() {
+        let gw = get_default_gateway().unwrap();
+        let mut journal = journal("tunnel-routes");
+        let split = Split{
+            include:vec!["198.51.100.0/24".parse().unwrap()],
+            exclude:vec!["203.0.113.0/24".parse().unwrap(),"192.0.2.77/32".parse().unwrap()]
+        };
+        let gateway = gw.parse().unwrap();
+        set_tunnel_routes(&mut journal,gateway,None,"192.0.2.77".parse().unwrap(),true,false,&split).unwrap();
+        let state = env::temp_dir().join(format!("e-net-{}-tunnel-routes",process::id()));
+        assert!(!fs::read_to_string(state).unwrap().contains("default\t"));
+        assert_eq!(get_default_gateway().unwrap(),gw);
+        assert!(get_route_gateway("192.0.2.77").unwrap().contains(&*gw));
+        assert!(get_route_gateway("198.51.100.0/24").unwrap().contains(&*gw));
+        assert!(get_route_gateway("203.0.113.0/24").unwrap().contains(&*gw));
+        drop(journal);
+        assert!(get_route_gateway("192.0.2.77").unwrap().is_empty());
+        assert!(get_route_gateway("203.0.113.0/24").unwrap().is_empty());
+        delete_prefix_route(&split.include[0]).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn route6_test() {
         assert!(is_root());
-        set_dns("8.8.8.8").unwrap();
+        enable_ipv6_forwarding(&mut journal("forwarding6")).unwrap();
+        add_route6("2001:db8:e::/48","fd00::1").unwrap();
+        assert!(run("ip",&["-6","route","list","2001:db8:e::/48"]).unwrap().contains("via fd00::1"));
+        delete_route6("2001:db8:e::/48").unwrap();
+        assert!(run("ip",&["-6","route","list","2001:db8:e::/48"]).unwrap().is_empty());
+        if let Some(gateway6) = get_default_gateway6().unwrap() {
+            add_route6("2001:db8:e::1/128",&gateway6).unwrap();
+            let (gateway6,device) = gateway6.split_once('%').unwrap();
+            let route = run("ip",&["-6","route","list","2001:db8:e::1"]).unwrap();
+            assert!(route.contains(&format!("via {} dev {}",gateway6,device)));
+            delete_route6("2001:db8:e::1/128").unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn get_default_interface_test() {
+        let interface = get_default_interface().unwrap();
+        assert!(get_route_gateway("0/0").unwrap().contains(&format!("dev {}",interface)));
+    }
+
+    #[test]
+    fn masquerade_commands_test() {
+        let mut masquerade = Masquerade{
+            firewall:Firewall::Iptables,
+            table:String::from("e_net_tun0"),
+            interface:String::from("eth0"),
+            subnets:vec!["10.10.10.0/24".parse().unwrap(),"fd00:e::/64".parse().unwrap()]
+        };
+        assert_eq!(masquerade.commands(true),vec![
+            "iptables -t nat -A POSTROUTING -s 10.10.10.0/24 -o eth0 -j MASQUERADE".split(' ').collect::<Vec<_>>(),
+            "ip6tables -t nat -A POSTROUTING -s fd00:e::/64 -o eth0 -j MASQUERADE".split(' ').collect::<Vec<_>>()
+        ]);
+        assert_eq!(masquerade.commands(false)[0][3],"-D");
+        masquerade.firewall = Firewall::Nftables;
+        let commands = masquerade.commands(true);
+        assert_eq!(commands.len(),4);
+        assert_eq!(commands[2].join(" "),"nft add rule inet e_net_tun0 postrouting ip saddr 10.10.10.0/24 oifname eth0 masquerade");
+        assert_eq!(commands[3].join(" "),"nft add rule inet e_net_tun0 postrouting ip6 saddr fd00:e::/64 oifname eth0 masquerade");
+        assert_eq!(masquerade.commands(false),vec![vec!["nft","delete","table","inet","e_net_tun0"]]);
+    }
+
+    #[test]
+    fn kill_switch_commands_test() {
+        let mut kill_switch = KillSwitch{
+            firewall:Firewall::Iptables,
+            endpoints:vec!["192.0.2.10:9527".parse().unwrap(),"[2001:db8::10]:9527".parse().unwrap()]
+        };
+        let commands:Vec<String> = kill_switch.commands(true).iter().map(|command| command.join(" ")).collect();
+        assert_eq!(commands[..6],[
+            "iptables -N E_NET_KILL_SWITCH",
+            "iptables -A E_NET_KILL_SWITCH -o lo -j ACCEPT",
+            "iptables -A E_NET_KILL_SWITCH -p udp --sport 68 --dport 67 -j ACCEPT",
+            "iptables -A E_NET_KILL_SWITCH -d 192.0.2.10 -p udp --dport 9527 -j ACCEPT",
+            "iptables -A E_NET_KILL_SWITCH -j DROP",
+            "iptables -I OUTPUT -j E_NET_KILL_SWITCH"
+        ]);
+        assert_eq!(commands[12],"ip6tables -A E_NET_KILL_SWITCH -d 2001:db8::10 -p udp --dport 9527 -j ACCEPT");
+        assert_eq!(commands.len(),15);
+        assert_eq!(kill_switch.commands(false)[0].join(" "),"iptables -D OUTPUT -j E_NET_KILL_SWITCH");
+        assert_eq!(kill_switch.commands(false)[5].join(" "),"ip6tables -X E_NET_KILL_SWITCH");
+        let allow:Vec<String> = kill_switch.allow_commands("tun0",true).iter().map(|command| command.join(" ")).collect();
+        assert_eq!(allow,["iptables -I E_NET_KILL_SWITCH -o tun0 -j ACCEPT","ip6tables -I E_NET_KILL_SWITCH -o tun0 -j ACCEPT"]);
+        assert_eq!(kill_switch.allow_commands("tun0",false)[1].join(" "),"ip6tables -D E_NET_KILL_SWITCH -o tun0 -j ACCEPT");
+        kill_switch.firewall = Firewall::Nftables;
+        let commands = kill_switch.commands(true);
+        assert_eq!(commands.len(),10);
+        assert_eq!(commands[1][6],"{ type filter hook output priority 0 ; policy drop ; }");
+        assert_eq!(commands[2].join(" "),"nft add set inet e_net_kill_switch tunnels { type ifname ; }");
+        assert_eq!(commands[4].join(" "),"nft add rule inet e_net_kill_switch output oifname @tunnels accept");
+        assert_eq!(commands[9].join(" "),"nft add rule inet e_net_kill_switch output ip6 daddr 2001:db8::10 udp dport 9527 accept");
+        assert_eq!(kill_switch.commands(false),vec![vec!["nft","delete","table","inet","e_net_kill_switch"]]);
+        assert_eq!(kill_switch.allow_commands("tun0",false)[0].join(" "),"nft delete element inet e_net_kill_switch tunnels { \"tun0\" }");
+    }
+
+    #[test]
+    fn kill_switch_link_rules_test() {
+        let mut kill_switch = KillSwitch{ firewall:Firewall::Iptables, endpoints:Vec::new() };
+        let commands:Vec<String> = kill_switch.commands(true).iter().map(|command| command.join(" ")).collect();
+        for rule in [
+            "iptables -A E_NET_KILL_SWITCH -p udp --sport 68 --dport 67 -j ACCEPT",
+            "ip6tables -A E_NET_KILL_SWITCH -p ipv6-icmp --icmpv6-type router-solicitation -j ACCEPT",
+            "ip6tables -A E_NET_KILL_SWITCH -p ipv6-icmp --icmpv6-type neighbour-solicitation -j ACCEPT",
+            "ip6tables -A E_NET_KILL_SWITCH -p ipv6-icmp --icmpv6-type neighbour-advertisement -j ACCEPT",
+            "ip6tables -A E_NET_KILL_SWITCH -p udp --sport 546 --dport 547 -j ACCEPT"
+        ] {
+            let program = rule.split(' ').next().unwrap();
+            let drop = format!("{} -A E_NET_KILL_SWITCH -j DROP",program);
+            let position = |rule:&str| commands.iter().position(|command| command == rule).unwrap();
+            assert!(position(rule) < position(&drop));
+        }
+        kill_switch.firewall = Firewall::Nftables;
+        let commands:Vec<String> = kill_switch.commands(true).iter().map(|command| command.join(" ")).collect();
+        assert_eq!(commands[5..],[
+            "nft add rule inet e_net_kill_switch output meta l4proto ipv6-icmp icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept",
+            "nft add rule inet e_net_kill_switch output udp sport 68 udp dport 67 accept",
+            "nft add rule inet e_net_kill_switch output udp sport 546 udp dport 547 accept"
+        ]);
     }
 }
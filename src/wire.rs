@@ -0,0 +1,769 @@
+//! On-wire format of e-net datagrams.
+//!
+//! Every datagram starts with a fixed header, integers in big-endian order:
+//!
+//! | offset | size | field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 2    | magic, `eN`                                |
+//! | 2      | 1    | protocol version                           |
+//! | 3      | 1    | message type                               |
+//! | 4      | 1    | key epoch, 0 outside of a session          |
+//! | 5      | 3    | reserved, must be zero                     |
+//! | 8      | 4    | session index, 0 outside of a session      |
+//! | 12     | 8    | packet counter, 0 outside of a session     |
+//!
+//! The body depends on the message type:
+//!
+//! | type | message     | body                                                         |
+//! |------|-------------|--------------------------------------------------------------|
+//! | 1    | KdfRequest  | zero padding up to [`KDF_REQUEST_LEN`] bytes, in the clear   |
+//! | 2    | KdfOffer    | salt (16), Argon2id memory in KiB, iterations, parallelism (4 each), in the clear |
+//! | 3    | Unsupported | lowest and highest supported version (1 each), in the clear  |
+//! | 4    | Request     | nonce (12), ciphertext, tag, sealed with the handshake key   |
+//! | 5    | Response    | nonce (12), ciphertext, tag, sealed with the handshake key   |
+//! | 6    | Data        | ciphertext, tag, sealed with the session's traffic key       |
+//! | 7    | Rekey       | ciphertext, tag, sealed with the session's traffic key       |
+//! | 8    | RekeyAck    | ciphertext, tag, sealed with the session's traffic key       |
+//! | 9    | Reject      | nonce (12), ciphertext, tag, sealed with the handshake key   |
+//! | 10   | Keepalive   | ciphertext, tag, sealed with the session's traffic key       |
+//!
+//! Sealed bodies use the header as additional authenticated data, and session messages use
+//! the packet counter as nonce. The plaintexts are:
+//!
+//! - Request: lowest and highest version the client speaks (1 each), its ephemeral public
+//!   key (32), the time it was sent in seconds since the Unix epoch (8) and optionally its
+//!   static public key (32). Clients with a static key may
+//!   follow it with attributes as in the Response: attribute 6 is a prefix routed behind
+//!   the client, an address (4 or 16) and prefix length (1), repeated for each prefix.
+//! - Response: the version chosen for the session (1), the session index (4), the session
+//!   token (8), the server's ephemeral public key (32), then attributes made of a type (1),
+//!   a length (2) and a value. Unknown attributes are skipped. Attribute 1 is a DNS
+//!   server as text, repeated for each server, attribute 2 the client's address (4) and
+//!   prefix length (1), attribute 3 the address of the server inside the tunnel (4), which
+//!   clients route through.
+//!   Attributes 4 and 5 are their IPv6 counterparts (16 + 1 and 16), present together when
+//!   the tunnel is dual-stack. Attribute 7 is a search domain as text, repeated for each
+//!   domain. Attributes 8 and 9 are prefixes the client routes through the tunnel and
+//!   around it, encoded like attribute 6 and repeated for each prefix. Attribute 10 is a
+//!   domain as text, repeated for each domain, present if only names in these domains are
+//!   resolved through the DNS servers.
+//! - Reject: why the server refused an authenticated request, as text.
+//! - Data: the snappy compressed IP packet.
+//! - Rekey and RekeyAck: the sender's ephemeral public key (32).
+//! - Keepalive: empty. Clients send it while idle and the server echoes it, so that either
+//!   side notices when the other is gone.
+//!
+//! KdfRequest and Request carry the lowest version the client speaks in their header, so
+//! that any server sharing a version can read them. The server picks the highest version in
+//! both ranges, or answers Unsupported if there is none. The Response and every later
+//! datagram of the session carry the chosen version, and a KdfOffer echoes the version of
+//! the request it answers.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::crypto::{KdfParams, KEY_LEN, SALT_LEN};
+use crate::dns;
+use crate::route::{Prefix, Split};
+use crate::session::{Id, Token};
+
+pub const MAGIC:[u8;2] = *b"eN";
+/// Highest protocol version this build speaks.
+pub const VERSION:u8 = 1;
+/// Lowest protocol version this build speaks.
+pub const MIN_VERSION:u8 = 1;
+pub const HEADER_LEN:usize = 20;
+/// Length of a KDF request. It is padded so that the answer is never larger than the
+/// request, which keeps the unauthenticated exchange useless for traffic amplification.
+pub const KDF_REQUEST_LEN:usize = 64;
+
+const DNS_ATTRIBUTE:u8 = 1;
+const ADDRESS_ATTRIBUTE:u8 = 2;
+const GATEWAY_ATTRIBUTE:u8 = 3;
+const ADDRESS6_ATTRIBUTE:u8 = 4;
+const GATEWAY6_ATTRIBUTE:u8 = 5;
+const ROUTE_ATTRIBUTE:u8 = 6;
+const SEARCH_ATTRIBUTE:u8 = 7;
+const INCLUDE_ATTRIBUTE:u8 = 8;
+const EXCLUDE_ATTRIBUTE:u8 = 9;
+const DOMAIN_ATTRIBUTE:u8 = 10;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum MessageType {
+    KdfRequest = 1,
+    KdfOffer = 2,
+    Unsupported = 3,
+    Request = 4,
+    Response = 5,
+    Data = 6,
+    Rekey = 7,
+    RekeyAck = 8,
+    Reject = 9,
+    Keepalive = 10
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = String;
+
+    fn try_from(value:u8) -> Result<MessageType,String> {
+        Ok(match value {
+            1 => MessageType::KdfRequest,
+            2 => MessageType::KdfOffer,
+            3 => MessageType::Unsupported,
+            4 => MessageType::Request,
+            5 => MessageType::Response,
+            6 => MessageType::Data,
+            7 => MessageType::Rekey,
+            8 => MessageType::RekeyAck,
+            9 => MessageType::Reject,
+            10 => MessageType::Keepalive,
+            _ => return Err(format!("unknown message type {}",value))
+        })
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Header {
+    pub version:u8,
+    pub message_type:MessageType,
+    pub epoch:u8,
+    pub session:u32,
+    pub counter:u64
+}
+
+impl Header {
+    /// Header of a message sent outside of a session.
+    pub fn new(version:u8,message_type:MessageType) -> Header {
+        Header{ version, message_type, epoch:0, session:0, counter:0 }
+    }
+
+    pub fn encode(&self) -> [u8;HEADER_LEN] {
+        let mut header = [0u8;HEADER_LEN];
+        header[0..2].copy_from_slice(&MAGIC);
+        header[2] = self.version;
+        header[3] = self.message_type as u8;
+        header[4] = self.epoch;
+        header[8..12].copy_from_slice(&self.session.to_be_bytes());
+        header[12..20].copy_from_slice(&self.counter.to_be_bytes());
+        header
+    }
+
+    /// Parses the header at the start of `datagram`. The version is not checked, since what
+    /// is acceptable depends on the message.
+    pub fn decode(datagram:&[u8]) -> Result<Header,String> {
+        let mut reader = Reader::new(datagram);
+        if reader.bytes(2)? != MAGIC {
+            return Err(String::from("bad magic"));
+        }
+        let version = reader.u8()?;
+        let message_type = MessageType::try_from(reader.u8()?)?;
+        let epoch = reader.u8()?;
+        if reader.bytes(3)? != [0;3] {
+            return Err(String::from("reserved header bytes set"));
+        }
+        let session = reader.u32()?;
+        let counter = reader.u64()?;
+        Ok(Header{ version, message_type, epoch, session, counter })
+    }
+}
+
+/// Cursor over a received body, failing instead of reading past its end.
+struct Reader<'a> {
+    data:&'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn new(data:&'a [u8]) -> Reader<'a> {
+        Reader{ data }
+    }
+
+    fn bytes(&mut self,len:usize) -> Result<&'a [u8],String> {
+        if self.data.len() < len {
+            return Err(format!("truncated message: {} bytes missing",len - self.data.len()));
+        }
+        let (bytes,rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N:usize>(&mut self) -> Result<[u8;N],String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8,String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16,String> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32,String> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64,String> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn finish(&self) -> Result<(),String> {
+        if !self.is_empty() {
+            return Err(format!("{} trailing bytes",self.data.len()));
+        }
+        Ok(())
+    }
+}
+
+/// Salt and Argon2id parameters of the server, sent in the clear.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct KdfOffer {
+    pub salt:[u8;SALT_LEN],
+    pub params:KdfParams
+}
+
+impl KdfOffer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = self.salt.to_vec();
+        body.extend_from_slice(&self.params.memory_kib.to_be_bytes());
+        body.extend_from_slice(&self.params.iterations.to_be_bytes());
+        body.extend_from_slice(&self.params.parallelism.to_be_bytes());
+        body
+    }
+
+    pub fn decode(body:&[u8]) -> Result<KdfOffer,String> {
+        let mut reader = Reader::new(body);
+        let salt = reader.array()?;
+        let params = KdfParams{
+            memory_kib:reader.u32()?,
+            iterations:reader.u32()?,
+            parallelism:reader.u32()?
+        };
+        reader.finish()?;
+        Ok(KdfOffer{ salt, params })
+    }
+}
+
+/// Range of protocol versions a peer speaks.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Versions {
+    pub min:u8,
+    pub max:u8
+}
+
+impl Versions {
+    pub const SUPPORTED:Versions = Versions{ min:MIN_VERSION, max:VERSION };
+
+    /// Picks the highest version both ranges include.
+    pub fn negotiate(&self,peer:&Versions) -> Option<u8> {
+        let version = self.max.min(peer.max);
+        (version >= self.min.max(peer.min)).then_some(version)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        vec![self.min,self.max]
+    }
+
+    pub fn decode(body:&[u8]) -> Result<Versions,String> {
+        let mut reader = Reader::new(body);
+        let versions = Versions{ min:reader.u8()?, max:reader.u8()? };
+        reader.finish()?;
+        Ok(versions)
+    }
+}
+
+/// IPv6 address assigned to a client of a dual-stack tunnel.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Ipv6Lease {
+    pub address:Ipv6Addr,
+    pub prefix:u8,
+    pub gateway:Ipv6Addr
+}
+
+/// Messages sealed with the handshake key or a session's traffic key.
+#[derive(PartialEq, Debug)]
+pub enum Message {
+    /// Prefixes in `routes` are only sent along with an identity.
+    Request {
+        versions:Versions,
+        public_key:[u8;KEY_LEN],
+        timestamp:u64,
+        identity:Option<[u8;KEY_LEN]>,
+        routes:Vec<Prefix>
+    },
+    Response {
+        version:u8,
+        id:Id,
+        token:Token,
+        public_key:[u8;KEY_LEN],
+        address:Ipv4Addr,
+        prefix:u8,
+        gateway:Ipv4Addr,
+        ipv6:Option<Ipv6Lease>,
+        dns:dns::Config,
+        split:Split
+    },
+    Data{data:Vec<u8>},
+    Rekey{public_key:[u8;KEY_LEN]},
+    RekeyAck{public_key:[u8;KEY_LEN]},
+    Reject{reason:String},
+    Keepalive
+}
+
+impl Message {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::Request{..} => MessageType::Request,
+            Message::Response{..} => MessageType::Response,
+            Message::Data{..} => MessageType::Data,
+            Message::Rekey{..} => MessageType::Rekey,
+            Message::RekeyAck{..} => MessageType::RekeyAck,
+            Message::Reject{..} => MessageType::Reject,
+            Message::Keepalive => MessageType::Keepalive
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Message::Request{versions,public_key,timestamp,identity,routes} => {
+                body.extend_from_slice(&versions.encode());
+                body.extend_from_slice(public_key);
+                body.extend_from_slice(&timestamp.to_be_bytes());
+                if let Some(identity) = identity {
+                    body.extend_from_slice(identity);
+                    for route in routes {
+                        put_prefix(&mut body,ROUTE_ATTRIBUTE,route);
+                    }
+                }
+            }
+            Message::Response{version,id,token,public_key,address,prefix,gateway,ipv6,dns,split} => {
+                body.push(*version);
+                body.extend_from_slice(&id.to_be_bytes());
+                body.extend_from_slice(&token.to_be_bytes());
+                body.extend_from_slice(public_key);
+                for server in &dns.servers {
+                    put_attribute(&mut body,DNS_ATTRIBUTE,server.to_string().as_bytes());
+                }
+                let mut value = address.octets().to_vec();
+                value.push(*prefix);
+                put_attribute(&mut body,ADDRESS_ATTRIBUTE,&value);
+                put_attribute(&mut body,GATEWAY_ATTRIBUTE,&gateway.octets());
+                if let Some(ipv6) = ipv6 {
+                    let mut value = ipv6.address.octets().to_vec();
+                    value.push(ipv6.prefix);
+                    put_attribute(&mut body,ADDRESS6_ATTRIBUTE,&value);
+                    put_attribute(&mut body,GATEWAY6_ATTRIBUTE,&ipv6.gateway.octets());
+                }
+                for domain in &dns.search {
+                    put_attribute(&mut body,SEARCH_ATTRIBUTE,domain.as_bytes());
+                }
+                for prefix in &split.include {
+                    put_prefix(&mut body,INCLUDE_ATTRIBUTE,prefix);
+                }
+                for prefix in &split.exclude {
+                    put_prefix(&mut body,EXCLUDE_ATTRIBUTE,prefix);
+                }
+                for domain in &dns.domains {
+                    put_attribute(&mut body,DOMAIN_ATTRIBUTE,domain.as_bytes());
+                }
+            }
+            Message::Data{data} => body.extend_from_slice(data),
+            Message::Rekey{public_key} | Message::RekeyAck{public_key} => body.extend_from_slice(public_key),
+            Message::Reject{reason} => body.extend_from_slice(reason.as_bytes()),
+            Message::Keepalive => {}
+        }
+        body
+    }
+
+    pub fn decode(message_type:MessageType,body:&[u8]) -> Result<Message,String> {
+        let mut reader = Reader::new(body);
+        let msg = match message_type {
+            MessageType::Request => {
+                let versions = Versions{ min:reader.u8()?, max:reader.u8()? };
+                let public_key = reader.array()?;
+                let timestamp = reader.u64()?;
+                let identity = if reader.is_empty() { None } else { Some(reader.array()?) };
+                let mut routes = Vec::new();
+                while !reader.is_empty() {
+                    let attribute = reader.u8()?;
+                    let len = reader.u16()? as usize;
+                    let value = reader.bytes(len)?;
+                    if attribute == ROUTE_ATTRIBUTE {
+                        routes.push(read_prefix(value)?);
+                    }
+                }
+                Message::Request{ versions, public_key, timestamp, identity, routes }
+            }
+            MessageType::Response => {
+                let version = reader.u8()?;
+                let id = reader.u32()?;
+                let token = reader.u64()?;
+                let public_key = reader.array()?;
+                let mut dns = dns::Config::default();
+                let mut split = Split::default();
+                let mut lease = None;
+                let mut gateway = None;
+                let mut lease6 = None;
+                let mut gateway6 = None;
+                while !reader.is_empty() {
+                    let attribute = reader.u8()?;
+                    let len = reader.u16()? as usize;
+                    let value = reader.bytes(len)?;
+                    match attribute {
+                        DNS_ATTRIBUTE => {
+                            let server = String::from_utf8(value.to_vec()).map_err(|e|e.to_string())?;
+                            dns.servers.push(server.parse().map_err(|e|format!("DNS server {}: {}",server,e))?);
+                        }
+                        SEARCH_ATTRIBUTE | DOMAIN_ATTRIBUTE => {
+                            let domain = String::from_utf8(value.to_vec()).map_err(|e|e.to_string())?;
+                            dns::check_domain(&domain)?;
+                            if attribute == SEARCH_ATTRIBUTE {
+                                dns.search.push(domain);
+                            } else {
+                                dns.domains.push(domain);
+                            }
+                        }
+                        INCLUDE_ATTRIBUTE => split.include.push(read_prefix(value)?),
+                        EXCLUDE_ATTRIBUTE => split.exclude.push(read_prefix(value)?),
+                        ADDRESS_ATTRIBUTE => {
+                            let mut value = Reader::new(value);
+                            lease = Some((Ipv4Addr::from(value.array::<4>()?),value.u8()?));
+                            value.finish()?;
+                        }
+                        GATEWAY_ATTRIBUTE => {
+                            let mut value = Reader::new(value);
+                            gateway = Some(Ipv4Addr::from(value.array::<4>()?));
+                            value.finish()?;
+                        }
+                        ADDRESS6_ATTRIBUTE => {
+                            let mut value = Reader::new(value);
+                            lease6 = Some((Ipv6Addr::from(value.array::<16>()?),value.u8()?));
+                            value.finish()?;
+                        }
+                        GATEWAY6_ATTRIBUTE => {
+                            let mut value = Reader::new(value);
+                            gateway6 = Some(Ipv6Addr::from(value.array::<16>()?));
+                            value.finish()?;
+                        }
+                        _ => {}
+                    }
+                }
+                if dns.servers.is_empty() {
+                    return Err(String::from("response without DNS server"));
+                }
+                let (address,prefix) = lease.ok_or("response without address")?;
+                let gateway = gateway.ok_or("response without gateway")?;
+                let ipv6 = match (lease6,gateway6) {
+                    (Some((address,prefix)),Some(gateway)) => Some(Ipv6Lease{ address, prefix, gateway }),
+                    (None,None) => None,
+                    _ => return Err(String::from("response with partial IPv6 address"))
+                };
+                Message::Response{ version, id, token, public_key, address, prefix, gateway, ipv6, dns, split }
+            }
+            MessageType::Data => Message::Data{ data:reader.bytes(body.len())?.to_vec() },
+            MessageType::Rekey => Message::Rekey{ public_key:reader.array()? },
+            MessageType::RekeyAck => Message::RekeyAck{ public_key:reader.array()? },
+            MessageType::Keepalive => Message::Keepalive,
+            MessageType::Reject => Message::Reject{
+                reason:String::from_utf8(reader.bytes(body.len())?.to_vec()).map_err(|e|e.to_string())?
+            },
+            _ => return Err(format!("{:?} is not a sealed message",message_type))
+        };
+        reader.finish()?;
+        Ok(msg)
+    }
+}
+
+fn put_attribute(body:&mut Vec<u8>,attribute:u8,value:&[u8]) {
+    body.push(attribute);
+    body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    body.extend_from_slice(value);
+}
+
+/// Puts `prefix` as its network address (4 or 16) and length (1).
+fn put_prefix(body:&mut Vec<u8>,attribute:u8,prefix:&Prefix) {
+    let mut value = match prefix.network() {
+        IpAddr::V4(network) => network.octets().to_vec(),
+        IpAddr::V6(network) => network.octets().to_vec()
+    };
+    value.push(prefix.length());
+    put_attribute(body,attribute,&value);
+}
+
+fn read_prefix(value:&[u8]) -> Result<Prefix,String> {
+    let network = match value.len() {
+        5 => IpAddr::V4(Ipv4Addr::from(Reader::new(value).array::<4>()?)),
+        17 => IpAddr::V6(Ipv6Addr::from(Reader::new(value).array::<16>()?)),
+        len => return Err(format!("prefix of {} bytes",len))
+    };
+    Prefix::new(network,value[value.len() - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wire::*;
+
+    #[test]
+    fn header_golden_test() {
+        let header = Header{
+            version:1,
+            message_type:MessageType::Data,
+            epoch:3,
+            session:0x01020304,
+            counter:0x05060708090a0b0c
+        };
+        let golden = [
+            b'e', b'N', 1, 6, 3, 0, 0, 0,
+            1, 2, 3, 4,
+            5, 6, 7, 8, 9, 10, 11, 12
+        ];
+        assert_eq!(header.encode(),golden);
+        assert_eq!(Header::decode(&golden).unwrap(),header);
+        let mut datagram = golden.to_vec();
+        datagram.extend_from_slice(b"body");
+        assert_eq!(Header::decode(&datagram).unwrap(),header);
+    }
+
+    #[test]
+    fn header_invalid_test() {
+        let golden = Header::new(1,MessageType::Request).encode();
+        assert!(Header::decode(&golden[..HEADER_LEN - 1]).is_err());
+        for (offset,value) in [(0,b'x'),(3,0),(3,11),(5,1),(7,1)] {
+            let mut invalid = golden;
+            invalid[offset] = value;
+            assert!(Header::decode(&invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn kdf_offer_golden_test() {
+        let offer = KdfOffer{
+            salt:[7;SALT_LEN],
+            params:KdfParams{ memory_kib:0x10000, iterations:3, parallelism:1 }
+        };
+        let mut golden = vec![7;SALT_LEN];
+        golden.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1]);
+        assert_eq!(offer.encode(),golden);
+        assert_eq!(KdfOffer::decode(&golden).unwrap(),offer);
+        assert!(KdfOffer::decode(&golden[1..]).is_err());
+        assert!(HEADER_LEN + golden.len() <= KDF_REQUEST_LEN);
+    }
+
+    #[test]
+    fn request_golden_test() {
+        let msg = Message::Request{
+            versions:Versions{ min:1, max:2 },
+            public_key:[0xaa;KEY_LEN],
+            timestamp:0x0102030405060708,
+            identity:Some([0xbb;KEY_LEN]),
+            routes:Vec::new()
+        };
+        let mut golden = vec![1, 2];
+        golden.extend_from_slice(&[0xaa;KEY_LEN]);
+        golden.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        golden.extend_from_slice(&[0xbb;KEY_LEN]);
+        assert_eq!(msg.encode(),golden);
+        assert_eq!(Message::decode(MessageType::Request,&golden).unwrap(),msg);
+        let anonymous = Message::decode(MessageType::Request,&golden[..10 + KEY_LEN]).unwrap();
+        assert_eq!(anonymous,Message::Request{
+            versions:Versions{ min:1, max:2 },
+            public_key:[0xaa;KEY_LEN],
+            timestamp:0x0102030405060708,
+            identity:None,
+            routes:Vec::new()
+        });
+        assert!(Message::decode(MessageType::Request,&golden[..2 + KEY_LEN]).is_err());
+        assert!(Message::decode(MessageType::Request,&golden[..11 + KEY_LEN]).is_err());
+    }
+
+    #[test]
+    fn request_routes_golden_test() {
+        let msg = Message::Request{
+            versions:Versions{ min:1, max:1 },
+            public_key:[0xaa;KEY_LEN],
+            timestamp:1,
+            identity:Some([0xbb;KEY_LEN]),
+            routes:vec!["192.168.1.0/24".parse().unwrap(),"fd00:1::/48".parse().unwrap()]
+        };
+        let mut golden = vec![1, 1];
+        golden.extend_from_slice(&[0xaa;KEY_LEN]);
+        golden.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        golden.extend_from_slice(&[0xbb;KEY_LEN]);
+        golden.extend_from_slice(&[6, 0, 5, 192, 168, 1, 0, 24]);
+        golden.extend_from_slice(&[6, 0, 17, 0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 48]);
+        assert_eq!(msg.encode(),golden);
+        assert_eq!(Message::decode(MessageType::Request,&golden).unwrap(),msg);
+        let mut unknown = golden.clone();
+        unknown.extend_from_slice(&[99, 0, 1, 7]);
+        assert_eq!(Message::decode(MessageType::Request,&unknown).unwrap(),msg);
+        let mut short = golden[..10 + 2 * KEY_LEN].to_vec();
+        short.extend_from_slice(&[6, 0, 4, 192, 168, 1, 24]);
+        assert!(Message::decode(MessageType::Request,&short).is_err());
+        let mut long = golden[..10 + 2 * KEY_LEN].to_vec();
+        long.extend_from_slice(&[6, 0, 5, 192, 168, 1, 0, 33]);
+        assert!(Message::decode(MessageType::Request,&long).is_err());
+    }
+
+    #[test]
+    fn response_golden_test() {
+        let msg = Message::Response{
+            version:1,
+            id:0x00010002,
+            token:0x0102030405060708,
+            public_key:[0xaa;KEY_LEN],
+            address:Ipv4Addr::new(10,10,1,2),
+            prefix:16,
+            gateway:Ipv4Addr::new(10,10,0,1),
+            ipv6:None,
+            dns:dns::Config{ servers:vec![IpAddr::V4(Ipv4Addr::new(8,8,8,8))], ..dns::Config::default() },
+            split:Split::default()
+        };
+        let mut golden = vec![1, 0, 1, 0, 2, 1, 2, 3, 4, 5, 6, 7, 8];
+        golden.extend_from_slice(&[0xaa;KEY_LEN]);
+        golden.extend_from_slice(&[1, 0, 7]);
+        golden.extend_from_slice(b"8.8.8.8");
+        golden.extend_from_slice(&[2, 0, 5, 10, 10, 1, 2, 16]);
+        golden.extend_from_slice(&[3, 0, 4, 10, 10, 0, 1]);
+        assert_eq!(msg.encode(),golden);
+        assert_eq!(Message::decode(MessageType::Response,&golden).unwrap(),msg);
+        let mut extended = golden.clone();
+        extended.extend_from_slice(&[0xff, 0, 2, 1, 2]);
+        assert_eq!(Message::decode(MessageType::Response,&extended).unwrap(),msg);
+        assert!(Message::decode(MessageType::Response,&golden[..golden.len() - 1]).is_err());
+        assert!(Message::decode(MessageType::Response,&golden[..golden.len() - 7]).is_err());
+        assert!(Message::decode(MessageType::Response,&golden[..golden.len() - 15]).is_err());
+        let ipv6 = Ipv6Lease{
+            address:"fd00::2".parse().unwrap(),
+            prefix:64,
+            gateway:"fd00::1".parse().unwrap()
+        };
+        let dual_stack = match msg {
+            Message::Response{version,id,token,public_key,address,prefix,gateway,dns,split,..} => Message::Response{
+                version, id, token, public_key, address, prefix, gateway, ipv6:Some(ipv6), dns, split
+            },
+            _ => unreachable!()
+        };
+        let mut golden6 = golden.clone();
+        golden6.extend_from_slice(&[4, 0, 17, 0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 64]);
+        golden6.extend_from_slice(&[5, 0, 16, 0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(dual_stack.encode(),golden6);
+        assert_eq!(Message::decode(MessageType::Response,&golden6).unwrap(),dual_stack);
+        assert!(Message::decode(MessageType::Response,&golden6[..golden6.len() - 19]).is_err());
+    }
+
+    #[test]
+    fn response_dns_golden_test() {
+        let msg = Message::Response{
+            version:1,
+            id:1,
+            token:2,
+            public_key:[0xaa;KEY_LEN],
+            address:Ipv4Addr::new(10,10,1,2),
+            prefix:16,
+            gateway:Ipv4Addr::new(10,10,0,1),
+            ipv6:None,
+            dns:dns::Config{
+                servers:vec!["10.10.0.1".parse().unwrap(),"fd00::1".parse().unwrap()],
+                search:vec![String::from("corp.example")],
+                domains:vec![String::from("corp.example")]
+            },
+            split:Split::default()
+        };
+        let mut golden = vec![1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2];
+        golden.extend_from_slice(&[0xaa;KEY_LEN]);
+        golden.extend_from_slice(&[1, 0, 9]);
+        golden.extend_from_slice(b"10.10.0.1");
+        golden.extend_from_slice(&[1, 0, 7]);
+        golden.extend_from_slice(b"fd00::1");
+        golden.extend_from_slice(&[2, 0, 5, 10, 10, 1, 2, 16]);
+        golden.extend_from_slice(&[3, 0, 4, 10, 10, 0, 1]);
+        golden.extend_from_slice(&[7, 0, 12]);
+        golden.extend_from_slice(b"corp.example");
+        golden.extend_from_slice(&[10, 0, 12]);
+        golden.extend_from_slice(b"corp.example");
+        assert_eq!(msg.encode(),golden);
+        assert_eq!(Message::decode(MessageType::Response,&golden).unwrap(),msg);
+        let mut injected = golden.clone();
+        injected.extend_from_slice(&[7, 0, 12]);
+        injected.extend_from_slice(b"x\nnameserver");
+        assert!(Message::decode(MessageType::Response,&injected).is_err());
+        let mut injected = golden.clone();
+        injected.extend_from_slice(&[10, 0, 3]);
+        injected.extend_from_slice(b"a b");
+        assert!(Message::decode(MessageType::Response,&injected).is_err());
+        let mut named = golden[..13 + KEY_LEN].to_vec();
+        named.extend_from_slice(&[1, 0, 3]);
+        named.extend_from_slice(b"dns");
+        named.extend_from_slice(&golden[golden.len() - 45..]);
+        assert!(Message::decode(MessageType::Response,&named).is_err());
+    }
+
+    #[test]
+    fn response_split_golden_test() {
+        let msg = Message::Response{
+            version:1,
+            id:1,
+            token:2,
+            public_key:[0xaa;KEY_LEN],
+            address:Ipv4Addr::new(10,10,1,2),
+            prefix:16,
+            gateway:Ipv4Addr::new(10,10,0,1),
+            ipv6:None,
+            dns:dns::Config{ servers:vec![IpAddr::V4(Ipv4Addr::new(8,8,8,8))], ..dns::Config::default() },
+            split:Split{
+                include:vec!["0.0.0.0/0".parse().unwrap(),"fd00:1::/48".parse().unwrap()],
+                exclude:vec!["192.168.1.0/24".parse().unwrap()]
+            }
+        };
+        let mut golden = vec![1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2];
+        golden.extend_from_slice(&[0xaa;KEY_LEN]);
+        golden.extend_from_slice(&[1, 0, 7]);
+        golden.extend_from_slice(b"8.8.8.8");
+        golden.extend_from_slice(&[2, 0, 5, 10, 10, 1, 2, 16]);
+        golden.extend_from_slice(&[3, 0, 4, 10, 10, 0, 1]);
+        golden.extend_from_slice(&[8, 0, 5, 0, 0, 0, 0, 0]);
+        golden.extend_from_slice(&[8, 0, 17, 0xfd, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 48]);
+        golden.extend_from_slice(&[9, 0, 5, 192, 168, 1, 0, 24]);
+        assert_eq!(msg.encode(),golden);
+        assert_eq!(Message::decode(MessageType::Response,&golden).unwrap(),msg);
+        let mut long = golden.clone();
+        long.extend_from_slice(&[9, 0, 5, 192, 168, 1, 0, 33]);
+        assert!(Message::decode(MessageType::Response,&long).is_err());
+    }
+
+    #[test]
+    fn reject_golden_test() {
+        let msg = Message::Reject{ reason:String::from("full") };
+        assert_eq!(msg.encode(),b"full");
+        assert_eq!(Message::decode(MessageType::Reject,b"full").unwrap(),msg);
+        assert!(Message::decode(MessageType::Reject,&[0xff]).is_err());
+    }
+
+    #[test]
+    fn session_message_golden_test() {
+        let msg = Message::Data{ data:vec![1,2,3] };
+        assert_eq!(msg.encode(),vec![1,2,3]);
+        assert_eq!(Message::decode(MessageType::Data,&[1,2,3]).unwrap(),msg);
+        let msg = Message::Rekey{ public_key:[0xaa;KEY_LEN] };
+        assert_eq!(msg.encode(),vec![0xaa;KEY_LEN]);
+        assert_eq!(Message::decode(MessageType::Rekey,&[0xaa;KEY_LEN]).unwrap(),msg);
+        assert!(Message::decode(MessageType::RekeyAck,&[0xaa;KEY_LEN + 1]).is_err());
+        assert_eq!(Message::Keepalive.encode(),Vec::<u8>::new());
+        assert_eq!(Message::decode(MessageType::Keepalive,&[]).unwrap(),Message::Keepalive);
+        assert!(Message::decode(MessageType::Keepalive,&[0]).is_err());
+        assert!(Message::decode(MessageType::KdfOffer,&[]).is_err());
+    }
+
+    #[test]
+    fn negotiate_test() {
+        let ours = Versions{ min:1, max:3 };
+        assert_eq!(ours.negotiate(&Versions{ min:1, max:1 }),Some(1));
+        assert_eq!(ours.negotiate(&Versions{ min:2, max:5 }),Some(3));
+        assert_eq!(ours.negotiate(&Versions{ min:4, max:5 }),None);
+        assert_eq!(Versions::decode(&Versions::SUPPORTED.encode()).unwrap(),Versions::SUPPORTED);
+        assert!(Versions::decode(&[1]).is_err());
+    }
+}